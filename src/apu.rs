@@ -1,27 +1,855 @@
+use std::collections::VecDeque;
 use super::memory::Memory;
-pub struct Apu {
+use super::main_board::CPU_FREQUENCY;
+use super::model::Model;
+use super::save_state::{SaveState, StateReader, StateWriter};
+
+// https://gbdev.io/pandocs/Audio.html
+pub const SAMPLE_RATE: u32 = 44_100;
+pub const OSCILLOSCOPE_SAMPLES: usize = 512;
+const FRAME_SEQUENCER_PERIOD: u32 = 8192; // 512 Hz
+const MAX_BUFFERED_SAMPLES: usize = SAMPLE_RATE as usize; // 0.5s of stereo samples
+
+const DUTY_WAVEFORMS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+pub const DUTY_PERCENTAGES: [&str; 4] = ["12.5%", "25%", "50%", "75%"];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+// returns the nearest note name to a frequency, e.g. 440.0 -> "A4"
+pub fn note_name(frequency_hz: f32) -> String {
+    if frequency_hz <= 0.0 {
+        return "--".to_string();
+    }
+    let semitones_from_a4 = (12.0 * (frequency_hz / 440.0).log2()).round() as i32;
+    let midi_note = 69 + semitones_from_a4;
+    let octave = midi_note.div_euclid(12) - 1;
+    format!("{}{}", NOTE_NAMES[midi_note.rem_euclid(12) as usize], octave)
+}
+
+// https://gbdev.io/pandocs/Audio_details.html#length-timer
+pub struct LengthCounter {
+    pub enabled: bool,
+    pub counter: u16,
+    max: u16,
+}
+
+impl LengthCounter {
+    fn init(max: u16) -> Self {
+        Self { enabled: false, counter: 0, max }
+    }
+
+    fn load(&mut self, length_data: u16) {
+        self.counter = self.max - length_data;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    // returns true if the channel should be disabled
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+}
+
+// https://gbdev.io/pandocs/Audio_Registers.html#ff12--nr12-channel-1-volume--envelope
+pub struct VolumeEnvelope {
+    pub initial_volume: u8,
+    pub increase: bool,
+    pub period: u8,
+    pub volume: u8,
+    timer: u8,
+}
+
+impl VolumeEnvelope {
+    fn init() -> Self {
+        Self { initial_volume: 0, increase: false, period: 0, volume: 0, timer: 0 }
+    }
+
+    fn read(&self) -> u8 {
+        self.initial_volume << 4 | if self.increase { 0x08 } else { 0x00 } | self.period
+    }
+
+    fn write(&mut self, data: u8) {
+        self.initial_volume = data >> 4;
+        self.increase = data & 0x08 != 0;
+        self.period = data & 0x07;
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+// https://gbdev.io/pandocs/Audio_Registers.html#ff10--nr10-channel-1-sweep
+pub struct FrequencySweep {
+    pub period: u8,
+    pub negate: bool,
+    pub shift: u8,
+    enabled: bool,
+    timer: u8,
+    shadow_frequency: u16,
+}
+
+impl FrequencySweep {
+    fn init() -> Self {
+        Self { period: 0, negate: false, shift: 0, enabled: false, timer: 0, shadow_frequency: 0 }
+    }
+
+    fn read(&self) -> u8 {
+        0x80 | self.period << 4 | if self.negate { 0x08 } else { 0x00 } | self.shift
+    }
+
+    fn write(&mut self, data: u8) {
+        self.period = (data >> 4) & 0x07;
+        self.negate = data & 0x08 != 0;
+        self.shift = data & 0x07;
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+}
+
+// Channels 1 and 2. Only channel 1 has a frequency sweep unit.
+pub struct SquareChannel {
+    pub enabled: bool,
+    pub duty: u8,
+    pub frequency: u16,
+    pub length: LengthCounter,
+    pub envelope: VolumeEnvelope,
+    pub sweep: Option<FrequencySweep>,
+    timer: u32,
+    duty_position: usize,
+}
+
+impl SquareChannel {
+    fn init(has_sweep: bool) -> Self {
+        Self {
+            enabled: false,
+            duty: 0,
+            frequency: 0,
+            length: LengthCounter::init(64),
+            envelope: VolumeEnvelope::init(),
+            sweep: if has_sweep { Some(FrequencySweep::init()) } else { None },
+            timer: 0,
+            duty_position: 0,
+        }
+    }
+
+    pub fn frequency_hz(&self) -> f32 {
+        131_072.0 / (2048 - self.frequency) as f32
+    }
+
+    fn step(&mut self) {
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency as u32) * 4;
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+        self.timer -= 1;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_WAVEFORMS[self.duty as usize][self.duty_position] * self.envelope.volume
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = (2048 - self.frequency as u32) * 4;
+        let frequency = self.frequency;
+        let mut overflowed = false;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow_frequency = frequency;
+            sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            overflowed = sweep.shift != 0 && sweep.next_frequency() > 2047;
+        }
+        if overflowed {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let sweep = match self.sweep.as_mut() {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        let new_frequency = sweep.next_frequency();
+        if new_frequency > 2047 {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow_frequency = new_frequency;
+            self.frequency = new_frequency;
+            if sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+}
+
+pub struct WaveChannel {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    pub output_level: u8,
+    pub frequency: u16,
+    pub length: LengthCounter,
+    timer: u32,
+    position: usize,
+}
+
+impl WaveChannel {
+    fn init() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            output_level: 0,
+            frequency: 0,
+            length: LengthCounter::init(256),
+            timer: 0,
+            position: 0,
+        }
+    }
+
+    pub fn frequency_hz(&self) -> f32 {
+        65_536.0 / (2048 - self.frequency) as f32
+    }
+
+    // output level 0 mutes, then 100%, 50% and 25%
+    pub fn volume_shift(&self) -> u8 {
+        match self.output_level {
+            0 => 4,
+            1 => 0,
+            2 => 1,
+            _ => 2,
+        }
+    }
+
+    fn step(&mut self) {
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency as u32) * 2;
+            self.position = (self.position + 1) % 32;
+        }
+        self.timer -= 1;
+    }
+
+    fn output(&self, wave_ram: &[u8; 16]) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let byte = wave_ram[self.position / 2];
+        let sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+        sample >> self.volume_shift()
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = (2048 - self.frequency as u32) * 2;
+        self.position = 0;
+    }
+}
+
+pub struct NoiseChannel {
+    pub enabled: bool,
+    pub clock_shift: u8,
+    pub short_mode: bool,
+    pub divisor_code: u8,
+    pub length: LengthCounter,
+    pub envelope: VolumeEnvelope,
+    timer: u32,
+    lfsr: u16,
+}
+
+impl NoiseChannel {
+    fn init() -> Self {
+        Self {
+            enabled: false,
+            clock_shift: 0,
+            short_mode: false,
+            divisor_code: 0,
+            length: LengthCounter::init(64),
+            envelope: VolumeEnvelope::init(),
+            timer: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    pub fn frequency_hz(&self) -> f32 {
+        CPU_FREQUENCY as f32 / self.period() as f32
+    }
+
+    fn read_polynomial(&self) -> u8 {
+        self.clock_shift << 4 | if self.short_mode { 0x08 } else { 0x00 } | self.divisor_code
+    }
+
+    fn write_polynomial(&mut self, data: u8) {
+        self.clock_shift = data >> 4;
+        self.short_mode = data & 0x08 != 0;
+        self.divisor_code = data & 0x07;
+    }
+
+    fn step(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period();
+            let xor_bit = (self.lfsr & 0x01) ^ ((self.lfsr >> 1) & 0x01);
+            self.lfsr = (self.lfsr >> 1) | (xor_bit << 14);
+            if self.short_mode {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (xor_bit << 6);
+            }
+        }
+        self.timer -= 1;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 0x01 != 0 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+    }
+}
 
+pub struct Apu {
+    pub model: Model,
+    pub powered: bool,
+    pub channel1: SquareChannel,
+    pub channel2: SquareChannel,
+    pub channel3: WaveChannel,
+    pub channel4: NoiseChannel,
+    pub wave_ram: [u8; 16],
+    pub master_volume: u8,  // NR50
+    pub panning: u8,        // NR51
+    // emulator-side mutes, these don't change what the game sees in NR52
+    pub channel_muted: [bool; 4],
+    pub master_muted: bool,
+    frame_sequencer_cycles: u32,
+    frame_sequencer_step: u8,
+    sample_counter: u32,
+    samples: Vec<f32>,  // interleaved stereo, left then right
+    oscilloscopes: [VecDeque<f32>; 4],
 }
 
 impl Apu {
-    pub fn init() -> Self {
+    pub fn init(model: Model) -> Self {
         Self {
+            model,
+            powered: true,
+            channel1: SquareChannel::init(true),
+            channel2: SquareChannel::init(false),
+            channel3: WaveChannel::init(),
+            channel4: NoiseChannel::init(),
+            wave_ram: [0x0; 16],
+            master_volume: 0x77,
+            panning: 0xF3,
+            channel_muted: [false; 4],
+            master_muted: false,
+            frame_sequencer_cycles: 0,
+            frame_sequencer_step: 0,
+            sample_counter: 0,
+            samples: Vec::new(),
+            oscilloscopes: [
+                VecDeque::from(vec![0.0; OSCILLOSCOPE_SAMPLES]),
+                VecDeque::from(vec![0.0; OSCILLOSCOPE_SAMPLES]),
+                VecDeque::from(vec![0.0; OSCILLOSCOPE_SAMPLES]),
+                VecDeque::from(vec![0.0; OSCILLOSCOPE_SAMPLES]),
+            ],
+        }
+    }
+
+    pub fn run_cycles(&mut self, cpu_clock_cycles: u32) {
+        for _ in 0 .. cpu_clock_cycles {
+            if self.powered {
+                self.channel1.step();
+                self.channel2.step();
+                self.channel3.step();
+                self.channel4.step();
+                self.frame_sequencer_cycles += 1;
+                if self.frame_sequencer_cycles == FRAME_SEQUENCER_PERIOD {
+                    self.frame_sequencer_cycles = 0;
+                    self.clock_frame_sequencer();
+                }
+            }
+            self.sample_counter += SAMPLE_RATE;
+            if self.sample_counter >= CPU_FREQUENCY {
+                self.sample_counter -= CPU_FREQUENCY;
+                self.generate_sample();
+            }
+        }
+    }
 
+    // https://gbdev.io/pandocs/Audio_details.html#div-apu
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        if step.is_multiple_of(2) {
+            if self.channel1.length.clock() { self.channel1.enabled = false; }
+            if self.channel2.length.clock() { self.channel2.enabled = false; }
+            if self.channel3.length.clock() { self.channel3.enabled = false; }
+            if self.channel4.length.clock() { self.channel4.enabled = false; }
+        }
+        if step == 2 || step == 6 {
+            self.channel1.clock_sweep();
+        }
+        if step == 7 {
+            self.channel1.envelope.clock();
+            self.channel2.envelope.clock();
+            self.channel4.envelope.clock();
         }
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    // digital outputs 0-15 of each channel, before the DACs
+    pub fn channel_outputs(&self) -> [u8; 4] {
+        [
+            self.channel1.output(),
+            self.channel2.output(),
+            self.channel3.output(&self.wave_ram),
+            self.channel4.output(),
+        ]
+    }
+
+    pub fn channel_enabled(&self, channel: usize) -> bool {
+        match channel {
+            0 => self.channel1.enabled,
+            1 => self.channel2.enabled,
+            2 => self.channel3.enabled,
+            3 => self.channel4.enabled,
+            _ => panic!("Invalid apu channel index: {}", channel),
+        }
+    }
+
+    fn dac_enabled(&self, channel: usize) -> bool {
+        match channel {
+            0 => self.channel1.envelope.dac_enabled(),
+            1 => self.channel2.envelope.dac_enabled(),
+            2 => self.channel3.dac_enabled,
+            3 => self.channel4.envelope.dac_enabled(),
+            _ => panic!("Invalid apu channel index: {}", channel),
+        }
+    }
+
+    fn generate_sample(&mut self) {
+        let outputs = self.channel_outputs();
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (channel, &output) in outputs.iter().enumerate() {
+            // https://gbdev.io/pandocs/Audio_details.html#dacs
+            let analog = if self.dac_enabled(channel) {
+                output as f32 / 7.5 - 1.0
+            } else {
+                0.0
+            };
+            let scope = &mut self.oscilloscopes[channel];
+            scope.pop_front();
+            scope.push_back(analog);
+            if self.channel_muted[channel] {
+                continue;
+            }
+            if self.panning & (0x10 << channel) != 0 {
+                left += analog;
+            }
+            if self.panning & (0x01 << channel) != 0 {
+                right += analog;
+            }
+        }
+        let left_volume = ((self.master_volume >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (self.master_volume & 0x07) as f32 + 1.0;
+        let (left, right) = if self.master_muted || !self.powered {
+            (0.0, 0.0)
+        } else {
+            (left / 4.0 * left_volume / 8.0, right / 4.0 * right_volume / 8.0)
+        };
+        if self.samples.len() >= MAX_BUFFERED_SAMPLES {
+            // nobody is draining samples (e.g. stopped audio device), keep only the most recent ones
+            self.samples.drain(.. MAX_BUFFERED_SAMPLES / 2);
+        }
+        self.samples.push(left);
+        self.samples.push(right);
+    }
+
+    // interleaved stereo samples at SAMPLE_RATE generated since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    // the most recent OSCILLOSCOPE_SAMPLES dac outputs of a channel, in the range -1.0 ..= 1.0
+    pub fn oscilloscope(&self, channel: usize) -> Vec<f32> {
+        self.oscilloscopes[channel].iter().copied().collect()
+    }
+
+    // wave ram as the 32 4-bit samples played by channel 3
+    pub fn wave_samples(&self) -> Vec<f32> {
+        self.wave_ram.iter()
+            .flat_map(|byte| [(byte >> 4) as f32, (byte & 0x0F) as f32])
+            .collect()
+    }
+
+    fn power_off(&mut self) {
+        let wave_ram = self.wave_ram;
+        let channel_muted = self.channel_muted;
+        let master_muted = self.master_muted;
+        let lengths = [self.channel1.length.counter, self.channel2.length.counter,
+            self.channel3.length.counter, self.channel4.length.counter];
+        *self = Apu::init(self.model);
+        // https://gbdev.io/pandocs/Audio_details.html#power-control
+        // only the CGB clears the length counters when the apu is turned off
+        if !self.model.is_cgb() {
+            self.channel1.length.counter = lengths[0];
+            self.channel2.length.counter = lengths[1];
+            self.channel3.length.counter = lengths[2];
+            self.channel4.length.counter = lengths[3];
+        }
+        self.powered = false;
+        self.master_volume = 0x00;
+        self.panning = 0x00;
+        self.wave_ram = wave_ram;
+        self.channel_muted = channel_muted;
+        self.master_muted = master_muted;
     }
 }
 
 impl Memory for Apu {
+    // https://gbdev.io/pandocs/Audio_Registers.html
     fn read8(&self, addr: u16) -> u8 {
         match addr {
-           _ => panic!("unimplemented address read on Apu {:#04x}", addr)
+            0xFF10 => self.channel1.sweep.as_ref().unwrap().read(),
+            0xFF11 => 0x3F | self.channel1.duty << 6,
+            0xFF12 => self.channel1.envelope.read(),
+            0xFF13 => 0xFF,
+            0xFF14 => 0xBF | if self.channel1.length.enabled { 0x40 } else { 0x00 },
+            0xFF15 => 0xFF,
+            0xFF16 => 0x3F | self.channel2.duty << 6,
+            0xFF17 => self.channel2.envelope.read(),
+            0xFF18 => 0xFF,
+            0xFF19 => 0xBF | if self.channel2.length.enabled { 0x40 } else { 0x00 },
+            0xFF1A => 0x7F | if self.channel3.dac_enabled { 0x80 } else { 0x00 },
+            0xFF1B => 0xFF,
+            0xFF1C => 0x9F | self.channel3.output_level << 5,
+            0xFF1D => 0xFF,
+            0xFF1E => 0xBF | if self.channel3.length.enabled { 0x40 } else { 0x00 },
+            0xFF1F => 0xFF,
+            0xFF20 => 0xFF,
+            0xFF21 => self.channel4.envelope.read(),
+            0xFF22 => self.channel4.read_polynomial(),
+            0xFF23 => 0xBF | if self.channel4.length.enabled { 0x40 } else { 0x00 },
+            0xFF24 => self.master_volume,
+            0xFF25 => self.panning,
+            0xFF26 => {
+                let mut nr52 = 0x70 | if self.powered { 0x80 } else { 0x00 };
+                for channel in 0 .. 4 {
+                    if self.channel_enabled(channel) {
+                        nr52 |= 1 << channel;
+                    }
+                }
+                nr52
+            }
+            0xFF30 ..= 0xFF3F => self.wave_ram[(addr - 0xFF30) as usize],
+            _ => panic!("unimplemented address read on Apu {:#04x}", addr)
         }
     }
 
     fn write8(&mut self, addr: u16, data: u8) {
+        if !self.powered && addr != 0xFF26 && !(0xFF30 ..= 0xFF3F).contains(&addr) {
+            // registers are read-only while the apu is off, except for the length timers on pre-CGB models
+            if !self.model.is_cgb() {
+                match addr {
+                    0xFF11 => self.channel1.length.load((data & 0x3F) as u16),
+                    0xFF16 => self.channel2.length.load((data & 0x3F) as u16),
+                    0xFF1B => self.channel3.length.load(data as u16),
+                    0xFF20 => self.channel4.length.load((data & 0x3F) as u16),
+                    _ => {}
+                }
+            }
+            return;
+        }
         match addr {
+            0xFF10 => self.channel1.sweep.as_mut().unwrap().write(data),
+            0xFF11 => {
+                self.channel1.duty = data >> 6;
+                self.channel1.length.load((data & 0x3F) as u16);
+            }
+            0xFF12 => {
+                self.channel1.envelope.write(data);
+                if !self.channel1.envelope.dac_enabled() { self.channel1.enabled = false; }
+            }
+            0xFF13 => self.channel1.frequency = (self.channel1.frequency & 0x0700) | data as u16,
+            0xFF14 => {
+                self.channel1.frequency = (self.channel1.frequency & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.channel1.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 { self.channel1.trigger(); }
+            }
+            0xFF15 => {},
+            0xFF16 => {
+                self.channel2.duty = data >> 6;
+                self.channel2.length.load((data & 0x3F) as u16);
+            }
+            0xFF17 => {
+                self.channel2.envelope.write(data);
+                if !self.channel2.envelope.dac_enabled() { self.channel2.enabled = false; }
+            }
+            0xFF18 => self.channel2.frequency = (self.channel2.frequency & 0x0700) | data as u16,
+            0xFF19 => {
+                self.channel2.frequency = (self.channel2.frequency & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.channel2.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 { self.channel2.trigger(); }
+            }
+            0xFF1A => {
+                self.channel3.dac_enabled = data & 0x80 != 0;
+                if !self.channel3.dac_enabled { self.channel3.enabled = false; }
+            }
+            0xFF1B => self.channel3.length.load(data as u16),
+            0xFF1C => self.channel3.output_level = (data >> 5) & 0x03,
+            0xFF1D => self.channel3.frequency = (self.channel3.frequency & 0x0700) | data as u16,
+            0xFF1E => {
+                self.channel3.frequency = (self.channel3.frequency & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.channel3.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 { self.channel3.trigger(); }
+            }
+            0xFF1F => {},
+            0xFF20 => self.channel4.length.load((data & 0x3F) as u16),
+            0xFF21 => {
+                self.channel4.envelope.write(data);
+                if !self.channel4.envelope.dac_enabled() { self.channel4.enabled = false; }
+            }
+            0xFF22 => self.channel4.write_polynomial(data),
+            0xFF23 => {
+                self.channel4.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 { self.channel4.trigger(); }
+            }
+            0xFF24 => self.master_volume = data,
+            0xFF25 => self.panning = data,
+            0xFF26 => {
+                let power = data & 0x80 != 0;
+                if self.powered && !power {
+                    self.power_off();
+                } else if !self.powered && power {
+                    self.powered = true;
+                    self.frame_sequencer_step = 0;
+                }
+            }
+            0xFF30 ..= 0xFF3F => self.wave_ram[(addr - 0xFF30) as usize] = data,
             _ => panic!("unimplemented address write on Apu {:#04x}, value: {:#02x}", addr, data)
         }
     }
 }
- 
\ No newline at end of file
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.counter);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.enabled = reader.read_bool()?;
+        self.counter = reader.read_u16()?;
+        Ok(())
+    }
+}
+
+impl SaveState for VolumeEnvelope {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.initial_volume);
+        writer.write_bool(self.increase);
+        writer.write_u8(self.period);
+        writer.write_u8(self.volume);
+        writer.write_u8(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.initial_volume = reader.read_u8()?;
+        self.increase = reader.read_bool()?;
+        self.period = reader.read_u8()?;
+        self.volume = reader.read_u8()?;
+        self.timer = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for FrequencySweep {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.period);
+        writer.write_bool(self.negate);
+        writer.write_u8(self.shift);
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.timer);
+        writer.write_u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.period = reader.read_u8()?;
+        self.negate = reader.read_bool()?;
+        self.shift = reader.read_u8()?;
+        self.enabled = reader.read_bool()?;
+        self.timer = reader.read_u8()?;
+        self.shadow_frequency = reader.read_u16()?;
+        Ok(())
+    }
+}
+
+impl SaveState for SquareChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.duty);
+        writer.write_u16(self.frequency);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        if let Some(sweep) = &self.sweep {
+            sweep.save_state(writer);
+        }
+        writer.write_u32(self.timer);
+        writer.write_u32(self.duty_position as u32);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.enabled = reader.read_bool()?;
+        self.duty = reader.read_u8()? & 0x03;
+        self.frequency = reader.read_u16()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.load_state(reader)?;
+        }
+        self.timer = reader.read_u32()?;
+        self.duty_position = reader.read_u32()? as usize % 8;
+        Ok(())
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        writer.write_u8(self.output_level);
+        writer.write_u16(self.frequency);
+        self.length.save_state(writer);
+        writer.write_u32(self.timer);
+        writer.write_u32(self.position as u32);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.output_level = reader.read_u8()? & 0x03;
+        self.frequency = reader.read_u16()?;
+        self.length.load_state(reader)?;
+        self.timer = reader.read_u32()?;
+        self.position = reader.read_u32()? as usize % 32;
+        Ok(())
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.clock_shift);
+        writer.write_bool(self.short_mode);
+        writer.write_u8(self.divisor_code);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.write_u32(self.timer);
+        writer.write_u16(self.lfsr);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.enabled = reader.read_bool()?;
+        self.clock_shift = reader.read_u8()?;
+        self.short_mode = reader.read_bool()?;
+        self.divisor_code = reader.read_u8()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.timer = reader.read_u32()?;
+        self.lfsr = reader.read_u16()?;
+        Ok(())
+    }
+}
+
+// the mutes and the sample buffers belong to the emulator, not the game, so they aren't saved
+impl SaveState for Apu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.powered);
+        self.channel1.save_state(writer);
+        self.channel2.save_state(writer);
+        self.channel3.save_state(writer);
+        self.channel4.save_state(writer);
+        writer.write_bytes(&self.wave_ram);
+        writer.write_u8(self.master_volume);
+        writer.write_u8(self.panning);
+        writer.write_u32(self.frame_sequencer_cycles);
+        writer.write_u8(self.frame_sequencer_step);
+        writer.write_u32(self.sample_counter);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.powered = reader.read_bool()?;
+        self.channel1.load_state(reader)?;
+        self.channel2.load_state(reader)?;
+        self.channel3.load_state(reader)?;
+        self.channel4.load_state(reader)?;
+        reader.read_bytes(&mut self.wave_ram)?;
+        self.master_volume = reader.read_u8()?;
+        self.panning = reader.read_u8()?;
+        self.frame_sequencer_cycles = reader.read_u32()?;
+        self.frame_sequencer_step = reader.read_u8()?;
+        self.sample_counter = reader.read_u32()?;
+        Ok(())
+    }
+}
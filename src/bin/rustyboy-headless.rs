@@ -0,0 +1,186 @@
+use std::io::Write;
+use rustyboy::{headless, headless::{Outcome, RunOptions}, main_board::MainBoard, model, model::Model, trace_log::TraceLog};
+
+// with --trace - the report shares stdout with the trace, which may have been closed by head
+macro_rules! report {
+    ($($arg:tt)*) => {
+        let _ = writeln!(std::io::stdout(), $($arg)*);
+    };
+}
+
+// exit codes, 2 is left for bad arguments and roms that can't be loaded
+const EXIT_PASSED: i32 = 0;
+const EXIT_FAILED: i32 = 1;
+const EXIT_TIMED_OUT: i32 = 3;
+
+fn parse_hex_byte(name: &str, text: &str) -> u8 {
+    u8::from_str_radix(text.trim().trim_start_matches("0x"), 16).unwrap_or_else(|_| {
+        eprintln!("{}: expected a hex byte, got {}", name, text);
+        std::process::exit(2);
+    })
+}
+
+// FIRST:LAST, either one may be left out
+fn parse_range(name: &str, text: &str) -> (u64, u64) {
+    let parse = |value: &str, default: u64| if value.is_empty() {
+        default
+    } else {
+        value.parse().unwrap_or_else(|_| {
+            eprintln!("{}: expected FIRST:LAST, got {}", name, text);
+            std::process::exit(2);
+        })
+    };
+    match text.split_once(':') {
+        Some((first, last)) => (parse(first, 0), parse(last, u64::MAX)),
+        None => {
+            eprintln!("{}: expected FIRST:LAST, got {}", name, text);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn main() {
+    let mut romfile = String::from("");
+    let mut boot_rom: Option<String> = None;
+    let mut model_name: Option<String> = None;
+    let mut max_frames: u32 = 60 * 60;
+    let mut blargg = false;
+    let mut mooneye = false;
+    let mut serial_pass: Option<String> = None;
+    let mut serial_fail: Option<String> = None;
+    let mut stop_opcode: Option<String> = None;
+    let mut expected_registers: Option<String> = None;
+    let mut png_path: Option<String> = None;
+    let mut trace_path: Option<String> = None;
+    let mut trace_frames: Option<String> = None;
+    let mut trace_cycles: Option<String> = None;
+    let mut stub_ly = false;
+    {
+        let mut ap = argparse::ArgumentParser::new();
+        ap.set_description("Runs a rom without a display until it passes or fails. \
+            Exits with 0 when it passed, 1 when it failed and 3 when it ran out of frames");
+        ap.refer(&mut romfile).add_argument("rom", argparse::Store, "Rom filename").required();
+        ap.refer(&mut boot_rom).add_option(&["--boot-rom"], argparse::StoreOption,
+            "DMG/MGB/SGB/CGB boot rom to run before the game");
+        ap.refer(&mut model_name).add_option(&["--model"], argparse::StoreOption,
+            "hardware model: dmg0, dmg, mgb, sgb, sgb2, cgb or agb. Detected from the rom header by default");
+        ap.refer(&mut max_frames).add_option(&["--frames"], argparse::Store,
+            "frames to run for at most, passes after that many frames when there's nothing else to wait for");
+        ap.refer(&mut blargg).add_option(&["--blargg"], argparse::StoreTrue,
+            "Blargg test rom, waits for Passed or Failed on the serial port or a result in cartridge ram");
+        ap.refer(&mut mooneye).add_option(&["--mooneye"], argparse::StoreTrue,
+            "Mooneye test rom, waits for LD B,B and checks for the Fibonacci numbers in B, C, D, E, H and L");
+        ap.refer(&mut serial_pass).add_option(&["--serial-pass"], argparse::StoreOption,
+            "pass once the serial output contains this");
+        ap.refer(&mut serial_fail).add_option(&["--serial-fail"], argparse::StoreOption,
+            "fail once the serial output contains this");
+        ap.refer(&mut stop_opcode).add_option(&["--stop-opcode"], argparse::StoreOption,
+            "stop when the cpu is about to execute this opcode, in hex. 40 is LD B,B");
+        ap.refer(&mut expected_registers).add_option(&["--expect-registers"], argparse::StoreOption,
+            "B,C,D,E,H,L in hex that --stop-opcode passes with, e.g. 03,05,08,0D,15,22");
+        ap.refer(&mut png_path).add_option(&["--png"], argparse::StoreOption,
+            "save the final screen to this png file");
+        ap.refer(&mut trace_path).add_option(&["--trace"], argparse::StoreOption,
+            "log every instruction in Gameboy Doctor format to this file, - for stdout");
+        ap.refer(&mut trace_frames).add_option(&["--trace-frames"], argparse::StoreOption,
+            "only trace frames FIRST:LAST, counting from 0");
+        ap.refer(&mut trace_cycles).add_option(&["--trace-cycles"], argparse::StoreOption,
+            "only trace cycles FIRST:LAST, counting from 0");
+        ap.refer(&mut stub_ly).add_option(&["--stub-ly"], argparse::StoreTrue,
+            "LY always reads 0x90, as in the Gameboy Doctor reference logs");
+        ap.parse_args_or_exit();
+    }
+    let model = model_name.map(|name| Model::from_name(&name).unwrap_or_else(|| {
+        eprintln!("unknown model {}, expected one of {}", name, model::MODEL_NAMES.join(", "));
+        std::process::exit(2);
+    }));
+
+    let mut options = if mooneye {
+        RunOptions::mooneye(max_frames)
+    } else if blargg {
+        RunOptions::blargg(max_frames)
+    } else {
+        RunOptions::init(max_frames)
+    };
+    if serial_pass.is_some() {
+        options.serial_pass = serial_pass;
+    }
+    if serial_fail.is_some() {
+        options.serial_fail = serial_fail;
+    }
+    if let Some(opcode) = stop_opcode {
+        options.stop_opcode = Some(parse_hex_byte("--stop-opcode", &opcode));
+    }
+    if let Some(registers) = expected_registers {
+        let values: Vec<u8> = registers.split(',').map(|value| parse_hex_byte("--expect-registers", value)).collect();
+        if values.len() != 6 {
+            eprintln!("--expect-registers: expected 6 values for B,C,D,E,H,L, got {}", values.len());
+            std::process::exit(2);
+        }
+        let mut expected = [0x0; 6];
+        expected.copy_from_slice(&values);
+        options.expected_registers = Some(expected);
+    }
+
+    let mut main_board = MainBoard::init(&romfile[..], boot_rom.as_deref(), model).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    main_board.mmu.borrow_mut().gpu.stub_ly = stub_ly;
+    if let Some(path) = trace_path {
+        let mut trace = TraceLog::create(&path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(2);
+        });
+        if let Some(frames) = trace_frames {
+            let (first, last) = parse_range("--trace-frames", &frames);
+            trace.set_frame_range(first, last);
+        }
+        if let Some(cycles) = trace_cycles {
+            (trace.first_cycle, trace.last_cycle) = parse_range("--trace-cycles", &cycles);
+        }
+        main_board.cpu.trace = Some(trace);
+    }
+    let result = headless::run(&mut main_board, &options);
+    // exiting skips the destructors
+    if let Some(trace) = main_board.cpu.trace.as_mut() {
+        if let Err(e) = trace.flush() {
+            eprintln!("writing the trace log: {}", e);
+        }
+    }
+
+    if !result.serial_output.is_empty() {
+        report!("{}", result.serial_output);
+    }
+    let memory_text = headless::blargg_memory_text(&main_board);
+    if !memory_text.is_empty() {
+        report!("{}", memory_text);
+    }
+    let registers = result.registers;
+    report!("B={:02X} C={:02X} D={:02X} E={:02X} H={:02X} L={:02X}",
+        registers[0], registers[1], registers[2], registers[3], registers[4], registers[5]);
+    if let Some(path) = png_path {
+        if let Err(e) = headless::write_png(&path, main_board.screen_size(), &main_board.screen_rgba()) {
+            eprintln!("{}: {}", path, e);
+        }
+    }
+    let exit_code = match result.outcome {
+        Outcome::Passed => {
+            report!("passed after {} frames", result.frames);
+            EXIT_PASSED
+        }
+        Outcome::Failed => {
+            if mooneye && registers == headless::MOONEYE_FAIL_REGISTERS {
+                report!("failed after {} frames, the test reported a failure", result.frames);
+            } else {
+                report!("failed after {} frames", result.frames);
+            }
+            EXIT_FAILED
+        }
+        Outcome::TimedOut => {
+            report!("timed out after {} frames", result.frames);
+            EXIT_TIMED_OUT
+        }
+    };
+    std::process::exit(exit_code);
+}
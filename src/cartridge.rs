@@ -1,14 +1,41 @@
 // https://gbdev.io/pandocs/The_Cartridge_Header.html
-use std::{fs::File, io::Read};
+use std::io::{Error, ErrorKind};
 use super::memory::Memory;
+use super::save_state::{SaveState, StateReader, StateWriter};
 
 
-pub trait Cartridge: Memory {
+pub trait Cartridge: Memory + SaveState {
     fn get_type(&self) -> String;
+    // the whole rom image, save states are tied to it
+    fn rom(&self) -> &[u8];
+    // the bank mapped at a rom address
+    fn rom_bank(&self, addr: u16) -> usize {
+        if addr < 0x4000 { 0 } else { 1 }
+    }
+    // the whole cartridge ram, empty without any
+    fn ram(&self) -> &[u8] {
+        &[]
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+    // where a 0xA000-0xBFFF address is in the ram, None while the ram can't be accessed
+    fn ram_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+    // https://gbdev.io/pandocs/The_Cartridge_Header.html#0143--cgb-flag
+    fn is_cgb(&self) -> bool {
+        let cgb_flag = self.read8(0x143);
+        cgb_flag == 0x80 || cgb_flag == 0xC0
+    }
+    // https://gbdev.io/pandocs/The_Cartridge_Header.html#0146--sgb-flag
+    // SGB functions also need the old licensee code to be 0x33
+    fn is_sgb(&self) -> bool {
+        self.read8(0x146) == 0x03 && self.read8(0x14B) == 0x33
+    }
     fn get_title(&self) -> String {
         let start = 0x134;
-        let maybe_cgb_flag = self.read8(0x143);
-        let length = if maybe_cgb_flag == 0x80 || maybe_cgb_flag == 0xC0 { 11 } else { 16 };
+        let length = if self.is_cgb() { 11 } else { 16 };
         let mut title_string = String::new();
         for i in start .. start + length {
             match self.read8(i) {
@@ -21,15 +48,17 @@ pub trait Cartridge: Memory {
 }
 
 
-pub fn init(filepath: &str) -> Box<dyn Cartridge> {
-    let mut f = File::open(filepath).unwrap();
-    let mut buffer = Vec::new();
-    f.read_to_end(&mut buffer).unwrap();
+// InvalidData for a file that isn't a rom or a cartridge type that isn't emulated
+pub fn init(filepath: &str) -> std::io::Result<Box<dyn Cartridge>> {
+    let buffer = std::fs::read(filepath)?;
+    if buffer.len() < 2 * ROM_BANK_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{} bytes is smaller than any rom", buffer.len())));
+    }
     let cartridge_type = buffer[0x0147];
     match cartridge_type {
-        0x00 => Box::new(NoMbc::init(buffer)),
-        0x01 ..= 0x03 => Box::new(Mbc1::init(buffer)),
-        _ => panic!("Unknown ROM Cartridge type: {:#02X}", cartridge_type),
+        0x00 => Ok(Box::new(NoMbc::init(buffer))),
+        0x01 ..= 0x03 => Ok(Box::new(Mbc1::init(buffer)?)),
+        _ => Err(Error::new(ErrorKind::InvalidData, format!("unsupported cartridge type {:#04X}", cartridge_type))),
     }
 }
 
@@ -46,7 +75,10 @@ impl NoMbc {
 
 impl Memory for NoMbc {
     fn read8(&self, addr: u16) -> u8 {
-        self.rom[addr as usize]
+        match addr {
+            0x0000 ..= 0x7FFF => self.rom[addr as usize],
+            _ => 0xFF, // no external ram
+        }
     }
     
     fn write8(&mut self, _addr: u16, _data: u8) {
@@ -58,12 +90,27 @@ impl Cartridge for NoMbc {
         "NoMbc".to_string()
     }
 
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
 }
 
-/*enum BankMode {
-    SimpleRomBanking,
-//    RamBankingOrAdvancedRomBanking,
-}*/
+// no mapper, no ram, nothing to save
+impl SaveState for NoMbc {
+    fn save_state(&self, _writer: &mut StateWriter) {
+    }
+
+    fn load_state(&mut self, _reader: &mut StateReader) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum BankMode {
+    Simple,
+    // the 2 bit register also banks 0000-3FFF and the ram
+    Advanced,
+}
 
 /* Mbc1 - A memory bank controller - may have a battery, and may have ram
    https://gbdev.io/pandocs/MBC1.html */
@@ -71,52 +118,72 @@ struct Mbc1 {
     rom: std::vec::Vec<u8>,
     ram: std::vec::Vec<u8>,
     ram_enable: bool,
+    // lower 5 bits of the rom bank number
     rom_bank: u8,
+    // ram bank number, or upper 2 bits of the rom bank number
     ram_bank: u8,
-    // banking_mode_select: BankMode,
+    banking_mode_select: BankMode,
 }
 
 impl Mbc1 {
-    pub fn init(rom_bytes: std::vec::Vec<u8>) -> Self {
+    pub fn init(rom_bytes: std::vec::Vec<u8>) -> std::io::Result<Self> {
         let ram_size: usize = match rom_bytes[0x0149] {
             0x00 | 0x01 => 0,
             0x02 => 8192,
             0x03 => 8192 * 4,
             0x04 => 8192 * 16,
             0x05 => 8192 * 8,
-            _ => panic!("Unrecognized ram_size code: {:#02X}", rom_bytes[0x0149]),
+            code => return Err(Error::new(ErrorKind::InvalidData, format!("unsupported ram size code {:#04X}", code))),
         };
 
-        Mbc1 {
+        Ok(Mbc1 {
             rom: rom_bytes,
             ram: vec![0x0; ram_size],
             ram_enable: false,
             rom_bank: 0x1,
             ram_bank: 0x0,
-            // banking_mode_select: BankMode::SimpleRomBanking,
-        }
+            banking_mode_select: BankMode::Simple,
+        })
+    }
+
+    fn mapped_rom_bank(&self, addr: u16) -> usize {
+        let bank = match addr {
+            0x0000 ..= 0x3FFF if self.banking_mode_select == BankMode::Advanced => (self.ram_bank as usize) << 5,
+            0x0000 ..= 0x3FFF => 0,
+            _ => (self.ram_bank as usize) << 5 | self.rom_bank as usize,
+        };
+        // bank numbers wrap around to the banks the rom actually has
+        bank % std::cmp::max(self.rom.len() / ROM_BANK_SIZE, 1)
+    }
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        self.rom[self.mapped_rom_bank(addr) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1))]
+    }
+
+    fn ram_addr(&self, addr: u16) -> usize {
+        let bank = if self.banking_mode_select == BankMode::Advanced { self.ram_bank as usize } else { 0 };
+        (bank * RAM_BANK_SIZE + (addr - 0xA000) as usize) % self.ram.len()
     }
 }
 
-const ROM_BANK_SIZE: usize = 16_384; //16KiB
-const RAM_BANK_SIZE: usize = 2048; //2KiB
+pub const ROM_BANK_SIZE: usize = 16_384; //16KiB
+pub const RAM_BANK_SIZE: usize = 8192; //8KiB
 
 
 impl Memory for Mbc1 {
     fn read8(&self, addr: u16) -> u8 {
         match addr {
             // ROM Bank X0
-            0x0000 ..= 0x3FFF => self.rom[addr as usize],
+            0x0000 ..= 0x3FFF => self.read_rom(addr),
             // Rom Bank 01-7F
             0x4000 ..= 0x7FFF => {
                 assert!(self.rom_bank != 0);
-                let physical_addr = self.rom_bank as usize * ROM_BANK_SIZE + addr as usize;
-                self.rom[physical_addr]
+                self.read_rom(addr)
             }
             // RAM Bank 00-03, if any
             0xA000 ..= 0xBFFF => {
-                if self.ram_enable {
-                    self.ram[addr as usize + self.ram_bank as usize * RAM_BANK_SIZE - 0xA000]
+                if self.ram_enable && !self.ram.is_empty() {
+                    self.ram[self.ram_addr(addr)]
                 } else {
                     0xFF
                 }
@@ -124,8 +191,24 @@ impl Memory for Mbc1 {
             _ => panic!("Unmapped memory in Mbc1: {:#04x}", addr),
         }
     }
-    
-    fn write8(&mut self, _addr: u16, _data: u8) {
+
+    fn write8(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000 ..= 0x1FFF => self.ram_enable = data & 0x0F == 0x0A,
+            // writing bank 0 selects bank 1
+            0x2000 ..= 0x3FFF => self.rom_bank = std::cmp::max(data & 0x1F, 1),
+            0x4000 ..= 0x5FFF => self.ram_bank = data & 0x03,
+            0x6000 ..= 0x7FFF => {
+                self.banking_mode_select = if data & 0x01 != 0 { BankMode::Advanced } else { BankMode::Simple };
+            }
+            0xA000 ..= 0xBFFF => {
+                if self.ram_enable && !self.ram.is_empty() {
+                    let ram_addr = self.ram_addr(addr);
+                    self.ram[ram_addr] = data;
+                }
+            }
+            _ => panic!("Unmapped memory in Mbc1: {:#04x}", addr),
+        }
     }
 }
 
@@ -133,6 +216,45 @@ impl Cartridge for Mbc1 {
     fn get_type(&self) -> String {
         "Mbc1".to_string()
     }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_bank(&self, addr: u16) -> usize {
+        self.mapped_rom_bank(addr)
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        (self.ram_enable && !self.ram.is_empty()).then(|| self.ram_addr(addr))
+    }
+}
+
+impl SaveState for Mbc1 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enable);
+        writer.write_u8(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        writer.write_bool(self.banking_mode_select == BankMode::Advanced);
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.ram_enable = reader.read_bool()?;
+        self.rom_bank = std::cmp::max(reader.read_u8()? & 0x1F, 1);
+        self.ram_bank = reader.read_u8()? & 0x03;
+        self.banking_mode_select = if reader.read_bool()? { BankMode::Advanced } else { BankMode::Simple };
+        reader.read_bytes(&mut self.ram)?;
+        Ok(())
+    }
 }
 
 
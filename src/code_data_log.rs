@@ -0,0 +1,48 @@
+use std::path::Path;
+
+// How each rom and cartridge ram byte was used while playing, to tell code from data where static tracing
+// can't, as with jumps through tables. The log is kept in a .cdl file next to the rom and builds up
+// over play sessions. The file is one byte of flags per rom byte, followed by one per cartridge ram byte.
+pub const EXECUTED: u8 = 0x01;
+pub const OPERAND: u8 = 0x02;
+pub const DATA: u8 = 0x04;
+// copied by OAM DMA or HDMA
+pub const DMA_SOURCE: u8 = 0x08;
+
+pub struct CodeDataLog {
+    pub rom: Vec<u8>,
+    pub ram: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn init(rom_size: usize, ram_size: usize) -> Self {
+        Self { rom: vec![0x00; rom_size], ram: vec![0x00; ram_size] }
+    }
+
+    // a missing file or one for a different rom size gives an empty log
+    pub fn load(filepath: &str, rom_size: usize, ram_size: usize) -> Self {
+        let mut log = Self::init(rom_size, ram_size);
+        if let Ok(contents) = std::fs::read(filepath) {
+            if contents.len() == rom_size + ram_size {
+                let (rom, ram) = contents.split_at(rom_size);
+                log.rom.copy_from_slice(rom);
+                log.ram.copy_from_slice(ram);
+            }
+        }
+        log
+    }
+
+    // game.gb has game.cdl
+    pub fn path_for_rom(rom_path: &str) -> String {
+        Path::new(rom_path).with_extension("cdl").to_string_lossy().into_owned()
+    }
+
+    pub fn save(&self, filepath: &str) -> std::io::Result<()> {
+        std::fs::write(filepath, [&self.rom[..], &self.ram[..]].concat())
+    }
+}
+
+// read as data or copied, but never run
+pub fn is_data(flags: u8) -> bool {
+    flags & (DATA | DMA_SOURCE) != 0 && flags & (EXECUTED | OPERAND) == 0
+}
@@ -0,0 +1,63 @@
+use super::gpu::rgb555_to_rgb888;
+
+// CGB games were made for a dim, washed out LCD. Showing their RGB555 colors as-is on a modern
+// display looks far too saturated, so these curves approximate what the original screens showed.
+// Based on the color matrices of Pokefan531's handheld color shaders.
+#[derive(Copy, Clone, PartialEq)]
+pub enum ColorCorrection {
+    None = 0,
+    GbcLcd = 1,
+    GbaSp = 2,
+}
+
+pub const COLOR_CORRECTIONS: [ColorCorrection; 3] = [ColorCorrection::None, ColorCorrection::GbcLcd, ColorCorrection::GbaSp];
+pub const COLOR_CORRECTION_NAMES: [&str; 3] = ["None", "GBC LCD", "GBA SP"];
+
+struct Profile {
+    // gamma of the original screen, colors are linearized with it before mixing
+    target_gamma: f32,
+    // gamma of the display we are drawing to
+    display_gamma: f32,
+    luminance: f32,
+    // rows are the output red, green and blue, columns the input red, green and blue
+    matrix: [[f32; 3]; 3],
+}
+
+const GBC_LCD: Profile = Profile {
+    target_gamma: 2.2,
+    display_gamma: 2.2,
+    luminance: 0.94,
+    matrix: [
+        [0.82, 0.24, -0.06],
+        [0.125, 0.665, 0.21],
+        [0.195, 0.075, 0.73],
+    ],
+};
+
+const GBA_SP: Profile = Profile {
+    target_gamma: 2.0,
+    display_gamma: 2.2,
+    luminance: 0.93,
+    matrix: [
+        [0.845, 0.17, -0.015],
+        [0.09, 0.68, 0.23],
+        [0.16, 0.085, 0.755],
+    ],
+};
+
+pub fn correct(color_correction: ColorCorrection, color: u16) -> [u8; 3] {
+    match color_correction {
+        ColorCorrection::None => rgb555_to_rgb888(color),
+        ColorCorrection::GbcLcd => apply_profile(&GBC_LCD, color),
+        ColorCorrection::GbaSp => apply_profile(&GBA_SP, color),
+    }
+}
+
+fn apply_profile(profile: &Profile, color: u16) -> [u8; 3] {
+    let linear = [color & 0x1F, (color >> 5) & 0x1F, (color >> 10) & 0x1F]
+        .map(|channel| (channel as f32 / 31.0).powf(profile.target_gamma) * profile.luminance);
+    profile.matrix.map(|row| {
+        let mixed = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+        (mixed.clamp(0.0, 1.0).powf(1.0 / profile.display_gamma) * 255.0).round() as u8
+    })
+}
@@ -1,67 +1,12 @@
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::VecDeque;
+use super::instruction;
+use super::instruction::{AluOp, Address, Condition, Decoded, Instruction, R16, R8, ShiftOp};
 use super::memory::Memory;
-
-pub const OP_MNEMONICS: [&str; 256] = [
-    "NOP", "LD BC,d16", "LD (BC),A", "INC BC", "INC B", "DEC B", "LD B,d8", "RLCA", "LD (a16),SP", "ADD HL,BC", "LD A,(BC)", "DEC BC", "INC C", "DEC C", "LD C,d8", "RRCA",
-    "STOP 0", "LD DE,d16", "LD (DE),A", "INC DE", "INC D", "DEC D", "LD D,d8", "RLA", "JR r8", "ADD HL,DE", "LD A,(DE)", "DEC DE", "INC E", "DEC E", "LD E,d8", "RRA",
-    "JR NZ,r8", "LD HL,d16", "LD (HL+),A", "INC HL", "INC H", "DEC H", "LD H,d8", "DAA", "JR Z,r8", "ADD HL,HL", "LD A,(HL+)", "DEC HL", "INC L", "DEC L", "LD L,d8", "CPL",
-    "JR NC,r8", "LD SP,d16", "LD (HL-),A", "INC SP", "INC (HL)", "DEC (HL)", "LD (HL),d8", "SCF", "JR C,r8", "ADD HL,SP", "LD A,(HL-)", "DEC SP", "INC A", "DEC A", "LD A,d8", "CCF",
-    "LD B,B", "LD B,C", "LD B,D", "LD B,E", "LD B,H", "LD B,L", "LD B,(HL)", "LD B,A", "LD C,B", "LD C,C", "LD C,D", "LD C,E", "LD C,H", "LD C,L", "LD C,(HL)", "LD C,A",
-    "LD D,B", "LD D,C", "LD D,D", "LD D,E", "LD D,H", "LD D,L", "LD D,(HL)", "LD D,A", "LD E,B", "LD E,C", "LD E,D", "LD E,E", "LD E,H", "LD E,L", "LD E,(HL)", "LD E,A",
-    "LD H,B", "LD H,C", "LD H,D", "LD H,E", "LD H,H", "LD H,L", "LD H,(HL)", "LD H,A", "LD L,B", "LD L,C", "LD L,D", "LD L,E", "LD L,H", "LD L,L", "LD L,(HL)", "LD L,A",
-    "LD (HL),B", "LD (HL),C", "LD (HL),D", "LD (HL),E", "LD (HL),H", "LD (HL),L", "HALT", "LD (HL),A", "LD A,B", "LD A,C", "LD A,D", "LD A,E", "LD A,H", "LD A,L", "LD A,(HL)", "LD A,A",
-    "ADD A,B", "ADD A,C", "ADD A,D", "ADD A,E", "ADD A,H", "ADD A,L", "ADD A,(HL)", "ADD A,A", "ADC A,B", "ADC A,C", "ADC A,D", "ADC A,E", "ADC A,H", "ADC A,L", "ADC A,(HL)", "ADC A,A",
-    "SUB B", "SUB C", "SUB D", "SUB E", "SUB H", "SUB L", "SUB (HL)", "SUB A", "SBC A,B", "SBC A,C", "SBC A,D", "SBC A,E", "SBC A,H", "SBC A,L", "SBC A,(HL)", "SBC A,A",
-    "AND B", "AND C", "AND D", "AND E", "AND H", "AND L", "AND (HL)", "AND A", "XOR B", "XOR C", "XOR D", "XOR E", "XOR H", "XOR L", "XOR (HL)", "XOR A",
-    "OR B", "OR C", "OR D", "OR E", "OR H", "OR L", "OR (HL)", "OR A", "CP B", "CP C", "CP D", "CP E", "CP H", "CP L", "CP (HL)", "CP A",
-    "RET NZ", "POP BC", "JP NZ,a16", "JP a16", "CALL NZ,a16", "PUSH BC", "ADD A,d8", "RST 00H", "RET Z", "RET", "JP Z,a16", "PREFIX CB", "CALL Z,a16", "CALL a16", "ADC A,d8", "RST 08H",
-    "RET NC", "POP DE", "JP NC,a16", "UNKNOWN", "CALL NC,a16", "PUSH DE", "SUB d8", "RST 10H", "RET C", "RETI", "JP C,a16", " UNKNOWN", "CALL C,a16", "UNKNOWN", "SBC A,d8", "RST 18H",
-    "LDH (a8),A", "POP HL", "LD (C),A", "UNKNOWN", "UNKNOWN", "PUSH HL", "AND d8", "RST 20H", "ADD SP,r8", "JP (HL)", "LD (a16),A", "UNKNOWN", "UNKNOWN", "UNKNOWN", "XOR d8", "RST 28H",
-    "LDH A,(a8)", "POP AF", "LD A,(C)", "DI", "UNKNOWN", "PUSH AF", "OR d8", "RST 30H", "LD HL,SP+r8", "LD SP,HL", "LD A,(a16)", "EI", "UNKNOWN", "UNKNOWN", "CP d8", "RST 38H",
-];
-
-// note, '0xcb' in this table is marked as size 1 as that's a special prefix opcode that tells us to interpret the next byte as a 0xcb-prefixed opcode.
-// note, unknown opcodes (e.g. 0xD4) in this table are given size 1
-pub const OP_SIZES: [u8; 256] = [
-    1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1,
-    1, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
-    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
-    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 1, 3, 3, 2, 1,
-    1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1,
-    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1,
-    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1,
-];
-
-pub const OP_CB_MNEMONICS: [&str; 256] = [
-    "RLC B", "RLC C", "RLC D", "RLC E", "RLC H", "RLC L", "RLC (HL)", "RLC A", "RRC B", "RRC C", "RRC D", "RRC E", "RRC H", "RRC L", "RRC (HL)", "RRC A",
-    "RL B", "RL C", "RL D", "RL E", "RL H", "RL L", "RL (HL)", "RL A", "RR B", "RR C", "RR D", "RR E", "RR H", "RR L", "RR (HL)", "RR A",
-    "SLA B", "SLA C", "SLA D", "SLA E", "SLA H", "SLA L", "SLA (HL)", "SLA A", "SRA B", "SRA C", "SRA D", "SRA E", "SRA H", "SRA L", "SRA (HL)", "SRA A",
-    "SWAP B", "SWAP C", "SWAP D", "SWAP E", "SWAP H", "SWAP L", "SWAP (HL)", "SWAP A", "SRL B", "SRL C", "SRL D", "SRL E", "SRL H", "SRL L", "SRL (HL)", "SRL A",
-    "BIT 0,B", "BIT 0,C", "BIT 0,D", "BIT 0,E", "BIT 0,H", "BIT 0,L", "BIT 0,(HL)", "BIT 0,A", "BIT 1,B", "BIT 1,C", "BIT 1,D", "BIT 1,E", "BIT 1,H", "BIT 1,L", "BIT 1,(HL)", "BIT 1,A",
-    "BIT 2,B", "BIT 2,C", "BIT 2,D", "BIT 2,E", "BIT 2,H", "BIT 2,L", "BIT 2,(HL)", "BIT 2,A", "BIT 3,B", "BIT 3,C", "BIT 3,D", "BIT 3,E", "BIT 3,H", "BIT 3,L", "BIT 3,(HL)", "BIT 3,A",
-    "BIT 4,B", "BIT 4,C", "BIT 4,D", "BIT 4,E", "BIT 4,H", "BIT 4,L", "BIT 4,(HL)", "BIT 4,A", "BIT 5,B", "BIT 5,C", "BIT 5,D", "BIT 5,E", "BIT 5,H", "BIT 5,L", "BIT 5,(HL)", "BIT 5,A",
-    "BIT 6,B", "BIT 6,C", "BIT 6,D", "BIT 6,E", "BIT 6,H", "BIT 6,L", "BIT 6,(HL)", "BIT 6,A", "BIT 7,B", "BIT 7,C", "BIT 7,D", "BIT 7,E", "BIT 7,H", "BIT 7,L", "BIT 7,(HL)", "BIT 7,A",
-    "RES 0,B", "RES 0,C", "RES 0,D", "RES 0,E", "RES 0,H", "RES 0,L", "RES 0,(HL)", "RES 0,A", "RES 1,B", "RES 1,C", "RES 1,D", "RES 1,E", "RES 1,H", "RES 1,L", "RES 1,(HL)", "RES 1,A",
-    "RES 2,B", "RES 2,C", "RES 2,D", "RES 2,E", "RES 2,H", "RES 2,L", "RES 2,(HL)", "RES 2,A", "RES 3,B", "RES 3,C", "RES 3,D", "RES 3,E", "RES 3,H", "RES 3,L", "RES 3,(HL)", "RES 3,A",
-    "RES 4,B", "RES 4,C", "RES 4,D", "RES 4,E", "RES 4,H", "RES 4,L", "RES 4,(HL)", "RES 4,A", "RES 5,B", "RES 5,C", "RES 5,D", "RES 5,E", "RES 5,H", "RES 5,L", "RES 5,(HL)", "RES 5,A",
-    "RES 6,B", "RES 6,C", "RES 6,D", "RES 6,E", "RES 6,H", "RES 6,L", "RES 6,(HL)", "RES 6,A", "RES 7,B", "RES 7,C", "RES 7,D", "RES 7,E", "RES 7,H", "RES 7,L", "RES 7,(HL)", "RES 7,A",
-    "SET 0,B", "SET 0,C", "SET 0,D", "SET 0,E", "SET 0,H", "SET 0,L", "SET 0,(HL)", "SET 0,A", "SET 1,B", "SET 1,C", "SET 1,D", "SET 1,E", "SET 1,H", "SET 1,L", "SET 1,(HL)", "SET 1,A",
-    "SET 2,B", "SET 2,C", "SET 2,D", "SET 2,E", "SET 2,H", "SET 2,L", "SET 2,(HL)", "SET 2,A", "SET 3,B", "SET 3,C", "SET 3,D", "SET 3,E", "SET 3,H", "SET 3,L", "SET 3,(HL)", "SET 3,A",
-    "SET 4,B", "SET 4,C", "SET 4,D", "SET 4,E", "SET 4,H", "SET 4,L", "SET 4,(HL)", "SET 4,A", "SET 5,B", "SET 5,C", "SET 5,D", "SET 5,E", "SET 5,H", "SET 5,L", "SET 5,(HL)", "SET 5,A",
-    "SET 6,B", "SET 6,C", "SET 6,D", "SET 6,E", "SET 6,H", "SET 6,L", "SET 6,(HL)", "SET 6,A", "SET 7,B", "SET 7,C", "SET 7,D", "SET 7,E", "SET 7,H", "SET 7,L", "SET 7,(HL)", "SET 7,A",
-];
-
-pub const OP_CB_SIZE: u8 = 2;  // each operation that starts with a 0xcb prefix has one additional byte for a total of 2
+use super::model::Model;
+use super::save_state::{SaveState, StateReader, StateWriter};
+use super::trace_log::TraceLog;
 
 // https://gbdev.io/pandocs/CPU_Registers_and_Flags.html
 #[derive(Copy, Clone)]
@@ -100,9 +45,24 @@ enum IncrementMode {
 
 
 
+// bounds the shadow call stack when code leaves calls without returning from them
+pub const MAX_CALL_DEPTH: usize = 256;
+
+// a CALL, RST or interrupt that hasn't returned yet
+#[derive(Copy, Clone)]
+pub struct CallFrame {
+    // the called function or interrupt vector
+    pub target: u16,
+    pub return_addr: u16,
+    // where the return address was pushed
+    pub sp: u16,
+    pub interrupt: bool,
+}
+
 pub struct Cpu {
     pub mmu: Rc<RefCell<dyn Memory>>,
-    flags: u8,
+    // Z N H C in the upper nibble, the lower one is always zero
+    pub flags: u8,
     pub a: u8,
     pub b: u8,
     pub c: u8,
@@ -112,27 +72,104 @@ pub struct Cpu {
     pub l: u8,
     pub sp: u16,  // stack pointer
     pub pc: u16,  // program counter
+    // set by STOP. The main board either performs a CGB speed switch or waits for a button press
+    pub stopped: bool,
+    // https://gbdev.io/pandocs/Interrupts.html
+    pub ime: bool,
+    // EI only takes effect after the instruction that follows it
+    ime_scheduled: bool,
+    pub halted: bool,
+    // https://gbdev.io/pandocs/halt.html#halt-bug
+    halt_bug: bool,
+    // logs every instruction when set
+    pub trace: Option<TraceLog>,
+    // shadow call stack for the debugger, innermost call last
+    pub call_stack: VecDeque<CallFrame>,
+    // calls that haven't returned, counting the ones dropped from call_stack past MAX_CALL_DEPTH
+    pub call_depth: usize,
 }
 
 
 impl Cpu {
-    pub fn init(mmu: Rc<RefCell<dyn Memory>>) -> Cpu {
-        // https://gbdev.io/pandocs/Power_Up_Sequence.html
+    pub fn init(mmu: Rc<RefCell<dyn Memory>>, model: Model, cgb_mode: bool) -> Cpu {
+        let mut cpu = Cpu::init_for_boot_rom(mmu);
+        cpu.sp = 0xFFFE;
+        cpu.pc = 0x0100;
+        // https://gbdev.io/pandocs/Power_Up_Sequence.html#cpu-registers
+        // (A, F, B, C, D, E, H, L) as left behind by each model's boot rom
+        let registers = match model {
+            Model::Dmg0 => (0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03),
+            Model::Dmg | Model::Mgb => {
+                let a = if model == Model::Mgb { 0xFF } else { 0x01 };
+                // half-carry and carry are only clear when the header checksum is zero
+                let f = if cpu.mmu.borrow().read8(0x014D) == 0x00 { Flag::Z as u8 } else { Flag::Z as u8 | Flag::H as u8 | Flag::C as u8 };
+                (a, f, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D)
+            },
+            Model::Sgb => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::Sgb2 => (0xFF, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::Cgb | Model::Agb => {
+                let (mut f, mut b, d, e, h, l) = if cgb_mode {
+                    (Flag::Z as u8, 0x00, 0xFF, 0x56, 0x00, 0x0D)
+                } else {
+                    (Flag::Z as u8, cpu.title_checksum(), 0x00, 0x08, 0x99, 0x1A)
+                };
+                if model == Model::Agb {
+                    // the GBA boot rom ends with an extra INC B
+                    b = b.wrapping_add(1);
+                    f = (if b & 0x0F == 0x00 { Flag::H as u8 } else { 0x00 }) | (if b == 0x00 { Flag::Z as u8 } else { 0x00 });
+                }
+                (0x11, f, b, 0x00, d, e, h, l)
+            },
+        };
+        let (a, f, b, c, d, e, h, l) = registers;
+        cpu.a = a;
+        cpu.flags = f;
+        cpu.b = b;
+        cpu.c = c;
+        cpu.d = d;
+        cpu.e = e;
+        cpu.h = h;
+        cpu.l = l;
+        cpu
+    }
+
+    // the CGB boot rom sums the title of Nintendo published games to pick a DMG compatibility palette
+    fn title_checksum(&self) -> u8 {
+        let mmu = self.mmu.borrow();
+        let old_licensee = mmu.read8(0x014B);
+        let nintendo = old_licensee == 0x01
+            || (old_licensee == 0x33 && mmu.read8(0x0144) == b'0' && mmu.read8(0x0145) == b'1');
+        if !nintendo {
+            return 0x00;
+        }
+        (0x0134 ..= 0x0143).fold(0u8, |sum, addr| sum.wrapping_add(mmu.read8(addr)))
+    }
+
+    // the state at power on, the boot rom mapped at 0x0000 sets everything else up
+    pub fn init_for_boot_rom(mmu: Rc<RefCell<dyn Memory>>) -> Cpu {
         Self {
             mmu,
-            flags: Flag::Z as u8,
-            a: 0xFF,
+            flags: 0x00,
+            a: 0x00,
             b: 0x00,
-            c: 0x13,
+            c: 0x00,
             d: 0x00,
-            e: 0xD8,
-            h: 0x01,
-            l: 0x4d,
-            sp: 0xfffe,
-            pc: 0x0100,
+            e: 0x00,
+            h: 0x00,
+            l: 0x00,
+            sp: 0x0000,
+            pc: 0x0000,
+            stopped: false,
+            ime: false,
+            ime_scheduled: false,
+            halted: false,
+            halt_bug: false,
+            trace: None,
+            call_stack: VecDeque::new(),
+            call_depth: 0,
         }
     }
-    
+
 
     fn is_set(&self, f: Flag) -> bool {
         self.flags & f as u8 != 0
@@ -158,59 +195,247 @@ impl Cpu {
         return result
     }
 
-    pub fn emulate_operation(&mut self) -> u32{
-        let opcode = self.fetch();
-        if opcode != 0xCB {
-            rog::debugln!("[{:#04X}] {:#04X} | {}", self.pc.wrapping_sub(1), opcode, OP_MNEMONICS[opcode as usize]);
+    // https://gbdev.io/pandocs/Interrupts.html#interrupt-handling
+    // jumps to the handler of the highest priority pending interrupt. Returns the cycles taken, if any
+    fn handle_interrupts(&mut self) -> Option<u32> {
+        let pending = self.mmu.borrow().peek8(0xFFFF) & self.mmu.borrow().peek8(0xFF0F) & 0x1F;
+        if pending == 0 {
+            return None;
+        }
+        // any pending interrupt ends HALT, even when IME is off
+        self.halted = false;
+        if !self.ime {
+            return None;
+        }
+        self.ime = false;
+        let bit = pending.trailing_zeros() as u16;
+        let flag = self.mmu.borrow().peek8(0xFF0F);
+        self.mmu.borrow_mut().write8(0xFF0F, flag & !(1 << bit));
+        self.call(0x0040 + bit * 8, true);
+        rog::debugln!("interrupt {:#04X} -> {:#06X}", bit, self.pc);
+        Some(20)
+    }
+
+    pub fn emulate_operation(&mut self) -> u32 {
+        let cycles = self.run_operation();
+        if let Some(trace) = self.trace.as_mut() {
+            trace.cycles += cycles as u64;
+        }
+        cycles
+    }
+
+    fn run_operation(&mut self) -> u32 {
+        if let Some(cycles) = self.handle_interrupts() {
+            return cycles;
+        }
+        if self.halted {
+            return 4;
+        }
+        if self.trace.as_ref().is_some_and(|trace| trace.is_logging()) {
+            self.trace_instruction();
+        }
+        let enable_interrupts = self.ime_scheduled;
+        let cycles = self.execute_instruction();
+        // DI right after EI cancels the scheduled enable
+        if enable_interrupts && self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
+        cycles
+    }
+
+    fn trace_instruction(&mut self) {
+        let registers = [self.a, self.flags, self.b, self.c, self.d, self.e, self.h, self.l];
+        let pc_memory = {
+            let mmu = self.mmu.borrow();
+            [0, 1, 2, 3].map(|offset| mmu.peek8(self.pc.wrapping_add(offset)))
+        };
+        if let Some(trace) = self.trace.as_mut() {
+            // a closed pipe or a full disk ends the trace, the emulation carries on
+            if let Err(e) = trace.log(registers, self.sp, self.pc, pc_memory) {
+                eprintln!("writing the trace log: {}, tracing stopped", e);
+                self.trace = None;
+            }
         }
-        // https://www.pastraiser.com/cpu/gameboy/gameboy_opcodes.html
-        let cycles = match opcode {
+    }
+
+    fn execute_instruction(&mut self) -> u32 {
+        let addr = self.pc;
+        let decoded = self.fetch_instruction();
+        rog::debugln!("[{:#06X}] {}", addr, decoded.format(addr, &|_| None));
+        let mut branch_taken = false;
+        match decoded.instruction {
             // CPU Control Instructions
-            0x00 => 4, // NOP
-            // Jump instructions
-            0xC2 | 0xC3 | 0xCA | 0xD2 | 0xDA | 0xE9 | // jp 
-            0x18 | 0x20 | 0x28 | 0x30 | 0x38 |  // jr
-            0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC |  // call
-            0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9  => self.emulate_jump_operation(opcode),
+            Instruction::Nop => {},
+            Instruction::Stop => self.stopped = true,
+            Instruction::Halt => {
+                let pending = self.mmu.borrow().peek8(0xFFFF) & self.mmu.borrow().peek8(0xFF0F) & 0x1F;
+                if !self.ime && pending != 0 {
+                    // HALT is skipped and the next byte is read twice
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
+            },
+            Instruction::Di => {
+                self.ime = false;
+                self.ime_scheduled = false;
+            },
+            Instruction::Ei => self.ime_scheduled = true,
             // LD operations
-            0x02 | 0x06 | 0x0A | 0x0E |
-            0x12 | 0x16 | 0x1A | 0x1E |
-            0x22 | 0x26 | 0x2A | 0x2E |
-            0x32 | 0x36 | 0x3A | 0x3E |
-            0x40 ..= 0x7F |
-            0xE0 | 0xE2 | 0xEA |
-            0xF0 | 0xF2 | 0xFA => self.emulate_8bit_load_operation(opcode),
+            Instruction::Ld(to, from) => {
+                let data = self.read_r8(from);
+                self.write_r8(to, data);
+            },
+            Instruction::LdImmediate(to, n) => self.write_r8(to, n),
+            Instruction::LoadA(at) => {
+                let addr = self.memory_operand(at);
+                self.a = self.mmu.borrow().read8(addr);
+            },
+            Instruction::StoreA(at) => {
+                let addr = self.memory_operand(at);
+                self.mmu.borrow_mut().write8(addr, self.a);
+            },
             // 16-bit ld/store/move ops
-            0x01 | 0x11 | 0x21 | 0x31 | 0x08 |
-            0xC1 | 0xD1 | 0xE1 | 0xF1 | 
-            0xC5 | 0xD5 | 0xE5 | 0xF5 | 
-            0xF8 | 0xF9 => self.emulate_16bit_load_operation(opcode), 
+            Instruction::Ld16(pair, nn) => self.write_r16(pair, nn),
+            Instruction::StoreSp(nn) => self.mmu.borrow_mut().write16(nn, self.sp),
+            Instruction::LdHlSpOffset(e) => {
+                let sum = self.add_sp_offset(e);
+                self.write_r16(R16::HL, sum);
+            },
+            Instruction::LdSpHl => self.sp = self.read_r16(R16::HL),
+            Instruction::Push(pair) => self.push16(self.read_r16(pair)),
+            Instruction::Pop(pair) => {
+                let data = self.pop16();
+                self.write_r16(pair, data);
+            },
+            // 16-bit Arithmetic instructions, INC rr / DEC rr don't touch the flags
+            Instruction::Inc16(pair) => self.write_r16(pair, self.read_r16(pair).wrapping_add(1)),
+            Instruction::Dec16(pair) => self.write_r16(pair, self.read_r16(pair).wrapping_sub(1)),
+            Instruction::AddHl(pair) => {
+                let hl = self.read_r16(R16::HL);
+                let operand = self.read_r16(pair);
+                let sum = hl.wrapping_add(operand);
+                self.set_flag(Flag::N, 0);
+                // half-carry flag set if overflow from bit 11
+                self.set_flag(Flag::H, if (hl & 0x0FFF) + (operand & 0x0FFF) > 0x0FFF { 1 } else { 0 });
+                self.set_flag(Flag::C, if sum < hl { 1 } else { 0 });
+                self.write_r16(R16::HL, sum);
+            },
+            Instruction::AddSp(e) => self.sp = self.add_sp_offset(e),
             // 8-bit Arithmethic/Logic instructions
-            0x04 | 0x05 | 0x0C | 0x0D | 0x14 | 0x15 | 0x1C | 0x1D |
-            0x24 | 0x25 | 0x27 | 0x2C | 0x2D | 0x2F |
-            0x34 | 0x35 | 0x37 | 0x3C | 0x3D | 0x3F |
-            0x80 ..= 0x8F |
-            0x90 ..= 0x9F |
-            0xA0 ..= 0xAF |
-            0xB0 ..= 0xBF |
-            0xC6 | 0xD6 | 0xE6 | 0xF6 | 0xCE | 0xDE | 0xEE | 0xFE => self.emulate_8bit_arithmetic_or_logic(opcode),
-            0x07 | 0x17 | 0x0F | 0x1F | 0xCB => self.emulate_8bit_rotation_or_shift(opcode),
-            _ => panic!("Unrecognized opcode {:#02x} at addr {:#04x}", opcode, self.pc - 1),
+            Instruction::Inc(operand) => {
+                let result = self.op_inc(self.read_r8(operand));
+                self.write_r8(operand, result);
+            },
+            Instruction::Dec(operand) => {
+                let result = self.op_dec(self.read_r8(operand));
+                self.write_r8(operand, result);
+            },
+            Instruction::Alu(op, operand) => self.op_alu(op, self.read_r8(operand)),
+            Instruction::AluImmediate(op, n) => self.op_alu(op, n),
+            Instruction::Daa => self.op_daa(),
+            Instruction::Cpl => {
+                self.a = !self.a;
+                self.set_flag(Flag::N, 1);
+                self.set_flag(Flag::H, 1);
+            },
+            Instruction::Scf => {
+                self.set_flag(Flag::N, 0);
+                self.set_flag(Flag::H, 0);
+                self.set_flag(Flag::C, 1);
+            },
+            Instruction::Ccf => {
+                let c = if self.is_set(Flag::C) { 0 } else { 1 };
+                self.set_flag(Flag::N, 0);
+                self.set_flag(Flag::H, 0);
+                self.set_flag(Flag::C, c);
+            },
+            // RLCA RLA RRCA RRA
+            Instruction::Rlca => self.a = self.op_rlc(self.a, false),
+            Instruction::Rla => self.a = self.op_rl(self.a, false),
+            Instruction::Rrca => self.a = self.op_rrc(self.a, false),
+            Instruction::Rra => self.a = self.op_rr(self.a, false),
+            // Jump instructions
+            Instruction::Jp(condition, nn) => {
+                if self.condition_met(condition) {
+                    self.pc = nn;
+                    branch_taken = true;
+                }
+            },
+            Instruction::JpHl => self.pc = self.read_r16(R16::HL),
+            Instruction::Jr(condition, e) => {
+                if self.condition_met(condition) {
+                    self.pc = self.pc.wrapping_add(e as u16);
+                    branch_taken = true;
+                }
+            },
+            Instruction::Call(condition, nn) => {
+                if self.condition_met(condition) {
+                    self.call(nn, false);
+                    branch_taken = true;
+                }
+            },
+            Instruction::Ret(condition) => {
+                if self.condition_met(condition) {
+                    self.ret();
+                    branch_taken = true;
+                }
+            },
+            Instruction::Reti => {
+                self.ret();
+                self.ime = true;
+            },
+            Instruction::Rst(vector) => self.call(vector as u16, false),
+            // CB prefixed rotates, shifts and bit operations
+            Instruction::Shift(op, operand) => {
+                let data = self.read_r8(operand);
+                let result = match op {
+                    ShiftOp::Rlc => self.op_rlc(data, true),
+                    ShiftOp::Rrc => self.op_rrc(data, true),
+                    ShiftOp::Rl => self.op_rl(data, true),
+                    ShiftOp::Rr => self.op_rr(data, true),
+                    ShiftOp::Sla => self.op_sla(data),
+                    ShiftOp::Sra => self.op_sra(data),
+                    ShiftOp::Swap => self.op_swap(data),
+                    ShiftOp::Srl => self.op_srl(data),
+                };
+                self.write_r8(operand, result);
+            },
+            // BIT only reads its operand
+            Instruction::Bit(index, operand) => {
+                let data = self.read_r8(operand);
+                self.op_bit(data, index);
+            },
+            Instruction::Res(index, operand) => {
+                let result = self.op_res(self.read_r8(operand), index);
+                self.write_r8(operand, result);
+            },
+            Instruction::Set(index, operand) => {
+                let result = self.op_set(self.read_r8(operand), index);
+                self.write_r8(operand, result);
+            },
+            Instruction::Invalid(opcode) => panic!("Unrecognized opcode {:#02x} at addr {:#04x}", opcode, addr),
         };
         // return cycles taken (in hardware clock cycles)
-        cycles
-    }
-
-    fn fetch(&mut self) -> u8 {
-        let b = self.mmu.borrow().read8(self.pc);
-        self.pc = self.pc.wrapping_add(1);
-        b
-    }
-
-    fn fetch16(&mut self) -> u16 {
-        let w = self.mmu.borrow().read16(self.pc);
-        self.pc = self.pc.wrapping_add(2);
-        w
+        if branch_taken { decoded.branch_cycles } else { decoded.cycles }
+    }
+
+    // reads the instruction at pc and moves pc past it
+    fn fetch_instruction(&mut self) -> Decoded {
+        let mmu = self.mmu.borrow();
+        let opcode = mmu.fetch8(self.pc, true);
+        let length = instruction::length(opcode);
+        // with the HALT bug pc doesn't move past the opcode, so the byte after HALT is read twice
+        let operands_addr = if self.halt_bug { self.pc } else { self.pc.wrapping_add(1) };
+        let mut bytes = [opcode, 0, 0];
+        for i in 1 .. length {
+            bytes[i as usize] = mmu.fetch8(operands_addr.wrapping_add(i - 1), false);
+        }
+        drop(mmu);
+        self.pc = operands_addr.wrapping_add(length - 1);
+        self.halt_bug = false;
+        instruction::decode_bytes(bytes)
     }
 
     // get an address formed by concatenating H and L together. optionally increment/decrement
@@ -227,41 +452,65 @@ impl Cpu {
         hl
     }
 
-    // based on https://www.pastraiser.com/cpu/gameboy/gameboy_opcodes.html, this is true for many opcodes
-    fn fetch_reg_operand(&self, opcode: u8) -> u8 {
-        let operand = match opcode & 0x7 {
-            0x0 => self.b,
-            0x1 => self.c,
-            0x2 => self.d,
-            0x3 => self.e,
-            0x4 => self.h,
-            0x5 => self.l,
-            0x6 => {
-                let addr = (self.h as u16) << 8 | self.l as u16;
-                self.mmu.borrow().read8(addr)
-            },
-            0x7 => self.a,
-            _ => panic!("impossible"),
-        };
-        operand
+    fn read_r8(&self, operand: R8) -> u8 {
+        match operand {
+            R8::B => self.b,
+            R8::C => self.c,
+            R8::D => self.d,
+            R8::E => self.e,
+            R8::H => self.h,
+            R8::L => self.l,
+            R8::HlIndirect => self.mmu.borrow().read8(self.read_r16(R16::HL)),
+            R8::A => self.a,
+        }
     }
 
-    // based on https://www.pastraiser.com/cpu/gameboy/gameboy_opcodes.html, this is true for many opcodes
-    fn store_result_in_register(&mut self, opcode: u8, result: u8) {
-        match opcode & 0x7 {
-            0x0 => self.b = result,
-            0x1 => self.c = result,
-            0x2 => self.d = result,
-            0x3 => self.e = result,
-            0x4 => self.h = result,
-            0x5 => self.l = result,
-            0x6 => {
-                let addr = (self.h as u16) << 8 | self.l as u16;
-                self.mmu.borrow_mut().write8(addr, result)
-            },
-            0x7 => self.a = result,
-            _ => panic!("impossible!"),
-        };
+    fn write_r8(&mut self, operand: R8, data: u8) {
+        match operand {
+            R8::B => self.b = data,
+            R8::C => self.c = data,
+            R8::D => self.d = data,
+            R8::E => self.e = data,
+            R8::H => self.h = data,
+            R8::L => self.l = data,
+            R8::HlIndirect => self.mmu.borrow_mut().write8(self.read_r16(R16::HL), data),
+            R8::A => self.a = data,
+        }
+    }
+
+    fn read_r16(&self, pair: R16) -> u16 {
+        match pair {
+            R16::BC => (self.b as u16) << 8 | self.c as u16,
+            R16::DE => (self.d as u16) << 8 | self.e as u16,
+            R16::HL => (self.h as u16) << 8 | self.l as u16,
+            R16::SP => self.sp,
+            R16::AF => (self.a as u16) << 8 | self.flags as u16,
+        }
+    }
+
+    fn write_r16(&mut self, pair: R16, data: u16) {
+        let (high, low) = ((data >> 8) as u8, (data & 0xFF) as u8);
+        match pair {
+            R16::BC => { self.b = high; self.c = low; },
+            R16::DE => { self.d = high; self.e = low; },
+            R16::HL => { self.h = high; self.l = low; },
+            R16::SP => self.sp = data,
+            // the low nibble of F is always zero
+            R16::AF => { self.a = high; self.flags = low & 0xF0; },
+        }
+    }
+
+    // the address LD A,(x) and LD (x),A use, HL+ and HL- step HL
+    fn memory_operand(&mut self, at: Address) -> u16 {
+        match at {
+            Address::BC => self.read_r16(R16::BC),
+            Address::DE => self.read_r16(R16::DE),
+            Address::HlIncrement => self.get_hl(IncrementMode::Increment),
+            Address::HlDecrement => self.get_hl(IncrementMode::Decrement),
+            Address::Absolute(nn) => nn,
+            Address::HighC => 0xFF00 | self.c as u16,
+            Address::High(n) => 0xFF00 | n as u16,
+        }
     }
 
     fn push(&mut self, data: u8) {
@@ -276,280 +525,93 @@ impl Cpu {
     }
 
     fn push16(&mut self, data: u16) {
-        let _ = self.sp.wrapping_sub(2);
-        self.mmu.borrow_mut().write16(self.sp, data);
+        self.push((data >> 8) as u8);
+        self.push((data & 0xFF) as u8);
     }
 
     fn pop16(&mut self) -> u16 {
-        let data = self.mmu.borrow().read16(self.sp);
-        self.sp = self.sp.wrapping_add(2);
-        data
+        let low = self.pop();
+        let high = self.pop();
+        (high as u16) << 8 | low as u16
     }
 
-    fn emulate_jump_operation(&mut self, opcode: u8) -> u32 {
-        match opcode {
-            0xC2 => panic!("unimplemented!"),
-            0xC3 => { // jp
-                let target = self.fetch16();
-                self.pc = target;
-                16
-            },
-            0xCA | 0xD2 | 0xDA | 0xE9 | // jp 
-            0x18 | 0x20 | 0x28 | 0x30 | 0x38 => self.op_jr(opcode),
-            0xC4 | 0xCC => panic!("unimplemented opcode: {:#02x}", opcode),
-            0xCD => { // call
-                let target = self.fetch16();
-                self.push16(self.pc);
-                self.pc = target;
-                24
-            },
-            0xD4 | 0xDC | 0xC0 | 0xC8 => panic!("unimplemented opcode: {:#02x}", opcode),
-            0xC9 => { // ret
-                let target = self.pop16();
-                self.pc = target;
-                16
-            },
-            0xD0 | 0xD8 | 0xD9 => panic!("unimplemented opcode: {:#02x}", opcode),
-            _ => panic!("unexpected opcode: {:#02x}", opcode),
+    fn call(&mut self, target: u16, interrupt: bool) {
+        self.push16(self.pc);
+        if self.call_stack.len() == MAX_CALL_DEPTH {
+            self.call_stack.pop_front();
+        }
+        self.call_stack.push_back(CallFrame { target, return_addr: self.pc, sp: self.sp, interrupt });
+        self.call_depth += 1;
+        self.pc = target;
+    }
+
+    // drops every call whose return address is now above SP, including calls that were left
+    // some other way, like popping the return address and jumping
+    fn ret(&mut self) {
+        self.pc = self.pop16();
+        // returning from one of the calls dropped past MAX_CALL_DEPTH
+        if self.call_stack.is_empty() {
+            self.call_depth = self.call_depth.saturating_sub(1);
+        }
+        while self.call_stack.back().is_some_and(|frame| frame.sp < self.sp) {
+            self.call_stack.pop_back();
+            self.call_depth -= 1;
         }
     }
 
-    // jump conditionally
-    fn op_jr(&mut self, opcode: u8) -> u32 {
-        let r8 = self.fetch() as i8;
-        let target = self.pc.wrapping_add(r8 as u16);
-        rog::debugln!("              | r8={}", r8); 
-        let taken = opcode == 0x18 ||
-            (opcode == 0x20 && !self.is_set(Flag::Z)) ||
-            (opcode == 0x28 && self.is_set(Flag::Z)) ||
-            (opcode == 0x30 && !self.is_set(Flag::C)) ||
-            (opcode == 0x38 && self.is_set(Flag::C));
-        if taken {
-            self.pc = target;
-            12
-        } else {
-            8
+    fn condition_met(&self, condition: Option<Condition>) -> bool {
+        match condition {
+            None => true,
+            Some(Condition::NZ) => !self.is_set(Flag::Z),
+            Some(Condition::Z) => self.is_set(Flag::Z),
+            Some(Condition::NC) => !self.is_set(Flag::C),
+            Some(Condition::C) => self.is_set(Flag::C),
         }
     }
 
-
-    fn emulate_8bit_load_operation(&mut self, opcode: u8) -> u32 {
-        match opcode {
-            // move between A and an address
-            0x02 | 0x12 | 0x22 | 0x32 | 0x0A | 0x1A | 0x2A | 0x3A => {
-                let addr = match (opcode & 0xF0) >> 4 {
-                    0 => (self.b as u16) << 8 | self.c as u16,
-                    1 => (self.d as u16) << 8 | self.e as u16,
-                    2 => self.get_hl(IncrementMode::Increment),
-                    3 => self.get_hl(IncrementMode::Decrement),
-                    _ => panic!("invalid 8bit LD opcode {:02X}", opcode),
-                };
-                if opcode & 0x0F == 0x2 {
-                    self.mmu.borrow_mut().write8(addr, self.a);
-                } else {
-                    self.a = self.mmu.borrow().read8(addr);
-                }
-                8
-            },
-
-            0x06 | 0x08 | 0x0E |
-            0x16 | 0x18 | 0x1E |
-            0x26 | 0x28 | 0x2E |
-            0x36 | 0x38 | 0x3E |
-            0x40 ..= 0x7F |
-            0xE2 | 0xEA |
-            0xF2 | 0xF8 | 0xFA => panic!("LD Not yet implemented: {:#02X}", opcode),
-            0xE0 => {
-                let n = self.fetch();
-                self.mmu.borrow_mut().write8((n as u16).wrapping_add(0xFF00), self.a);
-                12
-            }
-            0xF0 => {
-                let n = self.fetch();
-                let addr = (n as u16).wrapping_add(0xFF00);
-                self.a = self.mmu.borrow().read8(addr);
-                rog::debugln!("                      ({:#04X})", addr);
-                12
-            }
-            _ => panic!("Unsupported LD opcode: {:#02X}", opcode),
+    // SP plus a signed offset for ADD SP,e and LD HL,SP+e, the flags come from the low byte
+    fn add_sp_offset(&mut self, e: i8) -> u16 {
+        let addend = i16::from(e) as u16;
+        // carry flag set if overflow from bit 7
+        let c_flag = (self.sp & 0x00FF) + (addend & 0x00FF) > 0x00FF;
+        // half-carry flag set if overflow from bit 3
+        let h_flag = (self.sp & 0x000F) + (addend & 0x000F) > 0x000F;
+        self.set_flag(Flag::Z, 0);
+        self.set_flag(Flag::N, 0);
+        self.set_flag(Flag::H, if h_flag { 1 } else { 0 });
+        self.set_flag(Flag::C, if c_flag { 1 } else { 0 });
+        self.sp.wrapping_add(addend)
+    }
+
+    fn op_alu(&mut self, op: AluOp, operand: u8) {
+        match op {
+            AluOp::Add => self.op_add(operand),
+            AluOp::Adc => self.op_adc(operand),
+            AluOp::Sub => self.op_sub(operand),
+            AluOp::Sbc => self.op_sbc(operand),
+            AluOp::And => self.op_and(operand),
+            AluOp::Xor => self.op_xor(operand),
+            AluOp::Or => self.op_or(operand),
+            AluOp::Cp => self.op_cp(operand),
         }
     }
 
-    fn emulate_16bit_load_operation(&mut self, opcode: u8) -> u32 {
-        let cpu_cycles = match opcode {
-            0x01 => {
-                let d16 = self.fetch16();
-                self.b  = (d16 >> 8) as u8;
-                self.c  = (d16 & 0xFF) as u8;
-                12
-            },
-            0x11 => {
-                let d16 = self.fetch16();
-                self.d  = (d16 >> 8) as u8;
-                self.e  = (d16 & 0xFF) as u8;
-                12
-            },
-            0x21 => {
-                let d16 = self.fetch16();
-                self.h  = (d16 >> 8) as u8;
-                self.l  = (d16 & 0xFF) as u8;
-                12
-            },
-            0x31 => {
-                self.sp = self.fetch16();
-                12
-            },
-            0x08 => {
-                let a16 = self.fetch16();
-                self.mmu.borrow_mut().write16(a16, self.sp);
-                20
-            },
-            0xC1 => {
-                self.c = self.pop();
-                self.b = self.pop();
-                12
-            },
-            0xD1 => {
-                self.e = self.pop();
-                self.d = self.pop();
-                12
-            },
-            0xE1 => {
-                self.l = self.pop();
-                self.h = self.pop();
-                12
-            },
-            0xF1 => {
-                self.flags = self.pop();
-                self.a = self.pop();
-                12
-            },
-            0xC5 => {
-                self.push(self.b);
-                self.push(self.c);
-                16
-            },
-            0xD5 => {
-                self.push(self.d);
-                self.push(self.e);
-                16
-            },
-            0xE5 => {
-                self.push(self.h);
-                self.push(self.l);
-                16
-            },
-            0xF5 => {
-                self.push(self.a);
-                self.push(self.flags);
-                16
-            },
-            0xF8 => {
-                let addend = i16::from(self.fetch() as i8) as u16;
-                // carry flag set if overflow from bit 7
-                let c_flag = (self.sp & 0x00FF) + (addend & 0x00FF) > 0x00FF;
-                // half-carry flag set if overflow from bit 3
-                let h_flag = (self.sp & 0x000F) + (addend & 0x000F) > 0x000F;
-                let sum = self.sp.wrapping_add(addend);
-                self.h = (sum >> 8) as u8;
-                self.l = (sum & 0xFF) as u8;
-                self.set_flag(Flag::Z, 0);
-                self.set_flag(Flag::N, 0);
-                self.set_flag(Flag::H, if h_flag { 1 } else { 0 });
-                self.set_flag(Flag::C, if c_flag { 1 } else { 0 });
-                12
-            },
-            0xF9 => {
-                let hl = (self.h as u16) << 8 | self.l as u16;
-                self.sp = hl;
-                8
-            },
-            _ => panic!("not implemented: {:#02X}", opcode),
-        };
-        cpu_cycles
+    fn op_inc(&mut self, operand: u8) -> u8 {
+        let result = operand.wrapping_add(1);
+        self.set_flag(Flag::Z, if result == 0x00 { 1 } else { 0 });
+        self.set_flag(Flag::N, 0);
+        self.set_flag(Flag::H, if operand & 0x0F == 0x0F { 1 } else { 0 });
+        result
     }
 
-    fn emulate_8bit_arithmetic_or_logic(&mut self, opcode: u8) -> u32 {
-        // 8-bit Arithmethic/Logic instructions
-        match opcode {
-            0x04 | 0x05 | 0x0C | 0x0D |
-            0x14 | 0x15 | 0x1C | 0x1D |
-            0x24 | 0x25 | 0x2C | 0x2D |
-            0x34 | 0x35 | 0x3C | 0x3D => self.op_inc_or_dec(opcode),
-            0x2F => {  // CPL
-                self.a = !self.a;
-                self.set_flag(Flag::N, 1);
-                self.set_flag(Flag::H, 1);
-            },
-            0x27 | 0x37 | 0x3F => panic!("unimplemented opcode: {:#02X}", opcode),
-            0x80 ..= 0xBF | 0xC6 | 0xD6 | 0xE6 | 0xF6 | 0xCE | 0xDE | 0xEE | 0xFE => {
-                let operand = if opcode < 0xC0 {
-                    self.fetch_reg_operand(opcode)
-                } else {
-                    self.fetch()
-                };
-                match opcode {
-                    0x80 ..= 0x87 | 0xC6 => self.op_add(operand),
-                    0x88 ..= 0x8F | 0xCE => self.op_adc(operand),
-                    0x90 ..= 0x97 | 0xD6 => self.op_sub(operand),
-                    0x98 ..= 0x9F | 0xDE => self.op_sbc(operand),
-                    0xA0 ..= 0xA7 | 0xE6 => self.op_and(operand),
-                    0xA8 ..= 0xAF | 0xEE => self.op_xor(operand),
-                    0xB0 ..= 0xB7 | 0xF6 => self.op_or(operand),
-                    0xB8 ..= 0xBF | 0xFE => self.op_cp(operand),
-                    _ => panic!("impossible opcode for 8bit math/logic: {:#02X}", opcode),
-                }
-            }
-            _ => panic!("impossible opcode for 8bit math/logic: {:#02X}", opcode),
-        }
-        let cpu_cycles = if opcode == 0x34 || opcode == 0x35 {
-            12
-        } else if opcode & 0x07 == 0x06 {
-            8  // those that dereference HL
-        } else {
-            4
-        };
-        cpu_cycles
-    }
-
-    fn op_inc_or_dec(&mut self, opcode: u8) {
-        let operand = match opcode {
-            0x04 | 0x05 => self.b,
-            0x0C | 0x0D => self.c,
-            0x14 | 0x15 => self.d,
-            0x1C | 0x1D => self.e,
-            0x24 | 0x25 => self.h,
-            0x2C | 0x2D => self.l,
-            0x34 | 0x35 => self.mmu.borrow().read8((self.h as u16) << 8 | self.l as u16),
-            0x3C | 0x3D => self.a,
-            _ => panic!("impossible opcode: {:#02X}!", opcode),
-        };
-        let result = if opcode & 0x01 == 0x00 {
-            // INC
-            self.set_flag(Flag::H, if operand & 0x07 == 0x07 { 1 } else { 0 });
-            self.set_flag(Flag::N, 0);
-            operand.wrapping_add(1)
-        } else {
-            // "DEC "
-            self.set_flag(Flag::H, if operand & 0x07 == 0x00 { 1 } else { 0 });
-            self.set_flag(Flag::N, 1);
-            operand.wrapping_sub(1)
-        };
+    fn op_dec(&mut self, operand: u8) -> u8 {
+        let result = operand.wrapping_sub(1);
         self.set_flag(Flag::Z, if result == 0x00 { 1 } else { 0 });
-        match opcode {
-            0x04 | 0x05 => self.b = result,
-            0x0C | 0x0D => self.c = result,
-            0x14 | 0x15 => self.d = result,
-            0x1C | 0x1D => self.e = result,
-            0x24 | 0x25 => self.h = result,
-            0x2C | 0x2D => self.l = result,
-            0x34 | 0x35 => self.mmu.borrow_mut().write8((self.h as u16) << 8 | self.l as u16, result),
-            0x3C | 0x3D => self.a = result,
-            _ => panic!("impossible opcode: {:#02X}!", opcode),
-        }
+        self.set_flag(Flag::N, 1);
+        self.set_flag(Flag::H, if operand & 0x0F == 0x00 { 1 } else { 0 });
+        result
     }
 
-
     fn op_add(&mut self, operand: u8) {
         let sum = self.a.wrapping_add(operand);
         self.set_flag(Flag::Z, if sum == 0 { 1 } else { 0 });
@@ -562,13 +624,12 @@ impl Cpu {
 
     fn op_adc(&mut self, operand: u8) {
         let c = if self.flags & Flag::C as u8 != 0 { 0x1 } else { 0x0 };
-        let operand_plus_c = operand.wrapping_add(c);
-        let sum = self.a.wrapping_add(operand_plus_c);
-        self.set_flag(Flag::Z, if sum == 0 { 1 } else { 0 });
+        let sum = self.a as u16 + operand as u16 + c as u16;
+        self.set_flag(Flag::Z, if sum & 0xFF == 0 { 1 } else { 0 });
         self.set_flag(Flag::N, 0);
-        self.set_flag(Flag::H, if (self.a & 0x0F) + (operand_plus_c & 0x0F) > 0x0F { 1 } else { 0 });
-        self.set_flag(Flag::C, if sum < self.a { 1 } else { 0 });
-        self.a = sum
+        self.set_flag(Flag::H, if (self.a & 0x0F) + (operand & 0x0F) + c > 0x0F { 1 } else { 0 });
+        self.set_flag(Flag::C, if sum > 0xFF { 1 } else { 0 });
+        self.a = sum as u8
     }
  
     fn op_sub(&mut self, operand: u8) {
@@ -582,15 +643,32 @@ impl Cpu {
 
     fn op_sbc(&mut self, operand: u8) {
         let c = if self.flags & Flag::C as u8 != 0 { 0x1 } else { 0x0 };
-        let operand_plus_c = operand.wrapping_add(c);
-        let diff = self.a.wrapping_sub(operand_plus_c);
+        let diff = self.a.wrapping_sub(operand).wrapping_sub(c);
         self.set_flag(Flag::Z, if diff == 0 { 1 } else { 0 });
         self.set_flag(Flag::N, 1);
-        self.set_flag(Flag::H, if (self.a & 0x0F) < (operand_plus_c & 0x0F) { 1 } else { 0 });
-        self.set_flag(Flag::C, if self.a < operand_plus_c { 1 } else { 0 });
+        self.set_flag(Flag::H, if (self.a & 0x0F) < (operand & 0x0F) + c { 1 } else { 0 });
+        self.set_flag(Flag::C, if (self.a as u16) < operand as u16 + c as u16 { 1 } else { 0 });
         self.a = diff
     }
 
+    // https://gbdev.io/pandocs/CPU_Instruction_Set.html - decimal adjust A after a BCD addition or subtraction
+    fn op_daa(&mut self) {
+        let subtraction = self.is_set(Flag::N);
+        let mut adjust = 0x00;
+        let mut carry = self.is_set(Flag::C);
+        if self.is_set(Flag::H) || (!subtraction && self.a & 0x0F > 0x09) {
+            adjust |= 0x06;
+        }
+        if carry || (!subtraction && self.a > 0x99) {
+            adjust |= 0x60;
+            carry = true;
+        }
+        self.a = if subtraction { self.a.wrapping_sub(adjust) } else { self.a.wrapping_add(adjust) };
+        self.set_flag(Flag::Z, if self.a == 0 { 1 } else { 0 });
+        self.set_flag(Flag::H, 0);
+        self.set_flag(Flag::C, if carry { 1 } else { 0 });
+    }
+
     fn op_and(&mut self, operand: u8) {
         self.a = self.a & operand;
         self.set_flag(Flag::Z, if self.a == 0 { 1 } else { 0 });
@@ -624,51 +702,6 @@ impl Cpu {
         self.set_flag(Flag::Z, if result == 0 { 1 } else { 0 });
     }
 
-    fn emulate_8bit_rotation_or_shift(&mut self, opcode: u8) -> u32 {
-        match opcode {
-            // RLCA RLA RRCA RRA 
-            0x07 => { 
-                self.a = self.op_rlc(self.a, false);
-                4
-            },
-            0x17 => {
-                self.a = self.op_rl(self.a, false);
-                4
-            },
-            0x0F => {
-                self.a = self.op_rrc(self.a, false);
-                4
-            },
-            0x1F => {
-                self.a = self.op_rr(self.a, false);
-                4
-            },
-            0xCB => {
-                let cb_opcode = self.fetch();
-                rog::debugln!("[{:#06X}] {:#04X}{:02X} | {}", self.pc.wrapping_sub(2), opcode, cb_opcode, OP_CB_MNEMONICS[cb_opcode as usize]);
-                let operand = self.fetch_reg_operand(cb_opcode);
-                let result = match cb_opcode {
-                    // https://www.pastraiser.com/cpu/gameboy/gameboy_opcodes.html
-                    0x00 ..= 0x07 => self.op_rlc(operand, true),
-                    0x08 ..= 0x0F => self.op_rrc(operand, true),
-                    0x10 ..= 0x17 => self.op_rl(operand, true),
-                    0x18 ..= 0x1F => self.op_rr(operand, true),
-                    0x20 ..= 0x27 => self.op_sla(operand),
-                    0x28 ..= 0x2F => self.op_sra(operand),
-                    0x30 ..= 0x37 => self.op_swap(operand),
-                    0x38 ..= 0x3F => self.op_srl(operand),
-                    0x40 ..= 0x7F => self.op_bit(operand, (cb_opcode - 0x40) >> 3),
-                    0x80 ..= 0xBF => self.op_res(operand, (cb_opcode - 0x80) >> 3),
-                    0xC0 ..= 0xFF => self.op_set(operand, (cb_opcode - 0xC0) >> 3),
-                };
-                self.store_result_in_register(cb_opcode, result);
-                let cpu_cycles = if cb_opcode & 0x7 == 0x6 { 16 } else { 8 };
-                cpu_cycles
-            },
-            _ => panic!("Unsupported shift opcode: {:#02X}", opcode),
-        }
-    }
-
     // preform a rotate-logical through carry with the given register value. bit 7 becomes the new carry bit.
     fn op_rlc(&mut self, operand: u8, is_cb_prefixed: bool) -> u8 {
         let carry_bit = operand >> 7;
@@ -679,6 +712,8 @@ impl Cpu {
                 true => match new_val { 0 => 1, _ => 0, },
                 false => 0,
             });
+        self.set_flag(Flag::N, 0);
+        self.set_flag(Flag::H, 0);
         new_val
     }
 
@@ -693,6 +728,8 @@ impl Cpu {
                 true => match new_val { 0 => 1, _ => 0, },
                 false => 0,
             });
+        self.set_flag(Flag::N, 0);
+        self.set_flag(Flag::H, 0);
         new_val
     }
 
@@ -706,6 +743,8 @@ impl Cpu {
                 true => match new_val { 0 => 1, _ => 0, },
                 false => 0,
             });
+        self.set_flag(Flag::N, 0);
+        self.set_flag(Flag::H, 0);
         rog::debugln!("    new_val: {:#02X}", new_val);
         new_val
     }
@@ -721,6 +760,8 @@ impl Cpu {
                 true => match new_val { 0 => 1, _ => 0, },
                 false => 0,
             });
+        self.set_flag(Flag::N, 0);
+        self.set_flag(Flag::H, 0);
         rog::debugln!("    new_val: {:#02X}", new_val);
         new_val
     }
@@ -758,7 +799,7 @@ impl Cpu {
 
     // swap the upper 4 bits with the lower 4
     fn op_swap(&mut self, operand: u8) -> u8 {
-        let result = (operand & 0x0F << 4) | (operand >> 4);
+        let result = ((operand & 0x0F) << 4) | (operand >> 4);
         self.set_flag(Flag::Z, if result == 0 { 1 } else { 0 });
         self.set_flag(Flag::N, 0);
         self.set_flag(Flag::H, 0);
@@ -786,3 +827,44 @@ impl Cpu {
         result
     }
 }
+
+impl SaveState for Cpu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.flags);
+        writer.write_u8(self.a);
+        writer.write_u8(self.b);
+        writer.write_u8(self.c);
+        writer.write_u8(self.d);
+        writer.write_u8(self.e);
+        writer.write_u8(self.h);
+        writer.write_u8(self.l);
+        writer.write_u16(self.sp);
+        writer.write_u16(self.pc);
+        writer.write_bool(self.stopped);
+        writer.write_bool(self.ime);
+        writer.write_bool(self.ime_scheduled);
+        writer.write_bool(self.halted);
+        writer.write_bool(self.halt_bug);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.flags = reader.read_u8()? & 0xF0;
+        self.a = reader.read_u8()?;
+        self.b = reader.read_u8()?;
+        self.c = reader.read_u8()?;
+        self.d = reader.read_u8()?;
+        self.e = reader.read_u8()?;
+        self.h = reader.read_u8()?;
+        self.l = reader.read_u8()?;
+        self.sp = reader.read_u16()?;
+        self.pc = reader.read_u16()?;
+        self.stopped = reader.read_bool()?;
+        self.ime = reader.read_bool()?;
+        self.ime_scheduled = reader.read_bool()?;
+        self.halted = reader.read_bool()?;
+        self.halt_bug = reader.read_bool()?;
+        self.call_stack.clear();
+        self.call_depth = 0;
+        Ok(())
+    }
+}
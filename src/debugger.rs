@@ -0,0 +1,222 @@
+use std::cell::Cell;
+use super::cpu::CallFrame;
+use super::expression::{Expression, LogMessage};
+use super::symbols::Symbols;
+
+// stops execution before the instruction at addr. With a bank it only stops while that rom bank is mapped.
+// A condition has to be non-zero for it to stop, a log point adds its message to the log and keeps going.
+pub struct Breakpoint {
+    pub addr: u16,
+    pub bank: Option<usize>,
+    pub enabled: bool,
+    pub condition: Option<(String, Expression)>,
+    pub log_message: Option<(String, LogMessage)>,
+    // times execution reached addr while enabled, whether the condition held or not
+    pub hits: u32,
+}
+
+impl Breakpoint {
+    pub fn init(addr: u16, bank: Option<usize>) -> Self {
+        Self { addr, bank, enabled: true, condition: None, log_message: None, hits: 0 }
+    }
+
+    // AAAA or BB:AAAA in hex, or a label
+    pub fn parse(text: &str, symbols: &Symbols) -> Option<Self> {
+        if let Some((bank, addr)) = symbols.find(text.trim()) {
+            // the bank only tells code apart in the switchable rom bank
+            return Some(Self::init(addr, (0x4000 .. 0x8000).contains(&addr).then_some(bank)));
+        }
+        let (bank, addr) = match text.trim().split_once(':') {
+            Some((bank, addr)) => (Some(usize::from_str_radix(bank.trim(), 16).ok()?), addr),
+            None => (None, text),
+        };
+        Some(Self::init(parse_addr(addr)?, bank))
+    }
+
+    // empty text removes the condition
+    pub fn set_condition(&mut self, text: &str) -> Result<(), String> {
+        self.condition = match text.trim() {
+            "" => None,
+            text => Some((text.to_string(), Expression::parse(text)?)),
+        };
+        Ok(())
+    }
+
+    // empty text makes it a normal breakpoint again
+    pub fn set_log_message(&mut self, text: &str) -> Result<(), String> {
+        self.log_message = match text {
+            "" => None,
+            text => Some((text.to_string(), LogMessage::parse(text)?)),
+        };
+        Ok(())
+    }
+
+    pub fn name(&self) -> String {
+        match self.bank {
+            Some(bank) => format!("{:02X}:{:04X}", bank, self.addr),
+            None => format!("{:04X}", self.addr),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+pub const ACCESSES: [Access; 3] = [Access::Read, Access::Write, Access::ReadWrite];
+pub const ACCESS_NAMES: [&str; 3] = ["read", "write", "read/write"];
+
+// stops execution after an instruction accessed memory in first..=last
+pub struct Watchpoint {
+    pub first: u16,
+    pub last: u16,
+    pub access: Access,
+    pub enabled: bool,
+}
+
+impl Watchpoint {
+    // AAAA or AAAA-BBBB, in hex
+    pub fn parse(text: &str, access: Access) -> Option<Self> {
+        let (first, last) = match text.split_once('-') {
+            Some((first, last)) => (parse_addr(first)?, parse_addr(last)?),
+            None => (parse_addr(text)?, parse_addr(text)?),
+        };
+        if first > last {
+            return None;
+        }
+        Some(Self { first, last, access, enabled: true })
+    }
+
+    pub fn name(&self) -> String {
+        let range = if self.first == self.last {
+            match io_register_name(self.first) {
+                Some(name) => format!("{:04X} {}", self.first, name),
+                None => format!("{:04X}", self.first),
+            }
+        } else {
+            format!("{:04X}-{:04X}", self.first, self.last)
+        };
+        format!("{} {}", range, ACCESS_NAMES[self.access as usize])
+    }
+
+    fn matches(&self, addr: u16, write: bool) -> bool {
+        let access = match self.access {
+            Access::Read => !write,
+            Access::Write => write,
+            Access::ReadWrite => true,
+        };
+        self.enabled && access && (self.first ..= self.last).contains(&addr)
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct WatchHit {
+    pub index: usize,
+    pub addr: u16,
+    pub data: u8,
+    pub write: bool,
+}
+
+// Checked by the mmu on every cpu access. Reads only get &self, so the hit is kept in a Cell.
+pub struct Watchpoints {
+    pub list: Vec<Watchpoint>,
+    hit: Cell<Option<WatchHit>>,
+}
+
+impl Watchpoints {
+    pub fn init() -> Self {
+        Self { list: Vec::new(), hit: Cell::new(None) }
+    }
+
+    pub fn check(&self, addr: u16, data: u8, write: bool) {
+        if self.list.is_empty() || self.hit.get().is_some() {
+            return;
+        }
+        if let Some(index) = self.list.iter().position(|watchpoint| watchpoint.matches(addr, write)) {
+            self.hit.set(Some(WatchHit { index, addr, data, write }));
+        }
+    }
+
+    // the first access that hit a watchpoint since the last call
+    pub fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+}
+
+// where step over, step out and run to cursor stop, depth is the cpu call depth
+#[derive(Copy, Clone)]
+pub enum StepTarget {
+    // stops at addr unless it's reached from deeper in the call stack, as in recursion
+    Address { addr: u16, depth: usize },
+    // stops once the call at depth returned
+    Return { depth: usize },
+}
+
+impl StepTarget {
+    pub fn reached(&self, pc: u16, depth: usize) -> bool {
+        match *self {
+            StepTarget::Address { addr, depth: target_depth } => pc == addr && depth <= target_depth,
+            StepTarget::Return { depth: target_depth } => depth < target_depth,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum BreakReason {
+    Breakpoint(u16),
+    Watchpoint(WatchHit),
+    Step(u16),
+}
+
+impl BreakReason {
+    pub fn describe(&self) -> String {
+        match self {
+            BreakReason::Breakpoint(addr) => format!("breakpoint at {:04X}", addr),
+            BreakReason::Watchpoint(hit) => format!("{} of {:02X} at {:04X}",
+                if hit.write { "write" } else { "read" }, hit.data, hit.addr),
+            BreakReason::Step(addr) => format!("stepped to {:04X}", addr),
+        }
+    }
+}
+
+// https://gbdev.io/pandocs/Interrupt_Sources.html, in order of their vectors from 0x0040
+pub const INTERRUPT_NAMES: [&str; 5] = ["VBlank", "STAT", "Timer", "Serial", "Joypad"];
+
+// what the call stack shows for a call
+pub fn call_name(frame: &CallFrame) -> String {
+    if frame.interrupt {
+        let index = ((frame.target - 0x0040) / 8) as usize;
+        format!("{} interrupt", INTERRUPT_NAMES.get(index).unwrap_or(&"unknown"))
+    } else {
+        format!("{:04X}", frame.target)
+    }
+}
+
+fn parse_addr(text: &str) -> Option<u16> {
+    let text = text.trim();
+    let text = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+    u16::from_str_radix(text, 16).ok()
+}
+
+// https://gbdev.io/pandocs/Hardware_Reg_List.html
+pub const IO_REGISTERS: [(&str, u16); 55] = [
+    ("P1", 0xFF00), ("SB", 0xFF01), ("SC", 0xFF02), ("DIV", 0xFF04), ("TIMA", 0xFF05), ("TMA", 0xFF06),
+    ("TAC", 0xFF07), ("IF", 0xFF0F),
+    ("NR10", 0xFF10), ("NR11", 0xFF11), ("NR12", 0xFF12), ("NR13", 0xFF13), ("NR14", 0xFF14),
+    ("NR21", 0xFF16), ("NR22", 0xFF17), ("NR23", 0xFF18), ("NR24", 0xFF19),
+    ("NR30", 0xFF1A), ("NR31", 0xFF1B), ("NR32", 0xFF1C), ("NR33", 0xFF1D), ("NR34", 0xFF1E),
+    ("NR41", 0xFF20), ("NR42", 0xFF21), ("NR43", 0xFF22), ("NR44", 0xFF23),
+    ("NR50", 0xFF24), ("NR51", 0xFF25), ("NR52", 0xFF26),
+    ("LCDC", 0xFF40), ("STAT", 0xFF41), ("SCY", 0xFF42), ("SCX", 0xFF43), ("LY", 0xFF44), ("LYC", 0xFF45),
+    ("DMA", 0xFF46), ("BGP", 0xFF47), ("OBP0", 0xFF48), ("OBP1", 0xFF49), ("WY", 0xFF4A), ("WX", 0xFF4B),
+    ("KEY1", 0xFF4D), ("VBK", 0xFF4F), ("BANK", 0xFF50),
+    ("HDMA1", 0xFF51), ("HDMA2", 0xFF52), ("HDMA3", 0xFF53), ("HDMA4", 0xFF54), ("HDMA5", 0xFF55),
+    ("BCPS", 0xFF68), ("BCPD", 0xFF69), ("OCPS", 0xFF6A), ("OCPD", 0xFF6B), ("SVBK", 0xFF70), ("IE", 0xFFFF),
+];
+
+pub fn io_register_name(addr: u16) -> Option<&'static str> {
+    IO_REGISTERS.iter().find(|&&(_, register)| register == addr).map(|&(name, _)| name)
+}
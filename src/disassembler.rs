@@ -0,0 +1,337 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use super::cartridge::Cartridge;
+use super::code_data_log;
+use super::code_data_log::CodeDataLog;
+use super::debugger::INTERRUPT_NAMES;
+use super::instruction;
+use super::instruction::{Address, Condition, Decoded, Instruction, R8};
+use super::symbols::Symbols;
+
+// Static disassembly of a whole rom. Code is traced from the entry point, the RST and the interrupt
+// vectors, following jumps and calls. Jumps into 0x4000-0x7FFF go to the bank the code last selected
+// with LD A,n then LD ($2000-$3FFF),A, or the bank the code is in, or bank 1 from bank 0.
+// Jumps through HL or tables aren't followed, the code data log fills in what was run while playing.
+// It also stops tracing at bytes it saw read as data. Everything else that isn't reached is written out as data.
+
+pub const BANK_SIZE: usize = 0x4000;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ByteKind {
+    Data,
+    // the first byte of an instruction
+    Code,
+    // the other bytes of an instruction
+    Operand,
+}
+
+pub struct Disassembly {
+    pub rom: Vec<u8>,
+    pub kinds: Vec<ByteKind>,
+    // by rom offset
+    pub labels: BTreeMap<usize, String>,
+    // the code data log flags of each rom byte
+    pub logged: Vec<u8>,
+    // the bank 0x4000-0x7FFF targets of instructions were traced into, by rom offset of the instruction
+    target_banks: HashMap<usize, usize>,
+}
+
+impl Disassembly {
+    pub fn trace(cartridge: &dyn Cartridge, symbols: &Symbols, code_data_log: &CodeDataLog) -> Self {
+        let rom = cartridge.rom().to_vec();
+        let mut logged = code_data_log.rom.clone();
+        logged.resize(rom.len(), 0x00);
+        let mut disassembly = Self {
+            kinds: vec![ByteKind::Data; rom.len()],
+            rom,
+            labels: BTreeMap::new(),
+            logged,
+            target_banks: HashMap::new(),
+        };
+        let mut pending = vec![(0x0100, 1)];
+        disassembly.labels.insert(0x0100, "Entry".to_string());
+        for vector in (0x00 ..= 0x38).step_by(8) {
+            pending.push((vector, 1));
+            disassembly.labels.insert(vector as usize, format!("RST_{:02X}", vector));
+        }
+        for (i, name) in INTERRUPT_NAMES.iter().enumerate() {
+            let vector = 0x40 + 8 * i as u16;
+            pending.push((vector, 1));
+            disassembly.labels.insert(vector as usize, format!("{}Interrupt", name));
+        }
+        while let Some((addr, bank)) = pending.pop() {
+            disassembly.trace_from(addr, bank, &mut pending);
+        }
+        // code that was run but isn't reached by tracing, from jump tables and the like
+        for offset in 0 .. disassembly.rom.len() {
+            if disassembly.logged[offset] & code_data_log::EXECUTED == 0 || disassembly.kinds[offset] != ByteKind::Data {
+                continue;
+            }
+            let (bank, addr) = (offset / BANK_SIZE, (offset % BANK_SIZE) as u16);
+            let addr = if bank == 0 { addr } else { addr + 0x4000 };
+            disassembly.labels.entry(offset).or_insert_with(|| format!("Code_{:03X}_{:04X}", bank, addr));
+            pending.push((addr, bank));
+            while let Some((addr, bank)) = pending.pop() {
+                disassembly.trace_from(addr, bank, &mut pending);
+            }
+        }
+        // Symbols replace the traced labels, in order so the output doesn't change between runs. The first
+        // symbol at an offset wins. rgbasm labels can't have dots outside of local labels and have to be
+        // unique, a clash gets the bank and then the address appended.
+        let mut symbols: Vec<(usize, u16, &str)> = symbols.iter().collect();
+        symbols.sort();
+        let mut named = HashSet::new();
+        let mut used: HashSet<String> = disassembly.labels.values().cloned().collect();
+        for (bank, addr, name) in symbols {
+            let Some(offset) = disassembly.rom_offset(addr, bank) else { continue };
+            if !named.insert(offset) {
+                continue;
+            }
+            if let Some(traced) = disassembly.labels.remove(&offset) {
+                used.remove(&traced);
+            }
+            let name = name.replace('.', "_");
+            let label = [name.clone(), format!("{}_{:03X}", name, bank), format!("{}_{:03X}_{:04X}", name, bank, addr)]
+                .into_iter().find(|label| !used.contains(label)).unwrap();
+            used.insert(label.clone());
+            disassembly.labels.insert(offset, label);
+        }
+        disassembly
+    }
+
+    pub fn bank_count(&self) -> usize {
+        self.rom.len().div_ceil(BANK_SIZE)
+    }
+
+    pub fn code_bytes(&self, bank: usize) -> usize {
+        let end = ((bank + 1) * BANK_SIZE).min(self.rom.len());
+        self.kinds[bank * BANK_SIZE .. end].iter().filter(|&&kind| kind != ByteKind::Data).count()
+    }
+
+    // where addr is in the rom, with bank mapped at 0x4000-0x7FFF
+    fn rom_offset(&self, addr: u16, bank: usize) -> Option<usize> {
+        let offset = match addr {
+            0x0000 ..= 0x3FFF => addr as usize,
+            // bank 0 can't be mapped here, selecting it gives bank 1
+            0x4000 ..= 0x7FFF => bank.max(1) * BANK_SIZE + (addr as usize - 0x4000),
+            _ => return None,
+        };
+        (offset < self.rom.len()).then_some(offset)
+    }
+
+    fn decode_at(&self, offset: usize) -> Decoded {
+        let byte = |i: usize| self.rom.get(offset + i).copied().unwrap_or(0x00);
+        instruction::decode_bytes([byte(0), byte(1), byte(2)])
+    }
+
+    // follows one path of execution until it ends or runs into code that's already traced
+    fn trace_from(&mut self, mut addr: u16, mut bank: usize, pending: &mut Vec<(u16, usize)>) {
+        // what LD A,n put in A, for spotting bank switches
+        let mut a = None;
+        loop {
+            let Some(offset) = self.rom_offset(addr, bank) else { return };
+            let decoded = self.decode_at(offset);
+            let length = decoded.length as usize;
+            // stop at code already traced, at instructions that would overlap it or cross the end of
+            // the rom area, and at opcodes that lock up the cpu
+            let region_end = if addr < 0x4000 { 0x4000 } else { 0x8000 };
+            if matches!(decoded.instruction, Instruction::Invalid(_)) || addr as usize + length > region_end
+                || offset + length > self.rom.len()
+                || self.kinds[offset .. offset + length].iter().any(|&kind| kind != ByteKind::Data)
+                || self.logged[offset .. offset + length].iter().any(|&flags| code_data_log::is_data(flags)) {
+                return;
+            }
+            self.kinds[offset] = ByteKind::Code;
+            for kind in &mut self.kinds[offset + 1 .. offset + length] {
+                *kind = ByteKind::Operand;
+            }
+            let next = addr.wrapping_add(decoded.length);
+            let (target, ends) = match decoded.instruction {
+                Instruction::Jp(condition, nn) => (Some((nn, false)), condition.is_none()),
+                Instruction::Jr(condition, e) => (Some((next.wrapping_add(e as u16), false)), condition.is_none()),
+                Instruction::Call(_, nn) => (Some((nn, true)), false),
+                Instruction::Rst(_) => (None, false),
+                Instruction::JpHl | Instruction::Ret(None) | Instruction::Reti => (None, true),
+                _ => (None, false),
+            };
+            if let Some((target, call)) = target {
+                if let Some(target_offset) = self.rom_offset(target, bank) {
+                    if target >= 0x4000 {
+                        self.target_banks.insert(offset, bank.max(1));
+                    }
+                    let kind = if call { "Call" } else { "Jump" };
+                    // calls name the routine even when something jumped to it first
+                    if self.labels.get(&target_offset).is_none_or(|label| label.starts_with("Jump") && call) {
+                        let label_bank = if target < 0x4000 { 0 } else { bank.max(1) };
+                        self.labels.insert(target_offset, format!("{}_{:03X}_{:04X}", kind, label_bank, target));
+                    }
+                    pending.push((target, bank));
+                }
+            }
+            if ends {
+                return;
+            }
+            match decoded.instruction {
+                Instruction::LdImmediate(R8::A, n) => a = Some(n),
+                Instruction::StoreA(Address::Absolute(0x2000 ..= 0x3FFF)) => {
+                    if let Some(n) = a {
+                        bank = n as usize % self.bank_count().max(1);
+                    }
+                },
+                // these leave A alone
+                Instruction::Nop | Instruction::Di | Instruction::Ei | Instruction::StoreA(_) | Instruction::Ld16(..)
+                | Instruction::Inc16(_) | Instruction::Dec16(_) | Instruction::Push(_) => {},
+                Instruction::Ld(to, _) | Instruction::LdImmediate(to, _) if to != R8::A => {},
+                _ => a = None,
+            }
+            addr = next;
+        }
+    }
+
+    // bank_000.asm, bank_001.asm... in dir
+    pub fn write_files(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+        std::fs::create_dir_all(dir)?;
+        let mut paths = Vec::new();
+        for bank in 0 .. self.bank_count() {
+            let path = dir.join(format!("bank_{:03}.asm", bank));
+            let mut writer = std::io::BufWriter::new(std::fs::File::create(&path)?);
+            self.write_bank(bank, &mut writer)?;
+            writer.flush()?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    // One rgbasm section per bank, each with its labels exported so the banks can be assembled
+    // separately and linked back into the same rom
+    pub fn write_bank(&self, bank: usize, writer: &mut dyn Write) -> std::io::Result<()> {
+        let start = bank * BANK_SIZE;
+        let end = (start + BANK_SIZE).min(self.rom.len());
+        let base = if bank == 0 { 0x0000 } else { 0x4000 };
+        if bank == 0 {
+            writeln!(writer, "SECTION \"ROM Bank $000\", ROM0[$0000]")?;
+        } else {
+            writeln!(writer, "SECTION \"ROM Bank ${:03X}\", ROMX[$4000], BANK[${:X}]", bank, bank)?;
+        }
+        let mut offset = start;
+        while offset < end {
+            let addr = (base + offset - start) as u16;
+            if let Some(label) = self.labels.get(&offset) {
+                writeln!(writer, "\n{}::", label)?;
+            }
+            if self.kinds[offset] == ByteKind::Code {
+                let decoded = self.decode_at(offset);
+                let target_bank = self.target_banks.get(&offset).copied();
+                let name = |target: u16| {
+                    let target_bank = if target < 0x4000 { 0 } else { target_bank? };
+                    let target_offset = self.rom_offset(target, target_bank)?;
+                    (self.kinds[target_offset] != ByteKind::Operand).then(|| self.labels.get(&target_offset).cloned())?
+                };
+                let bytes = &self.rom[offset .. offset + decoded.length as usize];
+                match rgbds_syntax(&decoded, addr, bytes, &name) {
+                    Some(text) => writeln!(writer, "    {}", text)?,
+                    None => writeln!(writer, "    db {}", hex_bytes(bytes))?,
+                }
+                offset += decoded.length as usize;
+            } else {
+                // bytes the code data log saw read are kept apart from those never seen used
+                let is_data = code_data_log::is_data(self.logged[offset]);
+                let mut data_end = offset + 1;
+                while data_end < end && data_end - offset < 16 && self.kinds[data_end] == ByteKind::Data
+                    && !self.labels.contains_key(&data_end) && code_data_log::is_data(self.logged[data_end]) == is_data {
+                    data_end += 1;
+                }
+                let comment = if is_data { " ; data" } else { "" };
+                writeln!(writer, "    db {}{}", hex_bytes(&self.rom[offset .. data_end]), comment)?;
+                offset = data_end;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("${:02X}", byte)).collect::<Vec<_>>().join(", ")
+}
+
+// The instruction as rgbasm takes it, None when it wouldn't assemble to the same bytes.
+// name gives the label for addresses that have one
+fn rgbds_syntax(decoded: &Decoded, addr: u16, bytes: &[u8], name: &dyn Fn(u16) -> Option<String>) -> Option<String> {
+    let address = |addr: u16| name(addr).unwrap_or_else(|| format!("${:04X}", addr));
+    let r8 = |operand: R8| match operand {
+        R8::HlIndirect => "[hl]".to_string(),
+        register => format!("{:?}", register).to_lowercase(),
+    };
+    let condition = |condition: Option<Condition>| match condition {
+        Some(condition) => format!("{}, ", format!("{:?}", condition).to_lowercase()),
+        None => String::new(),
+    };
+    let memory = |at: Address| match at {
+        Address::BC => "[bc]".to_string(),
+        Address::DE => "[de]".to_string(),
+        Address::HlIncrement => "[hl+]".to_string(),
+        Address::HlDecrement => "[hl-]".to_string(),
+        Address::Absolute(nn) => format!("[{}]", address(nn)),
+        Address::HighC => "[$ff00+c]".to_string(),
+        Address::High(n) => format!("[{}]", address(0xFF00 | n as u16)),
+    };
+    let alu = |op: instruction::AluOp| op.to_string().to_lowercase().replace(',', ", ");
+    let pair = |pair: instruction::R16| format!("{:?}", pair).to_lowercase();
+    Some(match decoded.instruction {
+        Instruction::Nop => "nop".to_string(),
+        // rgbasm always writes STOP as $10 $00
+        Instruction::Stop if bytes[1] == 0x00 => "stop".to_string(),
+        Instruction::Stop => return None,
+        Instruction::Halt => "halt".to_string(),
+        Instruction::Di => "di".to_string(),
+        Instruction::Ei => "ei".to_string(),
+        Instruction::Ld(to, from) => format!("ld {}, {}", r8(to), r8(from)),
+        Instruction::LdImmediate(to, n) => format!("ld {}, ${:02X}", r8(to), n),
+        Instruction::LoadA(at @ Address::High(_)) => format!("ldh a, {}", memory(at)),
+        Instruction::StoreA(at @ Address::High(_)) => format!("ldh {}, a", memory(at)),
+        // older rgbasm turns these into LDH unless run with --preserve-ld, bytes assemble the same everywhere
+        Instruction::LoadA(Address::Absolute(0xFF00 ..= 0xFFFF)) | Instruction::StoreA(Address::Absolute(0xFF00 ..= 0xFFFF)) => return None,
+        Instruction::LoadA(at) => format!("ld a, {}", memory(at)),
+        Instruction::StoreA(at) => format!("ld {}, a", memory(at)),
+        Instruction::Ld16(to, nn) => format!("ld {}, ${:04X}", pair(to), nn),
+        Instruction::StoreSp(nn) => format!("ld [{}], sp", address(nn)),
+        Instruction::LdHlSpOffset(e) if e < 0 => format!("ld hl, sp-{}", -(e as i16)),
+        Instruction::LdHlSpOffset(e) => format!("ld hl, sp+{}", e),
+        Instruction::LdSpHl => "ld sp, hl".to_string(),
+        Instruction::Push(to) => format!("push {}", pair(to)),
+        Instruction::Pop(to) => format!("pop {}", pair(to)),
+        Instruction::Inc16(to) => format!("inc {}", pair(to)),
+        Instruction::Dec16(to) => format!("dec {}", pair(to)),
+        Instruction::AddHl(from) => format!("add hl, {}", pair(from)),
+        Instruction::AddSp(e) => format!("add sp, {}", e),
+        Instruction::Inc(operand) => format!("inc {}", r8(operand)),
+        Instruction::Dec(operand) => format!("dec {}", r8(operand)),
+        Instruction::Alu(op, operand) => format!("{}{}", alu(op), r8(operand)),
+        Instruction::AluImmediate(op, n) => format!("{}${:02X}", alu(op), n),
+        Instruction::Daa => "daa".to_string(),
+        Instruction::Cpl => "cpl".to_string(),
+        Instruction::Scf => "scf".to_string(),
+        Instruction::Ccf => "ccf".to_string(),
+        Instruction::Rlca => "rlca".to_string(),
+        Instruction::Rrca => "rrca".to_string(),
+        Instruction::Rla => "rla".to_string(),
+        Instruction::Rra => "rra".to_string(),
+        Instruction::Jp(cc, nn) => format!("jp {}{}", condition(cc), address(nn)),
+        Instruction::JpHl => "jp hl".to_string(),
+        Instruction::Jr(cc, e) => {
+            let target = addr.wrapping_add(decoded.length).wrapping_add(e as u16);
+            format!("jr {}{}", condition(cc), address(target))
+        },
+        Instruction::Call(cc, nn) => format!("call {}{}", condition(cc), address(nn)),
+        Instruction::Ret(Some(cc)) => format!("ret {}", format!("{:?}", cc).to_lowercase()),
+        Instruction::Ret(None) => "ret".to_string(),
+        Instruction::Reti => "reti".to_string(),
+        Instruction::Rst(vector) => format!("rst ${:02X}", vector),
+        Instruction::Shift(op, operand) => format!("{} {}", format!("{:?}", op).to_lowercase(), r8(operand)),
+        Instruction::Bit(index, operand) => format!("bit {}, {}", index, r8(operand)),
+        Instruction::Res(index, operand) => format!("res {}, {}", index, r8(operand)),
+        Instruction::Set(index, operand) => format!("set {}, {}", index, r8(operand)),
+        Instruction::Invalid(_) => return None,
+    })
+}
@@ -4,4 +4,8 @@ pub enum ExecutionMode {
     Stopped,
     CpuOperation,
     Frame,
+    // these run like Running until the main board's step target is reached
+    StepOver,
+    StepOut,
+    RunToCursor,
 }
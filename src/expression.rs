@@ -0,0 +1,448 @@
+// Expressions for breakpoint conditions and log points, e.g.
+//   A == $3C && [HL] != 0
+//   W[$C0A0] > 1000 || HITS == 400
+// Numbers are decimal, or hex after $ or 0x. [addr] reads a byte, W[addr] a little-endian word.
+// Names: A F B C D E H L AF BC DE HL SP PC, the flags ZF NF HF CF, IME, LY, BANK (the rom bank
+// mapped at PC) and HITS (times the breakpoint was reached, this time included).
+// Operators bind like Rust's: * / %, + -, << >>, &, ^, |, comparisons, &&, ||. Unary - ! ~.
+// Comparisons and logic give 1 or 0, anything non-zero counts as true.
+use super::cpu::Cpu;
+use super::memory::Memory;
+use super::memory_management_unit::MemoryManagementUnit;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Name {
+    A, F, B, C, D, E, H, L,
+    Af, Bc, De, Hl, Sp, Pc,
+    Zf, Nf, Hf, Cf,
+    Ime, Ly, Bank, Hits,
+}
+
+const NAMES: [(&str, Name); 22] = [
+    ("A", Name::A), ("F", Name::F), ("B", Name::B), ("C", Name::C), ("D", Name::D), ("E", Name::E),
+    ("H", Name::H), ("L", Name::L), ("AF", Name::Af), ("BC", Name::Bc), ("DE", Name::De), ("HL", Name::Hl),
+    ("SP", Name::Sp), ("PC", Name::Pc), ("ZF", Name::Zf), ("NF", Name::Nf), ("HF", Name::Hf), ("CF", Name::Cf),
+    ("IME", Name::Ime), ("LY", Name::Ly), ("BANK", Name::Bank), ("HITS", Name::Hits),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+    Complement,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BinaryOp {
+    Or, And,
+    Equal, NotEqual, Less, LessEqual, Greater, GreaterEqual,
+    BitOr, BitXor, BitAnd,
+    ShiftLeft, ShiftRight,
+    Add, Subtract,
+    Multiply, Divide, Remainder,
+}
+
+// lowest precedence first, operators on the same level are left associative
+const BINARY_OPS: [&[(&str, BinaryOp)]; 9] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[("==", BinaryOp::Equal), ("!=", BinaryOp::NotEqual), ("<=", BinaryOp::LessEqual),
+      (">=", BinaryOp::GreaterEqual), ("<", BinaryOp::Less), (">", BinaryOp::Greater)],
+    &[("|", BinaryOp::BitOr)],
+    &[("^", BinaryOp::BitXor)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("<<", BinaryOp::ShiftLeft), (">>", BinaryOp::ShiftRight)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Subtract)],
+    &[("*", BinaryOp::Multiply), ("/", BinaryOp::Divide), ("%", BinaryOp::Remainder)],
+];
+
+// longest first, so << isn't read as <
+const SYMBOLS: [&str; 24] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+    "<", ">", "|", "^", "&", "+", "-", "*", "/", "%", "!", "~", "(", ")", "[", "]",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expression {
+    Number(i64),
+    Name(Name),
+    Byte(Box<Expression>),
+    Word(Box<Expression>),
+    Unary(UnaryOp, Box<Expression>),
+    Binary(BinaryOp, Box<Expression>, Box<Expression>),
+}
+
+// what an expression can look at
+pub struct Context<'a> {
+    pub cpu: &'a Cpu,
+    pub mmu: &'a MemoryManagementUnit,
+    pub hits: u32,
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Expression, String> {
+        let mut parser = Parser { tokens: tokenize(text)?, position: 0 };
+        let expression = parser.binary(0)?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(expression),
+            Some(token) => Err(format!("unexpected {}", token.describe())),
+        }
+    }
+
+    pub fn evaluate(&self, context: &Context) -> i64 {
+        match self {
+            Expression::Number(value) => *value,
+            Expression::Name(name) => evaluate_name(*name, context),
+            Expression::Byte(addr) => context.mmu.peek8(addr.evaluate(context) as u16) as i64,
+            Expression::Word(addr) => {
+                let addr = addr.evaluate(context) as u16;
+                context.mmu.peek8(addr) as i64 | (context.mmu.peek8(addr.wrapping_add(1)) as i64) << 8
+            }
+            Expression::Unary(op, operand) => {
+                let value = operand.evaluate(context);
+                match op {
+                    UnaryOp::Negate => value.wrapping_neg(),
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::Complement => !value,
+                }
+            }
+            // && and || don't evaluate their right side when the left decides
+            Expression::Binary(BinaryOp::And, left, right) => (left.evaluate(context) != 0 && right.evaluate(context) != 0) as i64,
+            Expression::Binary(BinaryOp::Or, left, right) => (left.evaluate(context) != 0 || right.evaluate(context) != 0) as i64,
+            Expression::Binary(op, left, right) => {
+                let (left, right) = (left.evaluate(context), right.evaluate(context));
+                match op {
+                    BinaryOp::Equal => (left == right) as i64,
+                    BinaryOp::NotEqual => (left != right) as i64,
+                    BinaryOp::Less => (left < right) as i64,
+                    BinaryOp::LessEqual => (left <= right) as i64,
+                    BinaryOp::Greater => (left > right) as i64,
+                    BinaryOp::GreaterEqual => (left >= right) as i64,
+                    BinaryOp::BitOr => left | right,
+                    BinaryOp::BitXor => left ^ right,
+                    BinaryOp::BitAnd => left & right,
+                    BinaryOp::ShiftLeft => left.wrapping_shl(right as u32),
+                    BinaryOp::ShiftRight => left.wrapping_shr(right as u32),
+                    BinaryOp::Add => left.wrapping_add(right),
+                    BinaryOp::Subtract => left.wrapping_sub(right),
+                    BinaryOp::Multiply => left.wrapping_mul(right),
+                    // dividing by zero gives zero rather than stopping the emulator
+                    BinaryOp::Divide => left.checked_div(right).unwrap_or(0),
+                    BinaryOp::Remainder => left.checked_rem(right).unwrap_or(0),
+                    BinaryOp::And | BinaryOp::Or => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+fn evaluate_name(name: Name, context: &Context) -> i64 {
+    let cpu = context.cpu;
+    let pair = |high: u8, low: u8| ((high as i64) << 8) | low as i64;
+    match name {
+        Name::A => cpu.a as i64,
+        Name::F => cpu.flags as i64,
+        Name::B => cpu.b as i64,
+        Name::C => cpu.c as i64,
+        Name::D => cpu.d as i64,
+        Name::E => cpu.e as i64,
+        Name::H => cpu.h as i64,
+        Name::L => cpu.l as i64,
+        Name::Af => pair(cpu.a, cpu.flags),
+        Name::Bc => pair(cpu.b, cpu.c),
+        Name::De => pair(cpu.d, cpu.e),
+        Name::Hl => pair(cpu.h, cpu.l),
+        Name::Sp => cpu.sp as i64,
+        Name::Pc => cpu.pc as i64,
+        Name::Zf => (cpu.flags >> 7 & 1) as i64,
+        Name::Nf => (cpu.flags >> 6 & 1) as i64,
+        Name::Hf => (cpu.flags >> 5 & 1) as i64,
+        Name::Cf => (cpu.flags >> 4 & 1) as i64,
+        Name::Ime => cpu.ime as i64,
+        Name::Ly => context.mmu.peek8(0xFF44) as i64,
+        Name::Bank => context.mmu.rom_bank(cpu.pc).map_or(-1, |bank| bank as i64),
+        Name::Hits => context.hits as i64,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Word(String),
+    Symbol(&'static str),
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Number(value) => format!("number {}", value),
+            Token::Word(word) => format!("'{}'", word),
+            Token::Symbol(symbol) => format!("'{}'", symbol),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let length = if let Some(&symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            symbol.len()
+        } else if rest.starts_with('$') || rest.starts_with(char::is_numeric) {
+            let (digits, radix, prefix) = if let Some(hex) = rest.strip_prefix('$') {
+                (hex, 16, 1)
+            } else if let Some(hex) = rest.strip_prefix("0x").or_else(|| rest.strip_prefix("0X")) {
+                (hex, 16, 2)
+            } else {
+                (rest, 10, 0)
+            };
+            let length = digits.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(digits.len());
+            let value = i64::from_str_radix(&digits[.. length], radix)
+                .map_err(|_| format!("bad number {}", &rest[.. prefix + length]))?;
+            tokens.push(Token::Number(value));
+            prefix + length
+        } else if rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            let length = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[.. length].to_ascii_uppercase()));
+            length
+        } else {
+            return Err(format!("unexpected '{}'", rest.chars().next().unwrap()));
+        };
+        rest = rest[length ..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if self.tokens.get(self.position) == Some(&Token::Symbol(SYMBOLS.iter().find(|&&s| s == symbol).unwrap())) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if self.eat(symbol) {
+            return Ok(());
+        }
+        match self.tokens.get(self.position) {
+            Some(token) => Err(format!("expected '{}', got {}", symbol, token.describe())),
+            None => Err(format!("expected '{}' at the end", symbol)),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expression, String> {
+        if level == BINARY_OPS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        'operators: loop {
+            for &(symbol, op) in BINARY_OPS[level] {
+                if self.eat(symbol) {
+                    let right = self.binary(level + 1)?;
+                    left = Expression::Binary(op, Box::new(left), Box::new(right));
+                    continue 'operators;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        for (symbol, op) in [("-", UnaryOp::Negate), ("!", UnaryOp::Not), ("~", UnaryOp::Complement)] {
+            if self.eat(symbol) {
+                return Ok(Expression::Unary(op, Box::new(self.unary()?)));
+            }
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expression, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expression::Number(value)),
+            Some(Token::Symbol("(")) => {
+                let expression = self.binary(0)?;
+                self.expect(")")?;
+                Ok(expression)
+            }
+            Some(Token::Symbol("[")) => {
+                let addr = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Expression::Byte(Box::new(addr)))
+            }
+            Some(Token::Word(word)) if word == "W" && self.eat("[") => {
+                let addr = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Expression::Word(Box::new(addr)))
+            }
+            Some(Token::Word(word)) => match NAMES.iter().find(|&&(name, _)| name == word) {
+                Some(&(_, name)) => Ok(Expression::Name(name)),
+                None => Err(format!("unknown name {}", word)),
+            },
+            Some(token) => Err(format!("unexpected {}", token.describe())),
+            None => Err("unexpected end".to_string()),
+        }
+    }
+}
+
+// Log point text with expressions in braces, e.g. "A={A} called {HITS:d} times".
+// Values are shown in hex, or decimal with :d
+pub struct LogMessage {
+    parts: Vec<LogPart>,
+}
+
+enum LogPart {
+    Text(String),
+    Value(Expression, bool),
+}
+
+impl LogMessage {
+    pub fn parse(text: &str) -> Result<LogMessage, String> {
+        let mut parts = Vec::new();
+        let mut rest = text;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start ..].find('}').ok_or("missing '}'")? + start;
+            if start > 0 {
+                parts.push(LogPart::Text(rest[.. start].to_string()));
+            }
+            let inside = &rest[start + 1 .. end];
+            let (expression, decimal) = match inside.strip_suffix(":d") {
+                Some(expression) => (expression, true),
+                None => (inside, false),
+            };
+            parts.push(LogPart::Value(Expression::parse(expression)?, decimal));
+            rest = &rest[end + 1 ..];
+        }
+        if !rest.is_empty() {
+            parts.push(LogPart::Text(rest.to_string()));
+        }
+        Ok(LogMessage { parts })
+    }
+
+    pub fn format(&self, context: &Context) -> String {
+        self.parts.iter().map(|part| match part {
+            LogPart::Text(text) => text.clone(),
+            LogPart::Value(expression, true) => expression.evaluate(context).to_string(),
+            LogPart::Value(expression, false) => match expression.evaluate(context) {
+                value @ 0 ..= 0xFF => format!("${:02X}", value),
+                value => format!("${:04X}", value),
+            },
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::model::Model;
+
+    fn number(value: i64) -> Box<Expression> {
+        Box::new(Expression::Number(value))
+    }
+
+    fn name(name: Name) -> Box<Expression> {
+        Box::new(Expression::Name(name))
+    }
+
+    fn binary(op: BinaryOp, left: Box<Expression>, right: Box<Expression>) -> Box<Expression> {
+        Box::new(Expression::Binary(op, left, right))
+    }
+
+    // an empty 32KiB rom without an mbc, the tests run in parallel so each has its own file
+    fn mmu(test: &str) -> MemoryManagementUnit {
+        let path = std::env::temp_dir().join(format!("rustyboy-{}-{}.gb", test, std::process::id()));
+        std::fs::write(&path, vec![0x00; 0x8000]).unwrap();
+        let mmu = MemoryManagementUnit::init(path.to_str().unwrap(), None, Some(Model::Dmg)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        mmu
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(Expression::parse("1 + 2 * 3"),
+            Ok(*binary(BinaryOp::Add, number(1), binary(BinaryOp::Multiply, number(2), number(3)))));
+        assert_eq!(Expression::parse("(1 + 2) * 3"),
+            Ok(*binary(BinaryOp::Multiply, binary(BinaryOp::Add, number(1), number(2)), number(3))));
+        assert_eq!(Expression::parse("A == 1 || B == 2 && C"),
+            Ok(*binary(BinaryOp::Or,
+                binary(BinaryOp::Equal, name(Name::A), number(1)),
+                binary(BinaryOp::And, binary(BinaryOp::Equal, name(Name::B), number(2)), name(Name::C)))));
+        // left associative
+        assert_eq!(Expression::parse("8 - 2 - 1"),
+            Ok(*binary(BinaryOp::Subtract, binary(BinaryOp::Subtract, number(8), number(2)), number(1))));
+        assert_eq!(Expression::parse("-1 << 4"),
+            Ok(*binary(BinaryOp::ShiftLeft, Box::new(Expression::Unary(UnaryOp::Negate, number(1))), number(4))));
+    }
+
+    #[test]
+    fn literals_and_memory() {
+        assert_eq!(Expression::parse("$3C"), Ok(Expression::Number(0x3C)));
+        assert_eq!(Expression::parse("0xC0a0"), Ok(Expression::Number(0xC0A0)));
+        assert_eq!(Expression::parse("400"), Ok(Expression::Number(400)));
+        assert_eq!(Expression::parse("hl"), Ok(Expression::Name(Name::Hl)));
+        assert_eq!(Expression::parse("[HL]"), Ok(Expression::Byte(name(Name::Hl))));
+        assert_eq!(Expression::parse("W[$C0A0 + 1]"),
+            Ok(Expression::Word(binary(BinaryOp::Add, number(0xC0A0), number(1)))));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Expression::parse("(1"), Err("expected ')' at the end".to_string()));
+        assert_eq!(Expression::parse("[1 2"), Err("expected ']', got number 2".to_string()));
+        assert_eq!(Expression::parse("foo"), Err("unknown name FOO".to_string()));
+        assert_eq!(Expression::parse("1 +"), Err("unexpected end".to_string()));
+        assert_eq!(Expression::parse("1 2"), Err("unexpected number 2".to_string()));
+        assert_eq!(Expression::parse("$XY"), Err("bad number $XY".to_string()));
+        assert_eq!(Expression::parse("A @ 1"), Err("unexpected '@'".to_string()));
+    }
+
+    #[test]
+    fn evaluate() {
+        let mmu = Rc::new(RefCell::new(mmu("evaluate")));
+        let mut cpu = Cpu::init(mmu.clone(), Model::Dmg, false);
+        (cpu.a, cpu.h, cpu.l) = (0x3C, 0xC0, 0xA0);
+        mmu.borrow_mut().write8(0xC0A0, 0x34);
+        mmu.borrow_mut().write8(0xC0A1, 0x12);
+        let mmu = mmu.borrow();
+        let context = Context { cpu: &cpu, mmu: &mmu, hits: 400 };
+        let evaluate = |text: &str| Expression::parse(text).unwrap().evaluate(&context);
+        assert_eq!(evaluate("1 + 2 * 3"), 7);
+        assert_eq!(evaluate("[HL]"), 0x34);
+        assert_eq!(evaluate("W[$C0A0]"), 0x1234);
+        assert_eq!(evaluate("A == $3C && [HL] != 0"), 1);
+        assert_eq!(evaluate("HITS == 400 || 1 / 0"), 1);
+        assert_eq!(evaluate("7 / 0"), 0);
+        assert_eq!(evaluate("!A"), 0);
+        assert_eq!(evaluate("~0"), -1);
+    }
+
+    #[test]
+    fn log_message() {
+        let mmu = Rc::new(RefCell::new(mmu("log_message")));
+        let mut cpu = Cpu::init(mmu.clone(), Model::Dmg, false);
+        (cpu.a, cpu.h, cpu.l) = (0x3C, 0xC0, 0x00);
+        let mmu = mmu.borrow();
+        let context = Context { cpu: &cpu, mmu: &mmu, hits: 400 };
+        let message = LogMessage::parse("A={A} HL={HL} called {HITS:d} times").unwrap();
+        assert_eq!(message.format(&context), "A=$3C HL=$C000 called 400 times");
+        assert_eq!(LogMessage::parse("{A}").unwrap().format(&context), "$3C");
+        assert_eq!(LogMessage::parse("no values").unwrap().format(&context), "no values");
+        assert_eq!(LogMessage::parse("A={A").err(), Some("missing '}'".to_string()));
+        assert_eq!(LogMessage::parse("A={foo}").err(), Some("unknown name FOO".to_string()));
+    }
+}
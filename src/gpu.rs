@@ -1,7 +1,11 @@
 use std::{rc::Rc, cell::RefCell};
-use super::interrupts::Interrupts;
+use super::color_correction;
+use super::color_correction::ColorCorrection;
+use super::interrupts::{Interrupt, Interrupts};
 use super::memory::Memory;
-use super::palette::PaletteData;
+use super::model::Model;
+use super::save_state::{SaveState, StateReader, StateWriter};
+use super::palette::{PaletteData, PaletteDataColor, PaletteLayer, ShadeColors, DMG_SHADES};
 
 pub const WIDTH: usize = 160;
 pub const HEIGHT: usize = 144;
@@ -10,16 +14,40 @@ pub const SCANLINES: usize = 154;
 pub const DOTS_PER_VBLANK: usize = 10 * DOTS_PER_HLINE;
 pub const DOTS_PER_FRAME: usize = 70224;
 pub const DOTS_BEFORE_VBLANK: usize = DOTS_PER_FRAME - DOTS_PER_VBLANK;
+pub const VRAM_SIZE: usize = 0x2000; // per bank, CGB has 2 banks
+pub const OAM_SIZE: usize = 0xA0;
+pub const PALETTE_RAM_SIZE: usize = 64; // CGB: 8 palettes of 4 RGB555 colors
+const MAX_SPRITES_PER_LINE: usize = 10;
 
+#[derive(Copy, Clone, PartialEq)]
 enum Mode {
-    HorizontalBlank,
-    VerticalBlank,
-    OamScan,
-    DrawingPixels,
+    HorizontalBlank = 0,
+    VerticalBlank = 1,
+    OamScan = 2,
+    DrawingPixels = 3,
 }
 
-fn will_enter_vblank(current_dot: usize, cycles_to_run: usize) -> bool {
-    return current_dot < DOTS_BEFORE_VBLANK && ((current_dot + cycles_to_run) >= DOTS_BEFORE_VBLANK);
+// https://gbdev.io/pandocs/LCDC.html
+#[derive(Copy, Clone)]
+enum LcdControl {
+    BackgroundEnable = 1 << 0,
+    ObjEnable = 1 << 1,
+    ObjSize = 1 << 2,
+    BackgroundTileMap = 1 << 3,
+    TileData = 1 << 4,
+    WindowEnable = 1 << 5,
+    WindowTileMap = 1 << 6,
+    LcdEnable = 1 << 7,
+}
+
+// https://gbdev.io/pandocs/STAT.html
+#[derive(Copy, Clone)]
+enum LcdStatus {
+    LycEqualsLy = 1 << 2,
+    HorizontalBlankInterrupt = 1 << 3,
+    VerticalBlankInterrupt = 1 << 4,
+    OamScanInterrupt = 1 << 5,
+    LycInterrupt = 1 << 6,
 }
 
 pub struct Gpu {
@@ -32,43 +60,117 @@ pub struct Gpu {
     scroll_y: u8,
     scroll_x: u8,
     lcd_y_coordinate: u8,
+    // LY always reads 0x90, the Gameboy Doctor reference logs are made that way
+    pub stub_ly: bool,
     ly_compare: u8,
     background_palette: PaletteData,
     obj_palette_0: PaletteData,
     obj_palette_1: PaletteData,
     window_pos_y: u8,
     window_pos_x: u8,
+    // the window has its own line counter, it only advances on lines where the window was drawn
+    window_line: u8,
+    pub vram: [u8; VRAM_SIZE * 2],
+    pub oam: [u8; OAM_SIZE],
+    pub model: Model,
+    // https://gbdev.io/pandocs/CGB_Registers.html
+    pub cgb_mode: bool,
+    vram_bank: u8,
+    pub background_palette_ram: [u8; PALETTE_RAM_SIZE],
+    pub obj_palette_ram: [u8; PALETTE_RAM_SIZE],
+    background_palette_spec: u8,
+    obj_palette_spec: u8,
+    obj_priority_mode: u8,
+    // shades of the finished pixels, see palette::PaletteDataColor
+    framebuffer: [[u8; WIDTH]; HEIGHT],
+    // CGB mode pixels are RGB555 colors from palette ram instead of shades
+    framebuffer_rgb555: [[u16; WIDTH]; HEIGHT],
+    // the palette register each pixel was drawn with, so layers can be given different colors
+    framebuffer_layers: [[PaletteLayer; WIDTH]; HEIGHT],
+    frame_ready: bool,
+    // set when a visible line enters horizontal blank, for HBlank DMA
+    hblank_started: bool,
+    pub shade_colors: ShadeColors,
+    // applied to CGB colors when the framebuffer is converted to RGB
+    pub color_correction: ColorCorrection,
 }
 
 impl Gpu {
-    pub fn init(interrupts: Rc<RefCell<Interrupts>>) -> Self {
+    pub fn init(interrupts: Rc<RefCell<Interrupts>>, model: Model, cgb_mode: bool) -> Self {
         Self {
             interrupts: interrupts,
             current_dot: 0,
             mode: Mode::OamScan,
-            lcd_control: 0x00, // TODO https://gbdev.io/pandocs/LCDC.html
-            lcd_status: 0x00, // TODO https://gbdev.io/pandocs/STAT.html#ff41---stat-lcd-status-rw
+            lcd_control: 0x91, // https://gbdev.io/pandocs/Power_Up_Sequence.html
+            // https://gbdev.io/pandocs/STAT.html#ff41---stat-lcd-status-rw
+            lcd_status: LcdStatus::LycEqualsLy as u8 | Mode::OamScan as u8,
             scroll_y: 0x00,
             scroll_x: 0x00,
             lcd_y_coordinate: 0x00,
+            stub_ly: false,
             ly_compare: 0x00,
-            background_palette: PaletteData::init(0x00),
+            background_palette: PaletteData::init(0xFC),
             obj_palette_0: PaletteData::init(0x00),
             obj_palette_1: PaletteData::init(0x00),
             window_pos_y: 0,
             window_pos_x: 0,
+            window_line: 0,
+            vram: [0x0; VRAM_SIZE * 2],
+            oam: [0x0; OAM_SIZE],
+            model,
+            cgb_mode,
+            vram_bank: 0,
+            // the CGB boot rom leaves all background colors white
+            background_palette_ram: [0xFF; PALETTE_RAM_SIZE],
+            obj_palette_ram: [0xFF; PALETTE_RAM_SIZE],
+            background_palette_spec: 0x00,
+            obj_palette_spec: 0x00,
+            obj_priority_mode: if cgb_mode { 0x00 } else { 0x01 },
+            framebuffer: [[0x0; WIDTH]; HEIGHT],
+            framebuffer_rgb555: [[0x7FFF; WIDTH]; HEIGHT],
+            framebuffer_layers: [[PaletteLayer::Background; WIDTH]; HEIGHT],
+            frame_ready: false,
+            hblank_started: false,
+            shade_colors: ShadeColors::init(DMG_SHADES),
+            color_correction: ColorCorrection::GbcLcd,
         }
     }
 
+    fn is_lcdc_set(&self, bit: LcdControl) -> bool {
+        self.lcd_control & bit as u8 != 0
+    }
+
+    pub fn is_lcd_enabled(&self) -> bool {
+        self.is_lcdc_set(LcdControl::LcdEnable)
+    }
+
+    // true once after each horizontal blank of a visible line began
+    pub fn take_hblank_started(&mut self) -> bool {
+        std::mem::take(&mut self.hblank_started)
+    }
+
+    fn is_stat_set(&self, bit: LcdStatus) -> bool {
+        self.lcd_status & bit as u8 != 0
+    }
+
     pub fn run_cycles(&mut self, cpu_clock_cycles: u32) {
-        // mainly used when to determine vblank periods
-        // TODO - dots take twice as many cycles if the cpu is running double speed
-        if will_enter_vblank(self.current_dot, cpu_clock_cycles as usize) {
-            // TODO interrupts.vblank = true; or something
-        }
-        self.current_dot = (self.current_dot + cpu_clock_cycles as usize) % DOTS_PER_FRAME;
-        self.lcd_y_coordinate = (self.current_dot / DOTS_PER_HLINE) as u8;
-        self.mode = if self.current_dot >= DOTS_BEFORE_VBLANK {
+        // in double speed mode the mmu only passes on half of the cpu cycles
+        if !self.is_lcdc_set(LcdControl::LcdEnable) {
+            return;
+        }
+        for _ in 0 .. cpu_clock_cycles {
+            self.step_dot();
+        }
+        // 4_194_304 cpu_clock_cycles / second
+    }
+
+    fn step_dot(&mut self) {
+        self.current_dot = (self.current_dot + 1) % DOTS_PER_FRAME;
+        if self.current_dot.is_multiple_of(DOTS_PER_HLINE) {
+            self.lcd_y_coordinate = (self.current_dot / DOTS_PER_HLINE) as u8;
+            self.compare_ly();
+        }
+        let mode = if self.current_dot >= DOTS_BEFORE_VBLANK {
             Mode::VerticalBlank
         } else {
             // TODO - some actions lengthen mode 3 (drawing pixels) https://gbdev.io/pandocs/pixel_fifo.html
@@ -77,29 +179,292 @@ impl Gpu {
                 80 ..= 251 => Mode::DrawingPixels,
                 _ => Mode::HorizontalBlank,
             }
+        };
+        if mode != self.mode {
+            self.enter_mode(mode);
+        }
+    }
+
+    fn enter_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.lcd_status = (self.lcd_status & !0x03) | mode as u8;
+        let stat_interrupt = match mode {
+            Mode::HorizontalBlank => {
+                self.render_scanline();
+                self.hblank_started = true;
+                self.is_stat_set(LcdStatus::HorizontalBlankInterrupt)
+            }
+            Mode::VerticalBlank => {
+                self.interrupts.borrow_mut().request(Interrupt::VBlank);
+                self.frame_ready = true;
+                self.window_line = 0;
+                self.is_stat_set(LcdStatus::VerticalBlankInterrupt)
+            }
+            Mode::OamScan => self.is_stat_set(LcdStatus::OamScanInterrupt),
+            Mode::DrawingPixels => false,
+        };
+        if stat_interrupt {
+            self.interrupts.borrow_mut().request(Interrupt::LcdStat);
+        }
+    }
+
+    fn compare_ly(&mut self) {
+        if self.lcd_y_coordinate == self.ly_compare {
+            self.lcd_status |= LcdStatus::LycEqualsLy as u8;
+            if self.is_stat_set(LcdStatus::LycInterrupt) {
+                self.interrupts.borrow_mut().request(Interrupt::LcdStat);
+            }
+        } else {
+            self.lcd_status &= !(LcdStatus::LycEqualsLy as u8);
+        }
+    }
+
+    fn write_lcd_status(&mut self, data: u8) {
+        self.lcd_status = (self.lcd_status & 0x7) | data & 0xF8;
+        // https://gbdev.io/pandocs/STAT.html#spurious-stat-interrupts
+        // pre-CGB models briefly enable every STAT source on a write, firing in either blank or on LY=LYC
+        let in_blank = self.mode == Mode::HorizontalBlank || self.mode == Mode::VerticalBlank;
+        if !self.model.is_cgb() && self.is_lcd_enabled() && (in_blank || self.is_stat_set(LcdStatus::LycEqualsLy)) {
+            self.interrupts.borrow_mut().request(Interrupt::LcdStat);
+        }
+    }
+
+    fn write_lcd_control(&mut self, data: u8) {
+        let was_enabled = self.is_lcdc_set(LcdControl::LcdEnable);
+        self.lcd_control = data;
+        if was_enabled && !self.is_lcdc_set(LcdControl::LcdEnable) {
+            // the screen goes blank, and LY is held at 0 until the LCD is switched back on
+            self.current_dot = 0;
+            self.lcd_y_coordinate = 0;
+            self.window_line = 0;
+            self.mode = Mode::HorizontalBlank;
+            self.lcd_status &= !0x03;
+            self.framebuffer = [[0x0; WIDTH]; HEIGHT];
+            self.framebuffer_layers = [[PaletteLayer::Background; WIDTH]; HEIGHT];
+            self.framebuffer_rgb555 = [[0x7FFF; WIDTH]; HEIGHT];
+            self.frame_ready = true;
+        } else if !was_enabled && self.is_lcdc_set(LcdControl::LcdEnable) {
+            self.mode = Mode::OamScan;
+            self.lcd_status = (self.lcd_status & !0x03) | Mode::OamScan as u8;
+            self.compare_ly();
+        }
+    }
+
+    // https://gbdev.io/pandocs/Tile_Data.html
+    fn tile_data_address(&self, tile_index: u8) -> usize {
+        if self.is_lcdc_set(LcdControl::TileData) {
+            tile_index as usize * 16
+        } else {
+            (0x1000 + (tile_index as i8 as i32) * 16) as usize
+        }
+    }
+
+    // the 2bit color index of a pixel of the tile whose data starts at tile_address in the given vram bank
+    fn tile_pixel(&self, bank: usize, tile_address: usize, x: u8, y: u8) -> u8 {
+        let address = bank * VRAM_SIZE + tile_address + y as usize * 2;
+        let low = self.vram[address];
+        let high = self.vram[address + 1];
+        let bit = 7 - x;
+        ((high >> bit) & 0x1) << 1 | ((low >> bit) & 0x1)
+    }
+
+    fn render_scanline(&mut self) {
+        let line = self.lcd_y_coordinate as usize;
+        if line >= HEIGHT {
+            return;
+        }
+        let mut background_color_indices = [0u8; WIDTH];
+        let mut background_priorities = [false; WIDTH];
+        // on CGB the background is always drawn, LCDC bit 0 only takes away its priority over sprites
+        if self.cgb_mode || self.is_lcdc_set(LcdControl::BackgroundEnable) {
+            self.render_background_line(line, &mut background_color_indices, &mut background_priorities);
+        } else {
+            self.framebuffer[line] = [PaletteDataColor::White as u8; WIDTH];
+        }
+        self.framebuffer_layers[line] = [PaletteLayer::Background; WIDTH];
+        if self.is_lcdc_set(LcdControl::ObjEnable) {
+            self.render_sprite_line(line, &background_color_indices, &background_priorities);
+        }
+    }
+
+    fn render_background_line(&mut self, line: usize, color_indices: &mut [u8; WIDTH], priorities: &mut [bool; WIDTH]) {
+        let window_visible = self.is_lcdc_set(LcdControl::WindowEnable)
+            && line >= self.window_pos_y as usize
+            && self.window_pos_x <= 166;
+        let background_map = if self.is_lcdc_set(LcdControl::BackgroundTileMap) { 0x1C00 } else { 0x1800 };
+        let window_map = if self.is_lcdc_set(LcdControl::WindowTileMap) { 0x1C00 } else { 0x1800 };
+        let window_start_x = self.window_pos_x as i32 - 7;
+        for x in 0 .. WIDTH {
+            let (map, map_x, map_y) = if window_visible && x as i32 >= window_start_x {
+                (window_map, (x as i32 - window_start_x) as u8, self.window_line)
+            } else {
+                (background_map, (x as u8).wrapping_add(self.scroll_x), (line as u8).wrapping_add(self.scroll_y))
+            };
+            let map_address = map + (map_y as usize / 8) * 32 + map_x as usize / 8;
+            let tile_index = self.vram[map_address];
+            // https://gbdev.io/pandocs/Tile_Maps.html#bg-map-attributes-cgb-mode-only
+            let attributes = if self.cgb_mode { self.vram[VRAM_SIZE + map_address] } else { 0x00 };
+            let bank = ((attributes >> 3) & 0x1) as usize;
+            let tile_x = if attributes & 0x20 != 0 { 7 - map_x % 8 } else { map_x % 8 };
+            let tile_y = if attributes & 0x40 != 0 { 7 - map_y % 8 } else { map_y % 8 };
+            let color_index = self.tile_pixel(bank, self.tile_data_address(tile_index), tile_x, tile_y);
+            color_indices[x] = color_index;
+            priorities[x] = attributes & 0x80 != 0;
+            if self.cgb_mode {
+                self.framebuffer_rgb555[line][x] = cgb_color(&self.background_palette_ram, attributes & 0x07, color_index);
+            } else {
+                self.framebuffer[line][x] = self.background_palette.color(color_index) as u8;
+            }
+        }
+        if window_visible {
+            self.window_line = self.window_line.wrapping_add(1);
+        }
+    }
+
+    // https://gbdev.io/pandocs/OAM.html
+    fn render_sprite_line(&mut self, line: usize, background_color_indices: &[u8; WIDTH], background_priorities: &[bool; WIDTH]) {
+        let sprite_height = if self.is_lcdc_set(LcdControl::ObjSize) { 16 } else { 8 };
+        let mut sprites: Vec<usize> = (0 .. 40)
+            .filter(|sprite| {
+                let top = self.oam[sprite * 4] as i32 - 16;
+                top <= line as i32 && (line as i32) < top + sprite_height
+            })
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+        // on DMG the sprite with the smaller x coordinate wins, then the one earlier in OAM.
+        // CGB only looks at the OAM position, unless OPRI asks for DMG priorities.
+        // draw in reverse priority so the winners are drawn last.
+        if self.obj_priority_mode & 0x01 != 0 {
+            sprites.sort_by_key(|sprite| (self.oam[sprite * 4 + 1], *sprite));
+        }
+        // in CGB mode clearing LCDC bit 0 puts all sprites above the background
+        let background_can_win = !self.cgb_mode || self.is_lcdc_set(LcdControl::BackgroundEnable);
+        for &sprite in sprites.iter().rev() {
+            let y = self.oam[sprite * 4] as i32 - 16;
+            let x = self.oam[sprite * 4 + 1] as i32 - 8;
+            let attributes = self.oam[sprite * 4 + 3];
+            let mut tile_index = self.oam[sprite * 4 + 2];
+            if sprite_height == 16 {
+                tile_index &= 0xFE;
+            }
+            let mut row = line as i32 - y;
+            if attributes & 0x40 != 0 {
+                row = sprite_height - 1 - row;
+            }
+            let (palette, layer) = if attributes & 0x10 != 0 {
+                (&self.obj_palette_1, PaletteLayer::Obj1)
+            } else {
+                (&self.obj_palette_0, PaletteLayer::Obj0)
+            };
+            let bank = if self.cgb_mode { ((attributes >> 3) & 0x1) as usize } else { 0 };
+            let tile_address = tile_index as usize * 16 + if row >= 8 { 16 } else { 0 };
+            for column in 0 .. 8 {
+                let screen_x = x + column;
+                if screen_x < 0 || screen_x >= WIDTH as i32 {
+                    continue;
+                }
+                let screen_x = screen_x as usize;
+                let tile_column = if attributes & 0x20 != 0 { 7 - column } else { column };
+                let color_index = self.tile_pixel(bank, tile_address, tile_column as u8, (row % 8) as u8);
+                if color_index == 0 {
+                    continue; // transparent
+                }
+                let behind_background = attributes & 0x80 != 0 || background_priorities[screen_x];
+                if background_can_win && behind_background && background_color_indices[screen_x] != 0 {
+                    continue;
+                }
+                if self.cgb_mode {
+                    self.framebuffer_rgb555[line][screen_x] = cgb_color(&self.obj_palette_ram, attributes & 0x07, color_index);
+                } else {
+                    self.framebuffer[line][screen_x] = palette.color(color_index) as u8;
+                    self.framebuffer_layers[line][screen_x] = layer;
+                }
+            }
         }
-        // 4_194_304 cpu_clock_cycles / second
     }
- 
 
+    // returns the finished frame once per vblank
     pub fn get_updated_image(&mut self) -> std::option::Option<[[u8; WIDTH]; HEIGHT]> {
-        None
-        // TODO
+        if self.frame_ready {
+            self.frame_ready = false;
+            Some(self.framebuffer)
+        } else {
+            None
+        }
+    }
+
+    pub fn framebuffer(&self) -> &[[u8; WIDTH]; HEIGHT] {
+        &self.framebuffer
+    }
+
+    // the framebuffer as RGBA8 pixels, ready to be uploaded as a texture
+    pub fn framebuffer_rgba(&self) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(WIDTH * HEIGHT * 4);
+        if self.cgb_mode {
+            for &color in self.framebuffer_rgb555.iter().flatten() {
+                let rgb = color_correction::correct(self.color_correction, color);
+                pixels.extend_from_slice(&[rgb[0], rgb[1], rgb[2], 0xFF]);
+            }
+            return pixels;
+        }
+        for (shades, layers) in self.framebuffer.iter().zip(self.framebuffer_layers.iter()) {
+            for (&shade, &layer) in shades.iter().zip(layers.iter()) {
+                let rgb = self.shade_colors.layers[layer as usize][shade as usize];
+                pixels.extend_from_slice(&[rgb[0], rgb[1], rgb[2], 0xFF]);
+            }
+        }
+        pixels
+    }
+
+    // https://gbdev.io/pandocs/SGB_VRAM_Transfer.html
+    // the Super Game Boy reads 4KB from the screen: the data of the first 256 background tiles,
+    // shown in order from the top left of the background map
+    pub fn sgb_transfer_data(&self) -> Vec<u8> {
+        let background_map = if self.is_lcdc_set(LcdControl::BackgroundTileMap) { 0x1C00 } else { 0x1800 };
+        let mut data = Vec::with_capacity(256 * 16);
+        for tile in 0 .. 256 {
+            let tile_index = self.vram[background_map + (tile / 20) * 32 + tile % 20];
+            let address = self.tile_data_address(tile_index);
+            data.extend_from_slice(&self.vram[address .. address + 16]);
+        }
+        data
     }
 
     pub fn is_in_vblank(&self) -> bool {
         return 144 <= self.lcd_y_coordinate && self.lcd_y_coordinate <= 153;
     }
+
+    // https://gbdev.io/pandocs/Palettes.html#lcd-color-palettes-cgb-only
+    fn write_palette_data(palette_ram: &mut [u8; PALETTE_RAM_SIZE], spec: &mut u8, data: u8) {
+        palette_ram[(*spec & 0x3F) as usize] = data;
+        if *spec & 0x80 != 0 {
+            *spec = 0x80 | ((*spec + 1) & 0x3F);
+        }
+    }
+}
+
+// a little-endian RGB555 color from CGB palette ram
+fn cgb_color(palette_ram: &[u8; PALETTE_RAM_SIZE], palette: u8, color_index: u8) -> u16 {
+    let offset = palette as usize * 8 + color_index as usize * 2;
+    u16::from(palette_ram[offset]) | (u16::from(palette_ram[offset + 1]) << 8)
+}
+
+pub fn rgb555_to_rgb888(color: u16) -> [u8; 3] {
+    let expand = |channel: u16| ((channel << 3) | (channel >> 2)) as u8;
+    [expand(color & 0x1F), expand((color >> 5) & 0x1F), expand((color >> 10) & 0x1F)]
 }
 
 impl Memory for Gpu {
     // TODO - some memories are inaccessible in certian modes: https://gbdev.io/pandocs/pixel_fifo.html#pixel-fifo
     fn read8(&self, addr: u16) -> u8 {
         match addr {
+            0x8000 ..= 0x9FFF => self.vram[self.vram_bank as usize * VRAM_SIZE + (addr - 0x8000) as usize],
+            0xFE00 ..= 0xFE9F => self.oam[(addr - 0xFE00) as usize],
             0xFF40 => self.lcd_control,
-            0xFF41 => self.lcd_status,
+            0xFF41 => 0x80 | self.lcd_status,
             0xFF42 => self.scroll_y,
             0xFF43 => self.scroll_x,
+            0xFF44 if self.stub_ly => 0x90,
             0xFF44 => self.lcd_y_coordinate,
             0xFF45 => self.ly_compare,
             0xFF47 => self.background_palette.read(),
@@ -107,14 +472,23 @@ impl Memory for Gpu {
             0xFF49 => self.obj_palette_1.read(),
             0xFF4A => self.window_pos_y,
             0xFF4B => self.window_pos_x,
+            0xFF4F | 0xFF68 ..= 0xFF6C if !self.cgb_mode => 0xFF,
+            0xFF4F => 0xFE | self.vram_bank,
+            0xFF68 => 0x40 | self.background_palette_spec,
+            0xFF69 => self.background_palette_ram[(self.background_palette_spec & 0x3F) as usize],
+            0xFF6A => 0x40 | self.obj_palette_spec,
+            0xFF6B => self.obj_palette_ram[(self.obj_palette_spec & 0x3F) as usize],
+            0xFF6C => 0xFE | self.obj_priority_mode,
             _ => panic!("unimplemented address read on Gpu {:#04x}", addr)
         }
     }
 
     fn write8(&mut self, addr: u16, data: u8) {
         match addr {
-            0xFF40 => self.lcd_control = data,
-            0xFF41 => self.lcd_status = (self.lcd_status & 0x7) | data & 0xF8,
+            0x8000 ..= 0x9FFF => self.vram[self.vram_bank as usize * VRAM_SIZE + (addr - 0x8000) as usize] = data,
+            0xFE00 ..= 0xFE9F => self.oam[(addr - 0xFE00) as usize] = data,
+            0xFF40 => self.write_lcd_control(data),
+            0xFF41 => self.write_lcd_status(data),
             0xFF42 => self.scroll_y = data,
             0xFF43 => self.scroll_x = data,
             0xFF44 => self.lcd_y_coordinate = 0x00,
@@ -124,9 +498,101 @@ impl Memory for Gpu {
             0xFF49 => self.obj_palette_1 = PaletteData::init(data),
             0xFF4A => self.window_pos_y = data,
             0xFF4B => self.window_pos_x = data,
+            0xFF4F | 0xFF68 ..= 0xFF6C if !self.cgb_mode => {},
+            0xFF4F => self.vram_bank = data & 0x01,
+            0xFF68 => self.background_palette_spec = data & 0xBF,
+            0xFF69 => Gpu::write_palette_data(&mut self.background_palette_ram, &mut self.background_palette_spec, data),
+            0xFF6A => self.obj_palette_spec = data & 0xBF,
+            0xFF6B => Gpu::write_palette_data(&mut self.obj_palette_ram, &mut self.obj_palette_spec, data),
+            0xFF6C => self.obj_priority_mode = data & 0x01,
             _ => panic!("unimplemented address write on Gpu {:#04x}", addr)
 
         }
     }
 }
- 
\ No newline at end of file
+
+// shade colors and color correction are display settings, they stay as the user picked them
+impl SaveState for Gpu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.current_dot as u32);
+        writer.write_u8(self.mode as u8);
+        writer.write_u8(self.lcd_control);
+        writer.write_u8(self.lcd_status);
+        writer.write_u8(self.scroll_y);
+        writer.write_u8(self.scroll_x);
+        writer.write_u8(self.lcd_y_coordinate);
+        writer.write_u8(self.ly_compare);
+        writer.write_u8(self.background_palette.read());
+        writer.write_u8(self.obj_palette_0.read());
+        writer.write_u8(self.obj_palette_1.read());
+        writer.write_u8(self.window_pos_y);
+        writer.write_u8(self.window_pos_x);
+        writer.write_u8(self.window_line);
+        writer.write_bytes(&self.vram);
+        writer.write_bytes(&self.oam);
+        writer.write_u8(self.vram_bank);
+        writer.write_bytes(&self.background_palette_ram);
+        writer.write_bytes(&self.obj_palette_ram);
+        writer.write_u8(self.background_palette_spec);
+        writer.write_u8(self.obj_palette_spec);
+        writer.write_u8(self.obj_priority_mode);
+        for line in self.framebuffer.iter() {
+            writer.write_bytes(line);
+        }
+        for &color in self.framebuffer_rgb555.iter().flatten() {
+            writer.write_u16(color);
+        }
+        for &layer in self.framebuffer_layers.iter().flatten() {
+            writer.write_u8(layer as u8);
+        }
+        writer.write_bool(self.frame_ready);
+        writer.write_bool(self.hblank_started);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.current_dot = reader.read_u32()? as usize % DOTS_PER_FRAME;
+        self.mode = match reader.read_u8()? & 0x03 {
+            0 => Mode::HorizontalBlank,
+            1 => Mode::VerticalBlank,
+            2 => Mode::OamScan,
+            _ => Mode::DrawingPixels,
+        };
+        self.lcd_control = reader.read_u8()?;
+        self.lcd_status = reader.read_u8()?;
+        self.scroll_y = reader.read_u8()?;
+        self.scroll_x = reader.read_u8()?;
+        self.lcd_y_coordinate = reader.read_u8()?;
+        self.ly_compare = reader.read_u8()?;
+        self.background_palette = PaletteData::init(reader.read_u8()?);
+        self.obj_palette_0 = PaletteData::init(reader.read_u8()?);
+        self.obj_palette_1 = PaletteData::init(reader.read_u8()?);
+        self.window_pos_y = reader.read_u8()?;
+        self.window_pos_x = reader.read_u8()?;
+        self.window_line = reader.read_u8()?;
+        reader.read_bytes(&mut self.vram)?;
+        reader.read_bytes(&mut self.oam)?;
+        self.vram_bank = reader.read_u8()? & 0x01;
+        reader.read_bytes(&mut self.background_palette_ram)?;
+        reader.read_bytes(&mut self.obj_palette_ram)?;
+        self.background_palette_spec = reader.read_u8()?;
+        self.obj_palette_spec = reader.read_u8()?;
+        self.obj_priority_mode = reader.read_u8()?;
+        for line in self.framebuffer.iter_mut() {
+            reader.read_bytes(line)?;
+            line.iter_mut().for_each(|shade| *shade &= 0x03);
+        }
+        for color in self.framebuffer_rgb555.iter_mut().flatten() {
+            *color = reader.read_u16()?;
+        }
+        for layer in self.framebuffer_layers.iter_mut().flatten() {
+            *layer = match reader.read_u8()? {
+                1 => PaletteLayer::Obj0,
+                2 => PaletteLayer::Obj1,
+                _ => PaletteLayer::Background,
+            };
+        }
+        self.frame_ready = reader.read_bool()?;
+        self.hblank_started = reader.read_bool()?;
+        Ok(())
+    }
+}
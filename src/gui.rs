@@ -1,27 +1,105 @@
 use imgui::Ui;
 
-use crate::cpu;
+use crate::apu;
+use crate::code_data_log;
+use crate::color_correction;
+use crate::instruction;
+use crate::debugger;
+use crate::debugger::{Breakpoint, Watchpoint};
 use crate::memory::Memory;
+use crate::palette;
+use crate::palette::{ShadeColors, ShadePalette};
+use crate::save_state;
 
-use super::main_board::MainBoard;
+use super::main_board::{MainBoard, SPEEDS, SPEED_NAMES};
+use super::memory_management_unit::MemoryManagementUnit;
 use super::execution_modes::ExecutionMode;
 
 pub struct Gui {
     pub lcd_scale: u8,
+    pub lcd_fit_to_window: bool,
+    // the texture main uploads the gpu framebuffer to
+    pub lcd_texture_id: Option<imgui::TextureId>,
     pub execution_mode: ExecutionMode,
     pub disassembly_start_address: u16,
     pub disassembly_end_address: u16,
     pub disassembly_lines_to_print: u16,
+    // the selected disassembly line, for run to cursor
+    pub disassembly_cursor: Option<u16>,
+    pub user_palettes: Vec<ShadePalette>,
+    pub palette_editor_layer: usize,
+    pub palette_preset_index: usize,
+    pub new_palette_name: String,
+    pub save_state_slot: usize,
+    // result of the last save or load, shown under the buttons
+    pub save_state_message: String,
+    // true while the rewind button is held down
+    pub rewind_held: bool,
+    pub breakpoint_input: String,
+    pub breakpoint_condition_input: String,
+    pub breakpoint_log_input: String,
+    // why the last breakpoint couldn't be added
+    pub breakpoint_error: String,
+    pub watchpoint_input: String,
+    pub watchpoint_access: usize,
+    pub io_register_index: usize,
+    pub memory_goto_input: String,
+    // the memory view scrolls to this address on the next frame
+    pub memory_scroll_to: Option<u16>,
+    // the bank shown for each of BANKED_REGIONS, None for the one mapped in
+    pub memory_banks: [Option<usize>; 4],
+    // the byte being edited and its new value
+    pub memory_edit: Option<(u16, String)>,
+    memory_edit_focus: bool,
+    // what the memory view showed last frame, and for how many more frames each byte shows as changed
+    memory_previous: Vec<u8>,
+    memory_changed: Vec<u8>,
 }
 
+// the memory view's jump buttons
+const MEMORY_REGIONS: [(&str, u16); 8] = [
+    ("ROM0", 0x0000), ("ROMX", 0x4000), ("VRAM", 0x8000), ("SRAM", 0xA000), ("WRAM", 0xC000), ("OAM", 0xFE00),
+    ("IO", 0xFF00), ("HRAM", 0xFF80),
+];
+// the regions the memory view can show another bank of
+const BANKED_REGIONS: [(&str, u16, u16); 4] = [
+    ("ROMX", 0x4000, 0x7FFF), ("VRAM", 0x8000, 0x9FFF), ("SRAM", 0xA000, 0xBFFF), ("WRAMX", 0xD000, 0xDFFF),
+];
+// about a second at 60 frames per second
+const MEMORY_CHANGED_FRAMES: u8 = 60;
+
 impl Default for Gui {
     fn default() -> Self {
         Gui {
-            lcd_scale: 1,
+            lcd_scale: 2,
+            lcd_fit_to_window: false,
+            lcd_texture_id: None,
             execution_mode: ExecutionMode::Stopped,
             disassembly_start_address: 0x100,
             disassembly_end_address: 0x100 + 16,
             disassembly_lines_to_print: 16,
+            disassembly_cursor: None,
+            user_palettes: Vec::new(),
+            palette_editor_layer: 0,
+            palette_preset_index: 0,
+            new_palette_name: String::new(),
+            save_state_slot: 0,
+            save_state_message: String::new(),
+            rewind_held: false,
+            breakpoint_input: String::new(),
+            breakpoint_condition_input: String::new(),
+            breakpoint_log_input: String::new(),
+            breakpoint_error: String::new(),
+            watchpoint_input: String::new(),
+            watchpoint_access: 1,
+            io_register_index: 0,
+            memory_goto_input: String::new(),
+            memory_scroll_to: None,
+            memory_banks: [None; 4],
+            memory_edit: None,
+            memory_edit_focus: false,
+            memory_previous: Vec::new(),
+            memory_changed: vec![0; 0x10000],
         }
     }
 }
@@ -32,13 +110,15 @@ impl Gui {
         self.execution_mode = match self.execution_mode {
             ExecutionMode::CpuOperation => ExecutionMode::Stopped,
             ExecutionMode::Frame => ExecutionMode::Stopped,
+            ExecutionMode::Running | ExecutionMode::StepOver | ExecutionMode::StepOut | ExecutionMode::RunToCursor
+                if main_board.break_reason.is_some() => ExecutionMode::Stopped,
             _ => self.execution_mode,
         };
         ui.window("Rustyboy")
             .size([0.0, 0.0], imgui::Condition::FirstUseEver)
             .build(|| {
                 ui.child_window("Cartridge and Audio")
-                    .size([200.0, 400.0])
+                    .size([260.0, 700.0])
                     .build(|| {
                         ui.child_window("Cartridge")
                             .size([0.0, 100.0])
@@ -50,62 +130,123 @@ impl Gui {
                         });
                     ui.separator();
                     ui.child_window("Controls")
-                        .size([200.0, 100.0])
+                        .size([200.0, 280.0])
                         .build(|| {
                             if ui.button("go")
                             {
                                 self.execution_mode = ExecutionMode::Running;
+                                main_board.break_reason = None;
+                                main_board.step_target = None;
                             }
                             ui.same_line();
                             if ui.button("stop")
                             {
                                 self.execution_mode = ExecutionMode::Stopped;
+                                main_board.step_target = None;
                             }
                             if ui.button("step")
                             {
                                 self.execution_mode = ExecutionMode::CpuOperation;
+                                main_board.break_reason = None;
+                                main_board.step_target = None;
+                            }
+                            ui.same_line();
+                            if ui.button("over")
+                            {
+                                self.execution_mode = if main_board.step_over() { ExecutionMode::StepOver } else { ExecutionMode::CpuOperation };
+                                main_board.break_reason = None;
+                            }
+                            ui.same_line();
+                            if ui.button("out") && main_board.step_out()
+                            {
+                                self.execution_mode = ExecutionMode::StepOut;
+                                main_board.break_reason = None;
+                            }
+                            if let Some(addr) = self.disassembly_cursor {
+                                if ui.button(format!("run to {:04X}", addr))
+                                {
+                                    main_board.run_to(addr);
+                                    self.execution_mode = ExecutionMode::RunToCursor;
+                                    main_board.break_reason = None;
+                                }
                             }
                             if ui.button("step frame")
                             {
                                 self.execution_mode = ExecutionMode::Frame;
+                                main_board.break_reason = None;
+                                main_board.step_target = None;
+                            }
+                            let mut speed_index = SPEEDS.iter().position(|&speed| speed == main_board.speed).unwrap_or(2);
+                            ui.set_next_item_width(100.0);
+                            if ui.combo_simple_string("speed", &mut speed_index, &SPEED_NAMES) {
+                                main_board.speed = SPEEDS[speed_index];
                             }
+                            if main_board.fast_forward {
+                                ui.same_line();
+                                ui.text("(fast forward)");
+                            }
+                            ui.separator();
+                            let slot_names: Vec<String> = (0 .. save_state::SLOTS).map(|slot| format!("slot {}", slot)).collect();
+                            ui.set_next_item_width(80.0);
+                            ui.combo_simple_string("##save state slot", &mut self.save_state_slot, &slot_names);
+                            ui.same_line();
+                            if ui.button("save") {
+                                self.save_state(main_board, self.save_state_slot);
+                            }
+                            ui.same_line();
+                            if ui.button("load") {
+                                self.load_state(main_board, self.save_state_slot);
+                            }
+                            ui.text_wrapped(&self.save_state_message);
+                            ui.separator();
+                            self.show_rewind(ui, main_board);
                     });
                     ui.separator();
                     ui.child_window("APU")
                         .size([0.0, 0.0])
                         .build(|| {
-                            ui.button("mute");
-                            ui.same_line();
-                            ui.button("unmute");
-                            ui.text("Enabled: no");
-                            ui.separator();
-                            ui.text("wave ram:");
-                            ui.separator();
-                            ui.text("channel 1 (off): ");
-                            ui.text("channel 2 (off): ");
-                            ui.text("channel 3 (off): ");
-                            ui.text("channel 4 (off): ");
+                            self.show_apu(ui, main_board);
                     });
                 });
         ui.same_line();
         ui.child_window("LCD and memory")
-            .size([500.00, 100.0])
+            .size([680.00, 700.0])
             .build(|| {
                 ui.child_window("LCD")
+                    .size([0.0, 640.0])
                     .build(|| {
                         ui.text("LCD");
                         ui.slider("scale", 1, 4, &mut self.lcd_scale);
-                        // let lcd_image_size = imgui::ImVec2(160 * state.lcd_scale, 144 * state.lcd_scale);
-                        // let window_size = Ui::window_size(ui);
-                        // let draw_list = ui.get_window_draw_list();
-                        // TODO select button
+                        ui.same_line();
+                        ui.checkbox("fit to window", &mut self.lcd_fit_to_window);
+                        {
+                            let gpu = &mut main_board.mmu.borrow_mut().gpu;
+                            let mut color_correction = gpu.color_correction as usize;
+                            ui.set_next_item_width(120.0);
+                            if ui.combo_simple_string("color correction (CGB)", &mut color_correction,
+                                &color_correction::COLOR_CORRECTION_NAMES) {
+                                gpu.color_correction = color_correction::COLOR_CORRECTIONS[color_correction];
+                            }
+                        }
+                        let (width, height) = main_board.screen_size();
+                        let scale = if self.lcd_fit_to_window {
+                            // largest integer scale that fits, so pixels stay square
+                            let available = ui.content_region_avail();
+                            let fit = (available[0] / width as f32).min(available[1] / height as f32);
+                            fit.floor().max(1.0)
+                        } else {
+                            self.lcd_scale as f32
+                        };
+                        if let Some(texture_id) = self.lcd_texture_id {
+                            imgui::Image::new(texture_id, [width as f32 * scale, height as f32 * scale])
+                                .build(ui);
+                        }
                     });
 
                 ui.child_window("Memory")
                     .build(|| {
                         ui.separator();
-                        ui.text("Memory table tbd");
-                        // TODO select button
+                        self.show_memory(ui, main_board);
                     });
 
             });
@@ -129,7 +270,26 @@ impl Gui {
                 ui.child_window("Disassembly")
                     .size([200.0, 300.0])
                     .build(|| {
-                        ui.text(self.get_disassembly_text(&main_board));
+                        ui.text("Disassembly");
+                        for (addr, line) in self.get_disassembly_lines(main_board) {
+                            if ui.selectable_config(&line).selected(self.disassembly_cursor == Some(addr)).build() {
+                                self.disassembly_cursor = Some(addr);
+                            }
+                        }
+                    });
+                ui.child_window("Breakpoints")
+                    .size([300.0, 300.0])
+                    .build(|| {
+                        self.show_breakpoints(ui, main_board);
+                    });
+                ui.child_window("Call stack")
+                    .size([300.0, 150.0])
+                    .build(|| {
+                        ui.text("Call stack");
+                        for frame in main_board.cpu.call_stack.iter().rev() {
+                            let name = main_board.symbol(frame.target).map(str::to_string).unwrap_or_else(|| debugger::call_name(frame));
+                            ui.text(format!("{}, returns to {:04X}", name, frame.return_addr));
+                        }
                     });
                 ui.child_window("Interrupts")
                     .size([200.0, 200.0])
@@ -155,56 +315,439 @@ impl Gui {
                     ui.text("    tileset: on");
                     ui.text("    tilemap: on");
                     ui.text("    scroll: (42, 24)");
-                })
+                });
+            ui.child_window("Palettes")
+                .size([300.0, 260.0])
+                .build(|| {
+                    self.show_palettes(ui, main_board);
+                });
         });
         return self.execution_mode
     }
 
+    pub fn save_state(&mut self, main_board: &MainBoard, slot: usize) {
+        self.save_state_message = match main_board.save_state_to_slot(slot) {
+            Ok(()) => format!("saved slot {}", slot),
+            Err(error) => format!("saving slot {} failed: {}", slot, error),
+        };
+    }
+
+    pub fn load_state(&mut self, main_board: &mut MainBoard, slot: usize) {
+        self.save_state_message = match main_board.load_state_from_slot(slot) {
+            Ok(()) => format!("loaded slot {}", slot),
+            Err(error) => format!("loading slot {} failed: {}", slot, error),
+        };
+    }
+
+    fn show_rewind(&mut self, ui: &Ui, main_board: &mut MainBoard) {
+        ui.button("rewind (R)");
+        self.rewind_held = ui.is_item_active();
+        ui.same_line();
+        let rewind = &mut main_board.rewind;
+        if ui.checkbox("record", &mut rewind.enabled) && !rewind.enabled {
+            rewind.clear();
+        }
+        ui.set_next_item_width(100.0);
+        ui.slider("every n frames", 1, 60, &mut rewind.interval_frames);
+        ui.text(format!("{} snapshots, {:.1}/{} MiB", rewind.snapshot_count(),
+            rewind.memory_used() as f32 / (1024.0 * 1024.0), rewind.memory_budget / (1024 * 1024)));
+    }
+
+    fn show_breakpoints(&mut self, ui: &Ui, main_board: &mut MainBoard) {
+        if let Some(reason) = main_board.break_reason {
+            ui.text_colored([1.0, 0.4, 0.4, 1.0], format!("stopped: {}", reason.describe()));
+        }
+        ui.set_next_item_width(120.0);
+        ui.input_text("##breakpoint", &mut self.breakpoint_input).hint("AAAA, BB:AAAA or label").build();
+        ui.same_line();
+        if ui.button("add breakpoint") {
+            match self.new_breakpoint(main_board) {
+                Ok(breakpoint) => {
+                    main_board.breakpoints.push(breakpoint);
+                    self.breakpoint_input.clear();
+                    self.breakpoint_condition_input.clear();
+                    self.breakpoint_log_input.clear();
+                    self.breakpoint_error.clear();
+                }
+                Err(error) => self.breakpoint_error = error,
+            }
+        }
+        ui.set_next_item_width(200.0);
+        ui.input_text("condition", &mut self.breakpoint_condition_input).hint("e.g. A == $3C && HITS > 10").build();
+        ui.set_next_item_width(200.0);
+        ui.input_text("log", &mut self.breakpoint_log_input).hint("log without stopping, e.g. HL={HL}").build();
+        if !self.breakpoint_error.is_empty() {
+            ui.text_colored([1.0, 0.4, 0.4, 1.0], &self.breakpoint_error);
+        }
+        let mut deleted = None;
+        for (i, breakpoint) in main_board.breakpoints.iter_mut().enumerate() {
+            let label = main_board.symbols.name(breakpoint.bank, breakpoint.addr).unwrap_or_default();
+            ui.checkbox(format!("{} {}##breakpoint{}", breakpoint.name(), label, i), &mut breakpoint.enabled);
+            ui.same_line();
+            ui.text(format!("hits {}", breakpoint.hits));
+            ui.same_line();
+            if ui.small_button(format!("x##breakpoint{}", i)) {
+                deleted = Some(i);
+            }
+            if let Some((text, _)) = &breakpoint.condition {
+                ui.text(format!("    if {}", text));
+            }
+            if let Some((text, _)) = &breakpoint.log_message {
+                ui.text(format!("    log \"{}\"", text));
+            }
+        }
+        if let Some(i) = deleted {
+            main_board.breakpoints.remove(i);
+        }
+        if !main_board.log.is_empty() {
+            ui.text("log points");
+            ui.same_line();
+            if ui.small_button("clear##log") {
+                main_board.log.clear();
+            }
+            ui.child_window("Log").size([0.0, 100.0]).border(true).build(|| {
+                for line in &main_board.log {
+                    ui.text(line);
+                }
+                if ui.scroll_y() >= ui.scroll_max_y() {
+                    ui.set_scroll_here_y_with_ratio(1.0);
+                }
+            });
+        }
+        ui.separator();
+
+        let mut mmu = main_board.mmu.borrow_mut();
+        let watchpoints = &mut mmu.watchpoints.list;
+        ui.set_next_item_width(120.0);
+        ui.input_text("##watchpoint", &mut self.watchpoint_input).hint("AAAA or AAAA-BBBB").build();
+        ui.same_line();
+        ui.set_next_item_width(90.0);
+        ui.combo_simple_string("##watchpoint access", &mut self.watchpoint_access, &debugger::ACCESS_NAMES);
+        let access = debugger::ACCESSES[self.watchpoint_access];
+        if ui.button("add watchpoint") {
+            if let Some(watchpoint) = Watchpoint::parse(&self.watchpoint_input, access) {
+                watchpoints.push(watchpoint);
+                self.watchpoint_input.clear();
+            }
+        }
+        let register_names: Vec<&str> = debugger::IO_REGISTERS.iter().map(|&(name, _)| name).collect();
+        ui.set_next_item_width(120.0);
+        ui.combo_simple_string("##io register", &mut self.io_register_index, &register_names);
+        ui.same_line();
+        if ui.button("watch register") {
+            let addr = debugger::IO_REGISTERS[self.io_register_index].1;
+            watchpoints.push(Watchpoint { first: addr, last: addr, access, enabled: true });
+        }
+        let mut deleted = None;
+        for (i, watchpoint) in watchpoints.iter_mut().enumerate() {
+            ui.checkbox(format!("{}##watchpoint{}", watchpoint.name(), i), &mut watchpoint.enabled);
+            ui.same_line();
+            if ui.small_button(format!("x##watchpoint{}", i)) {
+                deleted = Some(i);
+            }
+        }
+        if let Some(i) = deleted {
+            watchpoints.remove(i);
+        }
+    }
+
+    fn new_breakpoint(&self, main_board: &MainBoard) -> Result<Breakpoint, String> {
+        let mut breakpoint = Breakpoint::parse(&self.breakpoint_input, &main_board.symbols)
+            .ok_or_else(|| format!("bad address {}", self.breakpoint_input.trim()))?;
+        breakpoint.set_condition(&self.breakpoint_condition_input).map_err(|error| format!("condition: {}", error))?;
+        breakpoint.set_log_message(&self.breakpoint_log_input).map_err(|error| format!("log: {}", error))?;
+        Ok(breakpoint)
+    }
+
+    fn show_apu(&mut self, ui: &Ui, main_board: &mut MainBoard) {
+        let mut mmu = main_board.mmu.borrow_mut();
+        let apu = &mut mmu.apu;
+        if ui.button(if apu.master_muted { "unmute" } else { "mute" }) {
+            apu.master_muted = !apu.master_muted;
+        }
+        ui.same_line();
+        ui.text(format!("Enabled: {}", if apu.powered { "yes" } else { "no" }));
+        ui.text(format!("volume L{} R{}  panning {:08b}",
+            (apu.master_volume >> 4) & 0x07, apu.master_volume & 0x07, apu.panning));
+        ui.separator();
+        ui.text("wave ram:");
+        ui.plot_lines("##wave_ram", &apu.wave_samples())
+            .graph_size([0.0, 40.0])
+            .scale_min(0.0)
+            .scale_max(15.0)
+            .build();
+        ui.separator();
+        for channel in 0 .. 4 {
+            let on_off = if apu.channel_enabled(channel) { "on" } else { "off" };
+            ui.text(format!("channel {} ({}): ", channel + 1, on_off));
+            ui.same_line();
+            ui.checkbox(format!("mute##channel{}", channel), &mut apu.channel_muted[channel]);
+            match channel {
+                0 | 1 => {
+                    let square = if channel == 0 { &apu.channel1 } else { &apu.channel2 };
+                    let hz = square.frequency_hz();
+                    ui.text(format!("  {:.1}Hz {} duty {}", hz, apu::note_name(hz), apu::DUTY_PERCENTAGES[square.duty as usize]));
+                    ui.text(format!("  vol {:2} len {:2}{}", square.envelope.volume, square.length.counter,
+                        if square.length.enabled { "" } else { " (off)" }));
+                    if let Some(sweep) = &square.sweep {
+                        ui.text(format!("  sweep period {} {}{}", sweep.period, if sweep.negate { '-' } else { '+' }, sweep.shift));
+                    }
+                }
+                2 => {
+                    let wave = &apu.channel3;
+                    let hz = wave.frequency_hz();
+                    ui.text(format!("  {:.1}Hz {} level {}", hz, apu::note_name(hz),
+                        ["mute", "100%", "50%", "25%"][wave.output_level as usize]));
+                    ui.text(format!("  len {:3}{}", wave.length.counter, if wave.length.enabled { "" } else { " (off)" }));
+                }
+                _ => {
+                    let noise = &apu.channel4;
+                    ui.text(format!("  {:.0}Hz {}-bit lfsr", noise.frequency_hz(), if noise.short_mode { 7 } else { 15 }));
+                    ui.text(format!("  vol {:2} len {:2}{}", noise.envelope.volume, noise.length.counter,
+                        if noise.length.enabled { "" } else { " (off)" }));
+                }
+            }
+            ui.plot_lines(format!("##oscilloscope{}", channel), &apu.oscilloscope(channel))
+                .graph_size([0.0, 30.0])
+                .scale_min(-1.0)
+                .scale_max(1.0)
+                .build();
+        }
+    }
+
+    fn show_palettes(&mut self, ui: &Ui, main_board: &mut MainBoard) {
+        let mut presets = palette::builtin_palettes();
+        presets.extend(self.user_palettes.iter().cloned());
+        let preset_names: Vec<&str> = presets.iter().map(|preset| preset.name.as_str()).collect();
+        self.palette_preset_index = self.palette_preset_index.min(presets.len() - 1);
+
+        let mut mmu = main_board.mmu.borrow_mut();
+        let shade_colors = &mut mmu.gpu.shade_colors;
+        ui.combo_simple_string("preset", &mut self.palette_preset_index, &preset_names);
+        if ui.button("apply to all") {
+            *shade_colors = ShadeColors::init(presets[self.palette_preset_index].shades);
+        }
+        ui.same_line();
+        if ui.button(format!("apply to {}", palette::PALETTE_LAYER_NAMES[self.palette_editor_layer])) {
+            shade_colors.layers[self.palette_editor_layer] = presets[self.palette_preset_index].shades;
+        }
+        ui.separator();
+        ui.combo_simple_string("layer", &mut self.palette_editor_layer, &palette::PALETTE_LAYER_NAMES);
+        let layer = self.palette_editor_layer;
+        for shade in 0 .. 4 {
+            let rgb = &mut shade_colors.layers[layer][shade];
+            let mut color = rgb.map(|channel| channel as f32 / 255.0);
+            if ui.color_edit3(format!("shade {}", shade), &mut color) {
+                *rgb = color.map(|channel| (channel * 255.0).round() as u8);
+            }
+        }
+        ui.input_text("##new_palette_name", &mut self.new_palette_name).build();
+        ui.same_line();
+        if ui.button("save palette") && !self.new_palette_name.is_empty() {
+            let name = self.new_palette_name.clone();
+            self.user_palettes.retain(|user_palette| user_palette.name != name);
+            self.user_palettes.push(ShadePalette { name, shades: shade_colors.layers[layer] });
+            if let Err(e) = palette::save_user_palettes(palette::PALETTE_CONFIG_FILE, &self.user_palettes) {
+                println!("Failed to save palettes to {}: {}", palette::PALETTE_CONFIG_FILE, e);
+            }
+        }
+    }
+
+    // Hex and ASCII view of the whole address space, click a byte to edit it. Reads use peek8 so
+    // watchpoints and the code data log don't see the view, edits go through write8 like a cpu write
+    fn show_memory(&mut self, ui: &Ui, main_board: &mut MainBoard) {
+        for (i, &(name, addr)) in MEMORY_REGIONS.iter().enumerate() {
+            if i > 0 {
+                ui.same_line();
+            }
+            if ui.small_button(name) {
+                self.memory_scroll_to = Some(addr);
+            }
+        }
+        ui.set_next_item_width(120.0);
+        let goto = ui.input_text("##memory goto", &mut self.memory_goto_input).hint("AAAA, BB:AAAA or label")
+            .enter_returns_true(true).build();
+        ui.same_line();
+        if ui.button("goto") || goto {
+            // takes the same addresses as breakpoints, a bank picks what the region shows
+            if let Some(location) = Breakpoint::parse(&self.memory_goto_input, &main_board.symbols) {
+                // a bank the region doesn't have is left out, the view stays on the bank it shows
+                let banks = main_board.mmu.borrow().banks(location.addr);
+                if let (Some(bank), Some(region), Some((banks, _))) = (location.bank, banked_region(location.addr), banks) {
+                    if banks.contains(&bank) && self.memory_banks[region] != Some(bank) {
+                        self.memory_banks[region] = Some(bank);
+                        self.memory_previous.clear();
+                    }
+                }
+                self.memory_scroll_to = Some(location.addr);
+            }
+        }
+        let mut edited = None;
+        {
+            let mmu = main_board.mmu.borrow();
+            for (region, &(name, first, _)) in BANKED_REGIONS.iter().enumerate() {
+                let Some((banks, mapped)) = mmu.banks(first) else {
+                    self.memory_banks[region] = None;
+                    continue;
+                };
+                let mut items = vec![format!("mapped ({})", mapped)];
+                items.extend(banks.clone().map(|bank| bank.to_string()));
+                // a bank out of range, as after loading a smaller rom, goes back to the mapped one
+                let index = self.memory_banks[region].and_then(|bank| bank.checked_sub(*banks.start()))
+                    .filter(|&index| index < items.len() - 1);
+                if index.is_none() {
+                    self.memory_banks[region] = None;
+                }
+                let mut selected = index.map_or(0, |index| index + 1);
+                ui.set_next_item_width(80.0);
+                if ui.combo_simple_string(format!("{} bank", name), &mut selected, &items) {
+                    self.memory_banks[region] = (selected > 0).then(|| banks.start() + selected - 1);
+                    // the bytes of the other bank aren't changes
+                    self.memory_previous.clear();
+                }
+                ui.same_line();
+            }
+            ui.new_line();
+
+            let values: Vec<u8> = (0 ..= 0xFFFF).map(|addr| self.memory_value(&mmu, addr)).collect();
+            for (addr, &value) in values.iter().enumerate() {
+                if self.memory_previous.get(addr).is_some_and(|&previous| previous != value) {
+                    self.memory_changed[addr] = MEMORY_CHANGED_FRAMES;
+                } else {
+                    self.memory_changed[addr] = self.memory_changed[addr].saturating_sub(1);
+                }
+            }
+            self.memory_previous = values;
+
+            ui.child_window("Memory rows").build(|| {
+                let row_height = ui.text_line_height_with_spacing();
+                if let Some(addr) = self.memory_scroll_to.take() {
+                    ui.set_scroll_y((addr / 16) as f32 * row_height);
+                }
+                let cell_width = ui.calc_text_size("00")[0];
+                let clipper = imgui::ListClipper::new(0x1000).items_height(row_height).begin(ui);
+                for row in clipper.iter() {
+                    let row_addr = row as u16 * 16;
+                    ui.text(format!("{:04X}", row_addr));
+                    for addr in row_addr ..= row_addr + 15 {
+                        ui.same_line();
+                        if let Some(value) = self.show_memory_byte(ui, addr, cell_width) {
+                            edited = Some((addr, value));
+                        }
+                    }
+                    ui.same_line();
+                    let ascii: String = (row_addr ..= row_addr + 15).map(|addr| match self.memory_previous[addr as usize] {
+                        byte @ 0x20 ..= 0x7E => byte as char,
+                        _ => '.',
+                    }).collect();
+                    ui.text(ascii);
+                }
+            });
+        }
+        if let Some((addr, value)) = edited {
+            let mut mmu = main_board.mmu.borrow_mut();
+            match banked_region(addr).and_then(|region| self.memory_banks[region]) {
+                Some(bank) => mmu.poke_bank(addr, bank, value),
+                None => mmu.write8(addr, value),
+            }
+            // editing isn't the game writing, it doesn't stop at watchpoints
+            mmu.watchpoints.take_hit();
+        }
+    }
+
+    // one byte of the memory view, the new value once an edit is entered
+    fn show_memory_byte(&mut self, ui: &Ui, addr: u16, cell_width: f32) -> Option<u8> {
+        if let Some((edit_addr, input)) = &mut self.memory_edit {
+            if *edit_addr == addr {
+                ui.set_next_item_width(cell_width + 4.0);
+                if std::mem::take(&mut self.memory_edit_focus) {
+                    ui.set_keyboard_focus_here();
+                }
+                let entered = ui.input_text("##memory edit", input).chars_hexadecimal(true).auto_select_all(true)
+                    .enter_returns_true(true).build();
+                let value = u8::from_str_radix(input.trim(), 16).ok();
+                if entered {
+                    // carries on with the next byte, like typing over it
+                    let next = addr.wrapping_add(1);
+                    self.memory_edit = Some((next, format!("{:02X}", self.memory_previous[next as usize])));
+                    self.memory_edit_focus = true;
+                    return value;
+                }
+                if ui.is_item_deactivated() {
+                    self.memory_edit = None;
+                }
+                return None;
+            }
+        }
+        let value = self.memory_previous[addr as usize];
+        let color = if self.memory_changed[addr as usize] > 0 { [1.0, 0.4, 0.4, 1.0] } else { ui.style_color(imgui::StyleColor::Text) };
+        let _color = ui.push_style_color(imgui::StyleColor::Text, color);
+        if ui.selectable_config(format!("{:02X}##memory{:04X}", value, addr)).size([cell_width, 0.0]).build() {
+            self.memory_edit = Some((addr, format!("{:02X}", value)));
+            self.memory_edit_focus = true;
+        }
+        None
+    }
+
+    // the byte the memory view shows at addr, from the selected bank where there is one
+    fn memory_value(&self, mmu: &MemoryManagementUnit, addr: u16) -> u8 {
+        match banked_region(addr).and_then(|region| self.memory_banks[region]) {
+            Some(bank) => mmu.peek_bank(addr, bank),
+            None => mmu.peek8(addr),
+        }
+    }
+
     fn set_disassembly_window_pc(&mut self, main_board: &MainBoard, current_pc: u16) {
-        if current_pc < self.disassembly_start_address || current_pc > self.disassembly_end_address {
+        if current_pc < self.disassembly_start_address || current_pc >= self.disassembly_end_address {
             self.disassembly_start_address = current_pc;
             self.disassembly_end_address = get_last_address_in_disassembly_text(main_board, current_pc, self.disassembly_lines_to_print);
         }
     }
 
-    fn get_disassembly_text(&mut self, main_board: &MainBoard) -> String {
-        let current_pc = main_board.cpu.pc.wrapping_sub(1);
-        self.set_disassembly_window_pc(main_board, current_pc);
-        let mut result = "Disassembly\n".to_string();
-        let mut i = 0;
-        while i < self.disassembly_lines_to_print {
-            let current_address = self.disassembly_start_address.wrapping_add(1 + i);
-            result.push(if current_address == main_board.cpu.pc { '>' } else { ' ' });
+    // the address and text of each line
+    fn get_disassembly_lines(&mut self, main_board: &MainBoard) -> Vec<(u16, String)> {
+        self.set_disassembly_window_pc(main_board, main_board.cpu.pc);
+        let mut result = Vec::new();
+        let mut current_address = self.disassembly_start_address;
+        for _ in 0 .. self.disassembly_lines_to_print {
+            if let Some(label) = main_board.symbol(current_address) {
+                result.push((current_address, format!(" {}:", label)));
+            }
+            let marker = if current_address == main_board.cpu.pc { '>' } else { ' ' };
             let (operation, size) = get_disassembled_operation(main_board, current_address);
-            result.push_str(&operation);
-            i = i.wrapping_add(size.into());
+            result.push((current_address, format!("{}{}", marker, operation)));
+            current_address = current_address.wrapping_add(size);
         }
-        return result;
+        result
     }
 
  
 
 }
 
-fn get_disassembled_operation(main_board: &MainBoard, pc: u16) -> (String, u8) {
-    let opcode = main_board.mmu.borrow().read8(pc);
-    if opcode != 0xCB {
-        (format!("[{:04X}]   {:02X} | {}\n", pc, opcode, cpu::OP_MNEMONICS[opcode as usize]), cpu::OP_SIZES[opcode as usize])
-    } else {
-        let cb_opcode = main_board.mmu.borrow().read8(pc.wrapping_add(1));
-        (format!("[{:04X}] {:02X}{:02X} | {}\n", pc, opcode, cb_opcode, cpu::OP_CB_MNEMONICS[cb_opcode as usize]), cpu::OP_CB_SIZE)
-    }
+// the index into BANKED_REGIONS of the region addr is in
+fn banked_region(addr: u16) -> Option<usize> {
+    BANKED_REGIONS.iter().position(|&(_, first, last)| (first ..= last).contains(&addr))
 }
 
-fn get_last_address_in_disassembly_text(main_board: &MainBoard, starting_address: u16, addresses_to_print: u16) -> u16 {
-    let mut i = 0;
-    let mut current_address = starting_address;
-    while i < addresses_to_print {
-        let opcode = main_board.mmu.borrow().read8(starting_address.wrapping_add(i));
-        current_address = current_address + if opcode == 0xcb { 2 } else { 1 };
-        i = i + 1;
+fn get_disassembled_operation(main_board: &MainBoard, pc: u16) -> (String, u16) {
+    let mmu = main_board.mmu.borrow();
+    // bytes the code data log only saw read as data aren't decoded
+    if code_data_log::is_data(mmu.code_data_flags(pc)) {
+        let byte = mmu.peek8(pc);
+        return (format!("[{:04X}] {:6} | DB ${:02X}", pc, format!("{:02X}", byte), byte), 1);
     }
-    current_address
+    let decoded = instruction::decode(&*mmu, pc);
+    let bytes: String = (0 .. decoded.length).map(|i| format!("{:02X}", mmu.peek8(pc.wrapping_add(i)))).collect();
+    // addresses are shown as labels or io register names where there are any
+    let name = |addr: u16| main_board.symbol(addr).or_else(|| debugger::io_register_name(addr)).map(str::to_string);
+    (format!("[{:04X}] {:6} | {}", pc, bytes, decoded.format(pc, &name)), decoded.length)
 }
 
-        
\ No newline at end of file
+fn get_last_address_in_disassembly_text(main_board: &MainBoard, starting_address: u16, lines_to_print: u16) -> u16 {
+    let mmu = main_board.mmu.borrow();
+    (0 .. lines_to_print).fold(starting_address, |addr, _| {
+        let length = if code_data_log::is_data(mmu.code_data_flags(addr)) { 1 } else { instruction::decode(&*mmu, addr).length };
+        addr.wrapping_add(length)
+    })
+}
@@ -0,0 +1,106 @@
+use super::memory::Memory;
+use super::save_state::{SaveState, StateReader, StateWriter};
+
+// https://gbdev.io/pandocs/CGB_Registers.html#lcd-vram-dma-transfers
+pub const BLOCK_SIZE: u16 = 0x10;
+// the cpu is stalled for 8 machine cycles per block at normal speed, twice that in double speed
+pub const CYCLES_PER_BLOCK: u32 = 32;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum HdmaMode {
+    // copies everything at once, the cpu is halted until it's done
+    GeneralPurpose,
+    // copies one block at the start of each horizontal blank
+    HorizontalBlank,
+}
+
+pub struct Hdma {
+    pub source: u16,
+    pub destination: u16,
+    // blocks left to copy minus one, like HDMA5 reports it
+    pub remaining_blocks: u8,
+    pub mode: HdmaMode,
+    pub active: bool,
+}
+
+impl Hdma {
+    pub fn init() -> Self {
+        Self {
+            source: 0x0000,
+            destination: 0x8000,
+            remaining_blocks: 0x7F,
+            mode: HdmaMode::GeneralPurpose,
+            active: false,
+        }
+    }
+
+    // returns the source and destination of the next block, and moves the transfer along
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, self.destination);
+        self.source = self.source.wrapping_add(BLOCK_SIZE);
+        self.destination = self.destination.wrapping_add(BLOCK_SIZE);
+        if self.destination > 0x9FFF {
+            // the destination can't leave vram, the transfer stops there
+            self.destination = 0x8000;
+            self.remaining_blocks = 0x7F;
+            self.active = false;
+            return block;
+        }
+        self.remaining_blocks = self.remaining_blocks.wrapping_sub(1);
+        if self.remaining_blocks == 0xFF {
+            self.remaining_blocks = 0x7F;
+            self.active = false;
+        }
+        block
+    }
+}
+
+impl Memory for Hdma {
+    fn read8(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF51 ..= 0xFF54 => 0xFF, // write only
+            // bit 7 is clear while a transfer is running. 0xFF once it completes
+            0xFF55 => if self.active { self.remaining_blocks } else { 0x80 | self.remaining_blocks },
+            _ => panic!("unimplemented address read on Hdma {:#04x}", addr)
+        }
+    }
+
+    fn write8(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xFF51 => self.source = (self.source & 0x00FF) | (data as u16) << 8,
+            0xFF52 => self.source = (self.source & 0xFF00) | (data & 0xF0) as u16,
+            0xFF53 => self.destination = 0x8000 | (self.destination & 0x00FF) | ((data & 0x1F) as u16) << 8,
+            0xFF54 => self.destination = (self.destination & 0xFF00) | (data & 0xF0) as u16,
+            0xFF55 => {
+                if self.active && self.mode == HdmaMode::HorizontalBlank && data & 0x80 == 0 {
+                    // cancels the running transfer, HDMA5 keeps the remaining length
+                    self.active = false;
+                    return;
+                }
+                self.remaining_blocks = data & 0x7F;
+                self.mode = if data & 0x80 != 0 { HdmaMode::HorizontalBlank } else { HdmaMode::GeneralPurpose };
+                self.active = true;
+            }
+            _ => panic!("unimplemented address write on Hdma {:#04x}, value: {:#02x}", addr, data)
+        }
+    }
+}
+
+impl SaveState for Hdma {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.source);
+        writer.write_u16(self.destination);
+        writer.write_u8(self.remaining_blocks);
+        writer.write_bool(self.mode == HdmaMode::HorizontalBlank);
+        writer.write_bool(self.active);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.source = reader.read_u16()?;
+        self.destination = 0x8000 | (reader.read_u16()? & 0x1FF0);
+        self.remaining_blocks = reader.read_u8()? & 0x7F;
+        self.mode = if reader.read_bool()? { HdmaMode::HorizontalBlank } else { HdmaMode::GeneralPurpose };
+        self.active = reader.read_bool()?;
+        Ok(())
+    }
+}
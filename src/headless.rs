@@ -0,0 +1,212 @@
+// Runs a rom with no display until it reports a result, for test roms in CI
+use std::io::{BufReader, BufWriter};
+use super::color_correction::ColorCorrection;
+use super::main_board::{MainBoard, CPU_CLOCKS_PER_FRAME};
+use super::memory::Memory;
+use super::palette::{ShadeColors, GRAYSCALE_SHADES};
+
+// LD B,B, the software breakpoint the Mooneye test roms end on
+pub const LD_B_B: u8 = 0x40;
+// https://github.com/Gekkio/mooneye-test-suite#passfail-reporting
+// B, C, D, E, H and L once a Mooneye test passes, all 0x42 when it fails
+pub const MOONEYE_PASS_REGISTERS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+pub const MOONEYE_FAIL_REGISTERS: [u8; 6] = [0x42; 6];
+// the newer Blargg roms report in cartridge ram, valid once this follows the status byte at A000
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const BLARGG_RUNNING: u8 = 0x80;
+// the rom wants the reset button pressed to carry on. There's no reset here, so it runs until it times out
+const BLARGG_RESET_REQUESTED: u8 = 0x81;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    // none of the conditions were met within max_frames
+    TimedOut,
+}
+
+// Without any conditions the rom simply runs for max_frames and passes
+pub struct RunOptions {
+    pub max_frames: u32,
+    // the serial output contains this
+    pub serial_pass: Option<String>,
+    pub serial_fail: Option<String>,
+    // the Blargg status byte in cartridge ram, checked once per frame
+    pub blargg_memory: bool,
+    // stop when the cpu is about to execute this opcode
+    pub stop_opcode: Option<u8>,
+    // B, C, D, E, H and L expected once stop_opcode is reached, any other values fail
+    pub expected_registers: Option<[u8; 6]>,
+}
+
+impl RunOptions {
+    pub fn init(max_frames: u32) -> Self {
+        Self {
+            max_frames,
+            serial_pass: None,
+            serial_fail: None,
+            blargg_memory: false,
+            stop_opcode: None,
+            expected_registers: None,
+        }
+    }
+
+    // https://github.com/retrio/gb-test-roms, results are printed over the link cable or
+    // left in cartridge ram, depending on the rom
+    pub fn blargg(max_frames: u32) -> Self {
+        Self {
+            serial_pass: Some("Passed".to_string()),
+            serial_fail: Some("Failed".to_string()),
+            blargg_memory: true,
+            ..Self::init(max_frames)
+        }
+    }
+
+    pub fn mooneye(max_frames: u32) -> Self {
+        Self {
+            stop_opcode: Some(LD_B_B),
+            expected_registers: Some(MOONEYE_PASS_REGISTERS),
+            ..Self::init(max_frames)
+        }
+    }
+
+    fn has_conditions(&self) -> bool {
+        self.serial_pass.is_some() || self.serial_fail.is_some() || self.blargg_memory || self.stop_opcode.is_some()
+    }
+}
+
+pub struct RunResult {
+    pub outcome: Outcome,
+    pub frames: u32,
+    pub serial_output: String,
+    // B, C, D, E, H and L at the end of the run
+    pub registers: [u8; 6],
+}
+
+pub fn run(main_board: &mut MainBoard, options: &RunOptions) -> RunResult {
+    let cycle_limit = options.max_frames as u64 * CPU_CLOCKS_PER_FRAME as u64;
+    let mut cycles: u64 = 0;
+    let mut serial_checked = 0;
+    let mut blargg_running = false;
+    let outcome = loop {
+        if cycles >= cycle_limit {
+            break if options.has_conditions() { Outcome::TimedOut } else { Outcome::Passed };
+        }
+        if let Some(opcode) = options.stop_opcode {
+            let cpu = &main_board.cpu;
+            if !cpu.halted && !cpu.stopped && main_board.mmu.borrow().peek8(cpu.pc) == opcode {
+                break match options.expected_registers {
+                    Some(expected) if registers(main_board) != expected => Outcome::Failed,
+                    _ => Outcome::Passed,
+                };
+            }
+        }
+        let frame = cycles / CPU_CLOCKS_PER_FRAME as u64;
+        cycles += main_board.emulate_cpu_operation() as u64;
+        if options.blargg_memory && cycles / CPU_CLOCKS_PER_FRAME as u64 != frame {
+            match blargg_status(main_board) {
+                Some(BLARGG_RUNNING | BLARGG_RESET_REQUESTED) => blargg_running = true,
+                // the status byte starts out as whatever was in ram, only trust it after a run
+                Some(0x00) if blargg_running => break Outcome::Passed,
+                Some(_) if blargg_running => break Outcome::Failed,
+                _ => {}
+            }
+        }
+        let mmu = main_board.mmu.borrow();
+        let serial_output = &mmu.serial_cable.output;
+        if serial_output.len() != serial_checked {
+            serial_checked = serial_output.len();
+            let text = mmu.serial_cable.output_string();
+            if options.serial_fail.as_ref().is_some_and(|fail| text.contains(fail.as_str())) {
+                break Outcome::Failed;
+            }
+            if options.serial_pass.as_ref().is_some_and(|pass| text.contains(pass.as_str())) {
+                break Outcome::Passed;
+            }
+        }
+    };
+    RunResult {
+        outcome,
+        frames: (cycles / CPU_CLOCKS_PER_FRAME as u64) as u32,
+        serial_output: main_board.mmu.borrow().serial_cable.output_string(),
+        registers: registers(main_board),
+    }
+}
+
+fn blargg_status(main_board: &MainBoard) -> Option<u8> {
+    let mmu = main_board.mmu.borrow();
+    let signature = [mmu.peek8(0xA001), mmu.peek8(0xA002), mmu.peek8(0xA003)];
+    if signature == BLARGG_SIGNATURE { Some(mmu.peek8(0xA000)) } else { None }
+}
+
+// the text a Blargg rom leaves in cartridge ram after its status byte
+pub fn blargg_memory_text(main_board: &MainBoard) -> String {
+    if blargg_status(main_board).is_none() {
+        return String::new();
+    }
+    let mmu = main_board.mmu.borrow();
+    let text: Vec<u8> = (0xA004 ..= 0xBFFF).map(|addr| mmu.peek8(addr)).take_while(|&byte| byte != 0x00).collect();
+    String::from_utf8_lossy(&text).to_string()
+}
+
+fn registers(main_board: &MainBoard) -> [u8; 6] {
+    let cpu = &main_board.cpu;
+    [cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l]
+}
+
+// RGBA8 pixels, as returned by MainBoard::screen_rgba
+pub fn write_png(path: &str, (width, height): (usize, usize), pixels: &[u8]) -> std::io::Result<()> {
+    let file = std::fs::File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let to_io_error = |e: png::EncodingError| std::io::Error::other(e);
+    let mut writer = encoder.write_header().map_err(to_io_error)?;
+    writer.write_image_data(pixels).map_err(to_io_error)
+}
+
+// RGBA8 pixels and the size of any 8 bit png
+pub fn read_png(path: &str) -> std::io::Result<((usize, usize), Vec<u8>)> {
+    let file = std::fs::File::open(path)?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    // palettes and less than 8 bits per channel come out as 8 bit rgb or grey
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let to_io_error = |e: png::DecodingError| std::io::Error::other(e);
+    let mut reader = decoder.read_info().map_err(to_io_error)?;
+    let mut buffer = vec![0x0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(to_io_error)?;
+    let bytes = &buffer[.. info.buffer_size()];
+    let pixels = match info.color_type {
+        png::ColorType::Rgba => bytes.to_vec(),
+        png::ColorType::Rgb => bytes.chunks(3).flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 0xFF]).collect(),
+        png::ColorType::GrayscaleAlpha => bytes.chunks(2).flat_map(|ga| [ga[0], ga[0], ga[0], ga[1]]).collect(),
+        png::ColorType::Grayscale => bytes.iter().flat_map(|&g| [g, g, g, 0xFF]).collect(),
+        png::ColorType::Indexed => return Err(std::io::Error::other(format!("{}: palette wasn't expanded", path))),
+    };
+    Ok(((info.width as usize, info.height as usize), pixels))
+}
+
+// the colours the dmg-acid2 and cgb-acid2 reference images use: plain greys and unmodified CGB colours
+pub fn use_reference_colors(main_board: &mut MainBoard) {
+    let mut mmu = main_board.mmu.borrow_mut();
+    mmu.gpu.shade_colors = ShadeColors::init(GRAYSCALE_SHADES);
+    mmu.gpu.color_correction = ColorCorrection::None;
+}
+
+// Compares two RGBA8 pictures of the same size. The diff image shows mismatched pixels in red
+// over a faded copy of the actual picture.
+pub fn diff_images(expected: &[u8], actual: &[u8]) -> (usize, Vec<u8>) {
+    let mut mismatched = 0;
+    let mut diff = Vec::with_capacity(actual.len());
+    for (expected, actual) in expected.chunks(4).zip(actual.chunks(4)) {
+        if expected[.. 3] == actual[.. 3] {
+            let grey = ((actual[0] as u32 + actual[1] as u32 + actual[2] as u32) / 3) as u8;
+            let faded = 0xC0 + grey / 4;
+            diff.extend_from_slice(&[faded, faded, faded, 0xFF]);
+        } else {
+            mismatched += 1;
+            diff.extend_from_slice(&[0xFF, 0x00, 0x00, 0xFF]);
+        }
+    }
+    (mismatched, diff)
+}
@@ -0,0 +1,508 @@
+use std::fmt;
+use super::memory::Memory;
+
+// https://gbdev.io/pandocs/CPU_Instruction_Set.html
+// https://www.pastraiser.com/cpu/gameboy/gameboy_opcodes.html
+
+// 8 bit operands in the order they're encoded in opcodes, (HL) is the byte HL points at
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum R8 {
+    B, C, D, E, H, L, HlIndirect, A,
+}
+
+const R8_ORDER: [R8; 8] = [R8::B, R8::C, R8::D, R8::E, R8::H, R8::L, R8::HlIndirect, R8::A];
+
+impl R8 {
+    // from the 3 bits starting at shift
+    fn from_bits(opcode: u8, shift: u8) -> R8 {
+        R8_ORDER[(opcode >> shift & 0x07) as usize]
+    }
+}
+
+// register pairs as encoded in bits 4-5, PUSH and POP have AF in place of SP
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum R16 {
+    BC, DE, HL, SP, AF,
+}
+
+impl R16 {
+    fn from_bits(opcode: u8) -> R16 {
+        [R16::BC, R16::DE, R16::HL, R16::SP][(opcode >> 4 & 0x03) as usize]
+    }
+
+    fn from_stack_bits(opcode: u8) -> R16 {
+        [R16::BC, R16::DE, R16::HL, R16::AF][(opcode >> 4 & 0x03) as usize]
+    }
+}
+
+// the conditions of jumps, calls and returns, encoded in bits 3-4
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Condition {
+    NZ, Z, NC, C,
+}
+
+impl Condition {
+    fn from_bits(opcode: u8) -> Condition {
+        [Condition::NZ, Condition::Z, Condition::NC, Condition::C][(opcode >> 3 & 0x03) as usize]
+    }
+}
+
+// where LD A,(x) and LD (x),A access memory
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Address {
+    BC,
+    DE,
+    // HL, incremented or decremented afterwards
+    HlIncrement,
+    HlDecrement,
+    Absolute(u16),
+    // 0xFF00 + C
+    HighC,
+    // 0xFF00 + n, the LDH instructions
+    High(u8),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AluOp {
+    Add, Adc, Sub, Sbc, And, Xor, Or, Cp,
+}
+
+const ALU_OPS: [AluOp; 8] = [AluOp::Add, AluOp::Adc, AluOp::Sub, AluOp::Sbc, AluOp::And, AluOp::Xor, AluOp::Or, AluOp::Cp];
+
+// the rotates and shifts of the CB prefixed instructions
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ShiftOp {
+    Rlc, Rrc, Rl, Rr, Sla, Sra, Swap, Srl,
+}
+
+const SHIFT_OPS: [ShiftOp; 8] = [ShiftOp::Rlc, ShiftOp::Rrc, ShiftOp::Rl, ShiftOp::Rr, ShiftOp::Sla, ShiftOp::Sra, ShiftOp::Swap, ShiftOp::Srl];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Instruction {
+    Nop,
+    Stop,
+    Halt,
+    Di,
+    Ei,
+    // LD r,r
+    Ld(R8, R8),
+    // LD r,n
+    LdImmediate(R8, u8),
+    // LD A,(x)
+    LoadA(Address),
+    // LD (x),A
+    StoreA(Address),
+    // LD rr,nn
+    Ld16(R16, u16),
+    // LD (nn),SP
+    StoreSp(u16),
+    // LD HL,SP+e
+    LdHlSpOffset(i8),
+    LdSpHl,
+    Push(R16),
+    Pop(R16),
+    Inc16(R16),
+    Dec16(R16),
+    AddHl(R16),
+    AddSp(i8),
+    Inc(R8),
+    Dec(R8),
+    Alu(AluOp, R8),
+    AluImmediate(AluOp, u8),
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
+    Rlca,
+    Rrca,
+    Rla,
+    Rra,
+    Jp(Option<Condition>, u16),
+    JpHl,
+    // the offset is from the address after the instruction
+    Jr(Option<Condition>, i8),
+    Call(Option<Condition>, u16),
+    Ret(Option<Condition>),
+    Reti,
+    Rst(u8),
+    Shift(ShiftOp, R8),
+    Bit(u8, R8),
+    Res(u8, R8),
+    Set(u8, R8),
+    // opcodes the cpu locks up on
+    Invalid(u8),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Decoded {
+    pub instruction: Instruction,
+    pub length: u16,
+    // cycles taken, counted at normal speed
+    pub cycles: u32,
+    // cycles taken when a conditional jump, call or return is taken
+    pub branch_cycles: u32,
+}
+
+// instruction length from the first byte
+pub fn length(opcode: u8) -> u16 {
+    match opcode {
+        0x01 | 0x11 | 0x21 | 0x31 | 0x08 | 0xC2 | 0xC3 | 0xCA | 0xD2 | 0xDA |
+        0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC | 0xEA | 0xFA => 3,
+        0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E |
+        0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xCB |
+        0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE |
+        0xE0 | 0xF0 | 0xE8 | 0xF8 => 2,
+        _ => 1,
+    }
+}
+
+// Decodes the instruction at addr, reading with peek8 so watchpoints don't see it.
+// The cpu reads the bytes itself and uses decode_bytes.
+pub fn decode(memory: &dyn Memory, addr: u16) -> Decoded {
+    decode_bytes([memory.peek8(addr), memory.peek8(addr.wrapping_add(1)), memory.peek8(addr.wrapping_add(2))])
+}
+
+// the opcode and the two bytes after it, which are only used as far as the instruction is long
+pub fn decode_bytes(bytes: [u8; 3]) -> Decoded {
+    let [opcode, n, _] = bytes;
+    let nn = u16::from_le_bytes([bytes[1], bytes[2]]);
+    let condition = Condition::from_bits(opcode);
+    let instruction = match opcode {
+        0x00 => Instruction::Nop,
+        0x10 => Instruction::Stop,
+        0x76 => Instruction::Halt,
+        0xF3 => Instruction::Di,
+        0xFB => Instruction::Ei,
+        0x40 ..= 0x7F => Instruction::Ld(R8::from_bits(opcode, 3), R8::from_bits(opcode, 0)),
+        0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => Instruction::LdImmediate(R8::from_bits(opcode, 3), n),
+        0x0A => Instruction::LoadA(Address::BC),
+        0x1A => Instruction::LoadA(Address::DE),
+        0x2A => Instruction::LoadA(Address::HlIncrement),
+        0x3A => Instruction::LoadA(Address::HlDecrement),
+        0xF2 => Instruction::LoadA(Address::HighC),
+        0xF0 => Instruction::LoadA(Address::High(n)),
+        0xFA => Instruction::LoadA(Address::Absolute(nn)),
+        0x02 => Instruction::StoreA(Address::BC),
+        0x12 => Instruction::StoreA(Address::DE),
+        0x22 => Instruction::StoreA(Address::HlIncrement),
+        0x32 => Instruction::StoreA(Address::HlDecrement),
+        0xE2 => Instruction::StoreA(Address::HighC),
+        0xE0 => Instruction::StoreA(Address::High(n)),
+        0xEA => Instruction::StoreA(Address::Absolute(nn)),
+        0x01 | 0x11 | 0x21 | 0x31 => Instruction::Ld16(R16::from_bits(opcode), nn),
+        0x08 => Instruction::StoreSp(nn),
+        0xF8 => Instruction::LdHlSpOffset(n as i8),
+        0xF9 => Instruction::LdSpHl,
+        0xC5 | 0xD5 | 0xE5 | 0xF5 => Instruction::Push(R16::from_stack_bits(opcode)),
+        0xC1 | 0xD1 | 0xE1 | 0xF1 => Instruction::Pop(R16::from_stack_bits(opcode)),
+        0x03 | 0x13 | 0x23 | 0x33 => Instruction::Inc16(R16::from_bits(opcode)),
+        0x0B | 0x1B | 0x2B | 0x3B => Instruction::Dec16(R16::from_bits(opcode)),
+        0x09 | 0x19 | 0x29 | 0x39 => Instruction::AddHl(R16::from_bits(opcode)),
+        0xE8 => Instruction::AddSp(n as i8),
+        0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => Instruction::Inc(R8::from_bits(opcode, 3)),
+        0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => Instruction::Dec(R8::from_bits(opcode, 3)),
+        0x80 ..= 0xBF => Instruction::Alu(ALU_OPS[(opcode >> 3 & 0x07) as usize], R8::from_bits(opcode, 0)),
+        0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => Instruction::AluImmediate(ALU_OPS[(opcode >> 3 & 0x07) as usize], n),
+        0x27 => Instruction::Daa,
+        0x2F => Instruction::Cpl,
+        0x37 => Instruction::Scf,
+        0x3F => Instruction::Ccf,
+        0x07 => Instruction::Rlca,
+        0x0F => Instruction::Rrca,
+        0x17 => Instruction::Rla,
+        0x1F => Instruction::Rra,
+        0xC3 => Instruction::Jp(None, nn),
+        0xC2 | 0xCA | 0xD2 | 0xDA => Instruction::Jp(Some(condition), nn),
+        0xE9 => Instruction::JpHl,
+        0x18 => Instruction::Jr(None, n as i8),
+        0x20 | 0x28 | 0x30 | 0x38 => Instruction::Jr(Some(condition), n as i8),
+        0xCD => Instruction::Call(None, nn),
+        0xC4 | 0xCC | 0xD4 | 0xDC => Instruction::Call(Some(condition), nn),
+        0xC9 => Instruction::Ret(None),
+        0xC0 | 0xC8 | 0xD0 | 0xD8 => Instruction::Ret(Some(condition)),
+        0xD9 => Instruction::Reti,
+        0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => Instruction::Rst(opcode & 0x38),
+        0xCB => {
+            let operand = R8::from_bits(n, 0);
+            let index = n >> 3 & 0x07;
+            match n {
+                0x00 ..= 0x3F => Instruction::Shift(SHIFT_OPS[index as usize], operand),
+                0x40 ..= 0x7F => Instruction::Bit(index, operand),
+                0x80 ..= 0xBF => Instruction::Res(index, operand),
+                0xC0 ..= 0xFF => Instruction::Set(index, operand),
+            }
+        },
+        _ => Instruction::Invalid(opcode),
+    };
+    let (cycles, branch_cycles) = instruction.cycles();
+    Decoded { instruction, length: length(opcode), cycles, branch_cycles }
+}
+
+impl Instruction {
+    // (not taken, taken), the same for instructions that don't branch
+    fn cycles(&self) -> (u32, u32) {
+        let cycles = match *self {
+            Instruction::Jp(Some(_), _) => return (12, 16),
+            Instruction::Jr(Some(_), _) => return (8, 12),
+            Instruction::Call(Some(_), _) => return (12, 24),
+            Instruction::Ret(Some(_)) => return (8, 20),
+            Instruction::Ld(R8::HlIndirect, _) | Instruction::Ld(_, R8::HlIndirect) => 8,
+            Instruction::LdImmediate(R8::HlIndirect, _) => 12,
+            Instruction::LdImmediate(..) => 8,
+            Instruction::LoadA(Address::Absolute(_)) | Instruction::StoreA(Address::Absolute(_)) => 16,
+            Instruction::LoadA(Address::High(_)) | Instruction::StoreA(Address::High(_)) => 12,
+            Instruction::LoadA(_) | Instruction::StoreA(_) => 8,
+            Instruction::Ld16(..) => 12,
+            Instruction::StoreSp(_) => 20,
+            Instruction::LdHlSpOffset(_) => 12,
+            Instruction::LdSpHl => 8,
+            Instruction::Push(_) => 16,
+            Instruction::Pop(_) => 12,
+            Instruction::Inc16(_) | Instruction::Dec16(_) | Instruction::AddHl(_) => 8,
+            Instruction::AddSp(_) => 16,
+            Instruction::Inc(R8::HlIndirect) | Instruction::Dec(R8::HlIndirect) => 12,
+            Instruction::Alu(_, R8::HlIndirect) | Instruction::AluImmediate(..) => 8,
+            Instruction::Jp(None, _) => 16,
+            Instruction::Jr(None, _) => 12,
+            Instruction::Call(None, _) => 24,
+            Instruction::Ret(None) | Instruction::Reti | Instruction::Rst(_) => 16,
+            Instruction::Bit(_, R8::HlIndirect) => 12,
+            Instruction::Shift(_, R8::HlIndirect) | Instruction::Res(_, R8::HlIndirect) | Instruction::Set(_, R8::HlIndirect) => 16,
+            Instruction::Shift(..) | Instruction::Bit(..) | Instruction::Res(..) | Instruction::Set(..) => 8,
+            _ => 4,
+        };
+        (cycles, cycles)
+    }
+}
+
+impl Decoded {
+    // the text for the instruction at addr. Addresses that jumps, calls and memory operands
+    // use go through name first, so they can be shown as labels
+    pub fn format(&self, addr: u16, name: &dyn Fn(u16) -> Option<String>) -> String {
+        let address = |addr: u16| name(addr).unwrap_or_else(|| format!("${:04X}", addr));
+        let with_condition = |mnemonic: &str, condition: Option<Condition>, operand: String| match condition {
+            Some(condition) => format!("{} {:?},{}", mnemonic, condition, operand),
+            None => format!("{} {}", mnemonic, operand),
+        };
+        let memory = |at: Address| match at {
+            Address::BC => "(BC)".to_string(),
+            Address::DE => "(DE)".to_string(),
+            Address::HlIncrement => "(HL+)".to_string(),
+            Address::HlDecrement => "(HL-)".to_string(),
+            Address::Absolute(addr) => format!("({})", address(addr)),
+            Address::HighC => "(C)".to_string(),
+            Address::High(n) => format!("({})", address(0xFF00 | n as u16)),
+        };
+        match self.instruction {
+            Instruction::Nop => "NOP".to_string(),
+            Instruction::Stop => "STOP".to_string(),
+            Instruction::Halt => "HALT".to_string(),
+            Instruction::Di => "DI".to_string(),
+            Instruction::Ei => "EI".to_string(),
+            Instruction::Ld(to, from) => format!("LD {},{}", to, from),
+            Instruction::LdImmediate(to, n) => format!("LD {},${:02X}", to, n),
+            Instruction::LoadA(at @ Address::High(_)) => format!("LDH A,{}", memory(at)),
+            Instruction::StoreA(at @ Address::High(_)) => format!("LDH {},A", memory(at)),
+            Instruction::LoadA(at) => format!("LD A,{}", memory(at)),
+            Instruction::StoreA(at) => format!("LD {},A", memory(at)),
+            Instruction::Ld16(to, nn) => format!("LD {:?},${:04X}", to, nn),
+            Instruction::StoreSp(nn) => format!("LD ({}),SP", address(nn)),
+            Instruction::LdHlSpOffset(e) => format!("LD HL,SP{:+}", e),
+            Instruction::LdSpHl => "LD SP,HL".to_string(),
+            Instruction::Push(pair) => format!("PUSH {:?}", pair),
+            Instruction::Pop(pair) => format!("POP {:?}", pair),
+            Instruction::Inc16(pair) => format!("INC {:?}", pair),
+            Instruction::Dec16(pair) => format!("DEC {:?}", pair),
+            Instruction::AddHl(pair) => format!("ADD HL,{:?}", pair),
+            Instruction::AddSp(e) => format!("ADD SP,{}", e),
+            Instruction::Inc(operand) => format!("INC {}", operand),
+            Instruction::Dec(operand) => format!("DEC {}", operand),
+            Instruction::Alu(op, operand) => format!("{}{}", op, operand),
+            Instruction::AluImmediate(op, n) => format!("{}${:02X}", op, n),
+            Instruction::Daa => "DAA".to_string(),
+            Instruction::Cpl => "CPL".to_string(),
+            Instruction::Scf => "SCF".to_string(),
+            Instruction::Ccf => "CCF".to_string(),
+            Instruction::Rlca => "RLCA".to_string(),
+            Instruction::Rrca => "RRCA".to_string(),
+            Instruction::Rla => "RLA".to_string(),
+            Instruction::Rra => "RRA".to_string(),
+            Instruction::Jp(condition, nn) => with_condition("JP", condition, address(nn)),
+            Instruction::JpHl => "JP (HL)".to_string(),
+            Instruction::Jr(condition, e) => {
+                let target = addr.wrapping_add(self.length).wrapping_add(e as u16);
+                with_condition("JR", condition, address(target))
+            },
+            Instruction::Call(condition, nn) => with_condition("CALL", condition, address(nn)),
+            Instruction::Ret(Some(condition)) => format!("RET {:?}", condition),
+            Instruction::Ret(None) => "RET".to_string(),
+            Instruction::Reti => "RETI".to_string(),
+            Instruction::Rst(vector) => format!("RST ${:02X}", vector),
+            Instruction::Shift(op, operand) => format!("{:?} {}", op, operand).to_uppercase(),
+            Instruction::Bit(index, operand) => format!("BIT {},{}", index, operand),
+            Instruction::Res(index, operand) => format!("RES {},{}", index, operand),
+            Instruction::Set(index, operand) => format!("SET {},{}", index, operand),
+            Instruction::Invalid(opcode) => format!("DB ${:02X}", opcode),
+        }
+    }
+}
+
+impl fmt::Display for R8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            R8::HlIndirect => write!(f, "(HL)"),
+            register => write!(f, "{:?}", register),
+        }
+    }
+}
+
+// the mnemonic up to the operand, SUB, AND, XOR, OR and CP leave A out
+impl fmt::Display for AluOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AluOp::Add => "ADD A,",
+            AluOp::Adc => "ADC A,",
+            AluOp::Sub => "SUB ",
+            AluOp::Sbc => "SBC A,",
+            AluOp::And => "AND ",
+            AluOp::Xor => "XOR ",
+            AluOp::Or => "OR ",
+            AluOp::Cp => "CP ",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // https://gbdev.io/pandocs/CPU_Instruction_Set.html, lengths in bytes and timings in machine cycles.
+    // The invalid opcodes are 0 in the timings and only have to decode as Invalid
+    const LENGTHS: [u8; 256] = [
+        1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1,
+        1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1,
+        2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1,
+        2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1,
+    ];
+
+    // not taken for conditional jumps, calls and returns
+    const TIMINGS: [u32; 256] = [
+        1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1,
+        1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+        2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+        2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 0, 3, 6, 2, 4,
+        2, 3, 3, 0, 3, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4,
+        3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4,
+        3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4,
+    ];
+
+    // the conditional jumps, calls and returns when taken
+    const TAKEN_TIMINGS: [(u8, u32); 16] = [
+        (0x20, 3), (0x28, 3), (0x30, 3), (0x38, 3),
+        (0xC0, 5), (0xC8, 5), (0xD0, 5), (0xD8, 5),
+        (0xC2, 4), (0xCA, 4), (0xD2, 4), (0xDA, 4),
+        (0xC4, 6), (0xCC, 6), (0xD4, 6), (0xDC, 6),
+    ];
+
+    // after the 0xCB prefix, all of them are 2 bytes long
+    const CB_TIMINGS: [u32; 256] = [
+        2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
+        2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
+        2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
+        2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
+        2, 2, 2, 2, 2, 2, 3, 2, 2, 2, 2, 2, 2, 2, 3, 2,
+        2, 2, 2, 2, 2, 2, 3, 2, 2, 2, 2, 2, 2, 2, 3, 2,
+        2, 2, 2, 2, 2, 2, 3, 2, 2, 2, 2, 2, 2, 2, 3, 2,
+        2, 2, 2, 2, 2, 2, 3, 2, 2, 2, 2, 2, 2, 2, 3, 2,
+        2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
+        2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
+        2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
+        2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
+        2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
+        2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
+        2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
+        2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
+    ];
+
+    fn no_names(_: u16) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn opcode_table() {
+        for opcode in 0x00 ..= 0xFF {
+            let decoded = decode_bytes([opcode, 0x00, 0x00]);
+            assert_eq!(length(opcode), LENGTHS[opcode as usize] as u16, "length of {:02X}", opcode);
+            assert_eq!(decoded.length, length(opcode), "decoded length of {:02X}", opcode);
+            // the prefix is counted in CB_TIMINGS
+            if opcode == 0xCB {
+                continue;
+            }
+            let timing = TIMINGS[opcode as usize];
+            if timing == 0 {
+                assert_eq!(decoded.instruction, Instruction::Invalid(opcode));
+                continue;
+            }
+            assert_eq!(decoded.cycles, timing * 4, "cycles of {:02X}", opcode);
+            let taken = TAKEN_TIMINGS.iter().find(|&&(branch, _)| branch == opcode).map_or(timing, |&(_, taken)| taken);
+            assert_eq!(decoded.branch_cycles, taken * 4, "cycles of {:02X} when taken", opcode);
+        }
+    }
+
+    #[test]
+    fn cb_opcode_table() {
+        for opcode in 0x00 ..= 0xFF {
+            let decoded = decode_bytes([0xCB, opcode, 0x00]);
+            assert_eq!(decoded.length, 2, "length of CB {:02X}", opcode);
+            assert_eq!(decoded.cycles, CB_TIMINGS[opcode as usize] * 4, "cycles of CB {:02X}", opcode);
+            assert_eq!(decoded.branch_cycles, decoded.cycles, "cycles of CB {:02X} when taken", opcode);
+        }
+    }
+
+    #[test]
+    fn format() {
+        let format = |addr: u16, bytes: [u8; 3]| decode_bytes(bytes).format(addr, &no_names);
+        assert_eq!(format(0x0149, [0x20, 0x05, 0x00]), "JR NZ,$0150");
+        assert_eq!(format(0x0200, [0x18, 0xFE, 0x00]), "JR $0200");
+        assert_eq!(format(0x0000, [0x21, 0x00, 0xC0]), "LD HL,$C000");
+        assert_eq!(format(0x0000, [0xE0, 0x40, 0x00]), "LDH ($FF40),A");
+        assert_eq!(format(0x0000, [0xFA, 0xA0, 0xC0]), "LD A,($C0A0)");
+        assert_eq!(format(0x0000, [0xF8, 0xFE, 0x00]), "LD HL,SP-2");
+        assert_eq!(format(0x0000, [0xFE, 0x10, 0x00]), "CP $10");
+        assert_eq!(format(0x0000, [0x8E, 0x00, 0x00]), "ADC A,(HL)");
+        assert_eq!(format(0x0000, [0xC8, 0x00, 0x00]), "RET Z");
+        assert_eq!(format(0x0000, [0xFF, 0x00, 0x00]), "RST $38");
+        assert_eq!(format(0x0000, [0xCB, 0x7C, 0x00]), "BIT 7,H");
+        assert_eq!(format(0x0000, [0xCB, 0x36, 0x00]), "SWAP (HL)");
+        assert_eq!(format(0x0000, [0xD3, 0x00, 0x00]), "DB $D3");
+    }
+
+    #[test]
+    fn format_with_labels() {
+        let name = |addr: u16| if addr == 0x0150 { Some("Main".to_string()) } else { None };
+        assert_eq!(decode_bytes([0xCD, 0x50, 0x01]).format(0x0100, &name), "CALL Main");
+        assert_eq!(decode_bytes([0xDA, 0x50, 0x01]).format(0x0100, &name), "JP C,Main");
+        assert_eq!(decode_bytes([0xC3, 0x51, 0x01]).format(0x0100, &name), "JP $0151");
+    }
+}
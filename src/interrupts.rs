@@ -1,11 +1,56 @@
-pub struct Interrupts {
+use super::memory::Memory;
+use super::save_state::{SaveState, StateReader, StateWriter};
 
+// https://gbdev.io/pandocs/Interrupt_Sources.html
+#[derive(Copy, Clone)]
+pub enum Interrupt {
+    VBlank = 1 << 0,
+    LcdStat = 1 << 1,
+    Timer = 1 << 2,
+    Serial = 1 << 3,
+    Joypad = 1 << 4,
+}
+
+// IF - the interrupt flags raised by the hardware components, shared between them
+pub struct Interrupts {
+    pub flag: u8,
 }
 
 impl Interrupts {
     pub fn init() -> Self {
         Self {
+            flag: 0x00,
+        }
+    }
+
+    pub fn request(&mut self, interrupt: Interrupt) {
+        self.flag |= interrupt as u8;
+    }
+}
 
+impl Memory for Interrupts {
+    fn read8(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF0F => 0xE0 | self.flag,
+            _ => panic!("unimplemented address read on Interrupts {:#04x}", addr)
         }
     }
-}
\ No newline at end of file
+
+    fn write8(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xFF0F => self.flag = data & 0x1F,
+            _ => panic!("unimplemented address write on Interrupts {:#04x}, value: {:#02x}", addr, data)
+        }
+    }
+}
+
+impl SaveState for Interrupts {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.flag);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.flag = reader.read_u8()? & 0x1F;
+        Ok(())
+    }
+}
@@ -1,30 +1,103 @@
 use std::{rc::Rc, cell::RefCell};
-use super::interrupts::Interrupts;
+use super::interrupts::{Interrupt, Interrupts};
 use super::memory::Memory;
+use super::save_state::{SaveState, StateReader, StateWriter};
+
+pub const MAX_PLAYERS: usize = 4;
+
+// https://gbdev.io/pandocs/Joypad_Input.html
+// the low nibble of a controller state holds the d-pad, the high nibble the buttons. 1 is pressed.
+#[derive(Copy, Clone)]
+pub enum Button {
+    Right = 1 << 0,
+    Left = 1 << 1,
+    Up = 1 << 2,
+    Down = 1 << 3,
+    A = 1 << 4,
+    B = 1 << 5,
+    Select = 1 << 6,
+    Start = 1 << 7,
+}
 
 pub struct Joypad {
     pub interrupts: Rc<RefCell<Interrupts>>,
+    select: u8,
+    // one state per controller, only the Super Game Boy can read more than the first
+    pub controllers: [u8; MAX_PLAYERS],
+    pub players: u8,
+    pub current_player: u8,
 }
 
 impl Joypad {
     pub fn init(interrupts: Rc<RefCell<Interrupts>>) -> Self {
         Self {
             interrupts: interrupts,
+            select: 0x30,
+            controllers: [0x00; MAX_PLAYERS],
+            players: 1,
+            current_player: 0,
+        }
+    }
+
+    pub fn set_button(&mut self, player: usize, button: Button, pressed: bool) {
+        let was_pressed = self.controllers[player] & button as u8 != 0;
+        if pressed {
+            self.controllers[player] |= button as u8;
+        } else {
+            self.controllers[player] &= !(button as u8);
+        }
+        if pressed && !was_pressed {
+            self.interrupts.borrow_mut().request(Interrupt::Joypad);
         }
     }
+
+    pub fn any_pressed(&self) -> bool {
+        self.controllers.iter().any(|&controller| controller != 0)
+    }
 }
 
 impl Memory for Joypad {
     fn read8(&self, addr: u16) -> u8 {
         match addr {
+            0xFF00 => {
+                let controller = self.controllers[self.current_player as usize];
+                let mut pressed = 0x00;
+                if self.select & 0x10 == 0 {
+                    pressed |= controller & 0x0F;
+                }
+                if self.select & 0x20 == 0 {
+                    pressed |= controller >> 4;
+                }
+                // with nothing selected the Super Game Boy reports which controller is being read
+                if self.select == 0x30 && self.players > 1 {
+                    pressed = self.current_player;
+                }
+                0xC0 | self.select | (!pressed & 0x0F)
+            }
            _ => panic!("unimplemented address read on Joypad {:#04x}", addr)
         }
     }
 
     fn write8(&mut self, addr: u16, data: u8) {
         match addr {
+            0xFF00 => self.select = data & 0x30,
             _ => panic!("unimplemented address write on Joypad {:#04x}, value: {:#02x}", addr, data)
         }
     }
 }
- 
\ No newline at end of file
+
+// the pressed buttons come from the host, only what the game selected is restored
+impl SaveState for Joypad {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.select);
+        writer.write_u8(self.players);
+        writer.write_u8(self.current_player);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.select = reader.read_u8()? & 0x30;
+        self.players = reader.read_u8()?.clamp(1, MAX_PLAYERS as u8);
+        self.current_player = reader.read_u8()? % self.players;
+        Ok(())
+    }
+}
@@ -1,15 +1,29 @@
 pub mod apu;
 pub mod cartridge;
+pub mod code_data_log;
+pub mod color_correction;
 pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
 pub mod execution_modes;
+pub mod expression;
 pub mod palette;
 pub mod gpu;
 pub mod gui;
+pub mod hdma;
+pub mod headless;
+pub mod instruction;
 pub mod interrupts;
 pub mod joypad;
 pub mod main_board;
 pub mod memory_management_unit;
+pub mod model;
+pub mod rewind;
+pub mod save_state;
 pub mod serial_cable;
+pub mod sgb;
+pub mod symbols;
 pub mod timer;
+pub mod trace_log;
 pub mod memory;
 
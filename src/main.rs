@@ -1,11 +1,13 @@
-use rustyboy::{gui::Gui, main_board::MainBoard, execution_modes::ExecutionMode};
+use rustyboy::{apu, cartridge, code_data_log::CodeDataLog, disassembler::{Disassembly, BANK_SIZE}, gui::Gui, joypad::Button, main_board::MainBoard, model, model::Model, execution_modes::ExecutionMode, palette, symbols::Symbols, trace_log::TraceLog};
 
 use glow::HasContext;
 use imgui::Context;
-use imgui_glow_renderer::AutoRenderer;
+use imgui_glow_renderer::{AutoRenderer, TextureMap};
 use imgui_sdl2_support::SdlPlatform;
 use sdl2::{
+    audio::{AudioQueue, AudioSpecDesired},
     event::Event,
+    keyboard::{Keycode, Mod},
     video::{GLProfile, Window},
 };
 
@@ -16,19 +18,128 @@ fn glow_context(window: &Window) -> glow::Context {
     }
 }
 
+// Create the texture the emulated LCD is drawn into. Nearest filtering keeps the pixels sharp when scaled.
+fn create_lcd_texture(gl: &glow::Context) -> glow::Texture {
+    unsafe {
+        let texture = gl.create_texture().unwrap();
+        gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+        gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::NEAREST as i32);
+        gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::NEAREST as i32);
+        gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE as i32);
+        gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE as i32);
+        texture
+    }
+}
+
+// Upload RGBA8 pixels of the emulated LCD
+fn upload_lcd_texture(gl: &glow::Context, texture: glow::Texture, (width, height): (usize, usize), pixels: &[u8]) {
+    unsafe {
+        gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+        gl.tex_image_2d(glow::TEXTURE_2D, 0, glow::RGBA as i32, width as i32, height as i32, 0,
+            glow::RGBA, glow::UNSIGNED_BYTE, Some(pixels));
+    }
+}
+
+// F1-F10 pick a save state slot
+fn keycode_to_save_state_slot(keycode: Keycode) -> Option<usize> {
+    match keycode {
+        Keycode::F1 => Some(0),
+        Keycode::F2 => Some(1),
+        Keycode::F3 => Some(2),
+        Keycode::F4 => Some(3),
+        Keycode::F5 => Some(4),
+        Keycode::F6 => Some(5),
+        Keycode::F7 => Some(6),
+        Keycode::F8 => Some(7),
+        Keycode::F9 => Some(8),
+        Keycode::F10 => Some(9),
+        _ => None,
+    }
+}
+
+// keyboard controls for the first controller
+fn keycode_to_button(keycode: Keycode) -> Option<Button> {
+    match keycode {
+        Keycode::Right => Some(Button::Right),
+        Keycode::Left => Some(Button::Left),
+        Keycode::Up => Some(Button::Up),
+        Keycode::Down => Some(Button::Down),
+        Keycode::Z => Some(Button::A),
+        Keycode::X => Some(Button::B),
+        Keycode::Backspace | Keycode::RShift => Some(Button::Select),
+        Keycode::Return => Some(Button::Start),
+        _ => None,
+    }
+}
+
+// rustyboy disassemble game.gb --output dir, writes the rom out as rgbasm source, one file per bank
+fn disassemble(args: Vec<String>) {
+    let mut romfile = String::from("");
+    let mut output = String::from("disassembly");
+    {
+        let mut ap = argparse::ArgumentParser::new();
+        ap.set_description("disassemble a rom into rgbasm source, using the .sym and .cdl files next to it");
+        ap.refer(&mut romfile).add_argument("rom", argparse::Store, "Rom filename").required();
+        ap.refer(&mut output).add_option(&["--output", "-o"], argparse::Store,
+            "directory for the bank_NNN.asm files, disassembly by default");
+        if let Err(code) = ap.parse(args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            std::process::exit(code);
+        }
+    }
+    let cartridge = cartridge::init(&romfile).unwrap_or_else(|err| {
+        eprintln!("{}: {}", romfile, err);
+        std::process::exit(1);
+    });
+    let symbols = Symbols::load_for_rom(&romfile);
+    let code_data_log = CodeDataLog::load(&CodeDataLog::path_for_rom(&romfile), cartridge.rom().len(), cartridge.ram().len());
+    let disassembly = Disassembly::trace(cartridge.as_ref(), &symbols, &code_data_log);
+    let paths = disassembly.write_files(std::path::Path::new(&output)).unwrap_or_else(|err| {
+        eprintln!("couldn't write to {}: {}", output, err);
+        std::process::exit(1);
+    });
+    for (bank, path) in paths.iter().enumerate() {
+        let size = (disassembly.rom.len() - bank * BANK_SIZE).min(BANK_SIZE);
+        println!("{}: {} of {} bytes code", path.display(), disassembly.code_bytes(bank), size);
+    }
+}
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("disassemble") {
+        disassemble(args[1..].to_vec());
+        return;
+    }
     // set up the emulated hardware
     rog::reg("rustyboy");
     rog::reg("rustyboy::cpu");
     let mut romfile = String::from("");
+    let mut boot_rom: Option<String> = None;
+    let mut model_name: Option<String> = None;
+    let mut trace_path: Option<String> = None;
     {
         let mut ap = argparse::ArgumentParser::new();
         ap.set_description("a toy gameboy emulator");
         ap.refer(&mut romfile).add_argument("rom", argparse::Store, "Rom filename");
+        ap.refer(&mut boot_rom).add_option(&["--boot-rom"], argparse::StoreOption,
+            "DMG/MGB/SGB/CGB boot rom to run before the game");
+        ap.refer(&mut model_name).add_option(&["--model"], argparse::StoreOption,
+            "hardware model: dmg0, dmg, mgb, sgb, sgb2, cgb or agb. Detected from the rom header by default");
+        ap.refer(&mut trace_path).add_option(&["--trace"], argparse::StoreOption,
+            "log every instruction in Gameboy Doctor format to this file, - for stdout");
         ap.parse_args_or_exit();
     }
-    let mut main_board = MainBoard::init(&romfile[..]).unwrap();
+    let model = model_name.map(|name| Model::from_name(&name).unwrap_or_else(|| {
+        eprintln!("unknown model {}, expected one of {}", name, model::MODEL_NAMES.join(", "));
+        std::process::exit(2);
+    }));
+    let mut main_board = MainBoard::init(&romfile[..], boot_rom.as_deref(), model).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    if let Some(path) = trace_path {
+        main_board.cpu.trace = Some(TraceLog::create(&path).unwrap());
+    }
+    println!("Running as model: {}", main_board.mmu.borrow().model.name());
     println!("Loaded rom type: {} title: {}", main_board.mmu.borrow().cartridge.get_type(),
         main_board.mmu.borrow().cartridge.get_title());
 
@@ -75,17 +186,62 @@ fn main() {
     let mut renderer = AutoRenderer::initialize(gl, &mut imgui).unwrap();
     let mut event_pump = sdl.event_pump().unwrap();
     let mut gui = Gui::default();
+    gui.user_palettes = palette::load_user_palettes(palette::PALETTE_CONFIG_FILE);
+    let lcd_texture = create_lcd_texture(renderer.gl_context());
+    gui.lcd_texture_id = renderer.texture_map_mut().register(lcd_texture);
+
+    /* stereo f32 audio, fed with whatever the apu generated each frame */
+    let audio_subsystem = sdl.audio().unwrap();
+    let desired_spec = AudioSpecDesired {
+        freq: Some(apu::SAMPLE_RATE as i32),
+        channels: Some(2),
+        samples: Some(1024),
+    };
+    let audio_queue: AudioQueue<f32> = audio_subsystem.open_queue(None, &desired_spec).unwrap();
+    audio_queue.resume();
+
+    // hold R to rewind
+    let mut rewind_key_held = false;
 
     'main: loop {
         for event in event_pump.poll_iter() {
             /* pass all events to imgui platfrom */
             platform.handle_event(&mut imgui, &event);
 
-            if let Event::Quit { .. } = event {
-                break 'main;
+            let keyboard_captured = imgui.io().want_capture_keyboard;
+            match event {
+                Event::Quit { .. } => break 'main,
+                Event::KeyDown { keycode: Some(Keycode::R), repeat: false, .. } if !keyboard_captured => rewind_key_held = true,
+                // hold tab to fast forward
+                Event::KeyDown { keycode: Some(Keycode::Tab), repeat: false, .. } if !keyboard_captured => main_board.fast_forward = true,
+                Event::KeyUp { keycode: Some(Keycode::Tab), .. } => main_board.fast_forward = false,
+                Event::KeyUp { keycode: Some(Keycode::R), .. } => rewind_key_held = false,
+                Event::KeyDown { keycode: Some(keycode), keymod, repeat: false, .. } if !keyboard_captured => {
+                    // shift + F-key saves to a slot, the F-key alone loads it
+                    if let Some(slot) = keycode_to_save_state_slot(keycode) {
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            gui.save_state(&main_board, slot);
+                        } else {
+                            gui.load_state(&mut main_board, slot);
+                        }
+                        println!("{}", gui.save_state_message);
+                    }
+                    if let Some(button) = keycode_to_button(keycode) {
+                        main_board.mmu.borrow_mut().joypad.set_button(0, button, true);
+                    }
+                }
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    if let Some(button) = keycode_to_button(keycode) {
+                        main_board.mmu.borrow_mut().joypad.set_button(0, button, false);
+                    }
+                }
+                _ => {}
             }
         }
 
+        let lcd_pixels = main_board.screen_rgba();
+        upload_lcd_texture(renderer.gl_context(), lcd_texture, main_board.screen_size(), &lcd_pixels);
+
         /* call prepare_frame before calling imgui.new_frame() */
         platform.prepare_frame(&mut imgui, &window, &event_pump);
 
@@ -99,36 +255,38 @@ fn main() {
 
         window.gl_swap_window();
 
+        if rewind_key_held || gui.rewind_held {
+            // one snapshot back per displayed frame, with the sound off
+            main_board.rewind_step();
+            main_board.mmu.borrow_mut().apu.take_samples();
+            audio_queue.clear();
+            continue;
+        }
+
         match execution_mode {
-            ExecutionMode::Running => main_board.emulate_frame(),
-            ExecutionMode::CpuOperation => main_board.emulate_cpu_operation(),
-            ExecutionMode::Frame => main_board.emulate_frame(),
-            ExecutionMode::Stopped => 0,
+            ExecutionMode::Running | ExecutionMode::StepOver | ExecutionMode::StepOut | ExecutionMode::RunToCursor => {
+                main_board.emulate_display_frame();
+            },
+            ExecutionMode::Frame => {
+                main_board.emulate_frame();
+                main_board.record_rewind_frame();
+            },
+            ExecutionMode::CpuOperation => { main_board.emulate_cpu_operation(); },
+            ExecutionMode::Stopped => {},
         };
-    }
-}
-
-/*
-fn main() {
-    rog::reg("rustyboy");
-    rog::reg("rustyboy::cpu");
-    let mut romfile = String::from("");
-    {
-        let mut ap = argparse::ArgumentParser::new();
-        ap.set_description("a toy gameboy emulator");
-        ap.refer(&mut romfile).add_argument("rom", argparse::Store, "Rom filename");
-        ap.parse_args_or_exit();
-    }
-    let mut main_board = MainBoard::init(&romfile[..]).unwrap();
-    println!("Loaded rom type: {} title: {}", main_board.mmu.borrow().cartridge.get_type(),
-        main_board.mmu.borrow().cartridge.get_title());
 
-    'game_loop: loop {
-        main_board.emulate_frame();
-        match main_board.mmu.borrow_mut().gpu.get_updated_image() {
-            None => {}
-            Some(updated_image) => {},
+        let samples = main_board.mmu.borrow_mut().apu.take_samples();
+        if main_board.is_audio_muted_by_speed() {
+            audio_queue.clear();
+            continue;
         }
+        // don't let latency build up if emulation runs ahead of the audio device
+        if audio_queue.size() < apu::SAMPLE_RATE * 4 * 2 / 10 {
+            audio_queue.queue_audio(&samples).unwrap();
+        }
+    }
+    // the next session adds to what was logged in this one
+    if let Err(e) = main_board.save_code_data_log() {
+        println!("Failed to save the code data log: {}", e);
     }
 }
-*/
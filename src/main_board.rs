@@ -1,47 +1,361 @@
 use std::time::{Instant, Duration};
 use std::{cell::RefCell, rc::Rc};
+use super::code_data_log::CodeDataLog;
 use super::cpu::Cpu;
+use super::debugger::{BreakReason, Breakpoint, StepTarget};
+use super::expression::Context;
+use super::gpu;
+use super::instruction;
+use super::instruction::Instruction;
 use super::memory_management_unit::MemoryManagementUnit;
+use super::model::Model;
+use super::rewind::Rewind;
+use super::save_state;
+use super::save_state::{SaveState, StateReader, StateWriter};
+use super::sgb;
+use super::symbols::Symbols;
 
 pub const VSYNC_FREQ: f64 = 59.73;
 pub const CPU_FREQUENCY: u32 = 4_194_304;
 pub const CPU_CLOCKS_PER_FRAME: u32 = (CPU_FREQUENCY as f64 / VSYNC_FREQ) as u32;
+// emulation speed multipliers, infinity runs as fast as the host allows
+pub const SPEEDS: [f64; 7] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0, f64::INFINITY];
+pub const SPEED_NAMES: [&str; 7] = ["0.25x", "0.5x", "1x", "2x", "4x", "8x", "unlimited"];
+// log point messages kept, the oldest are dropped
+pub const LOG_LINES: usize = 1000;
 
 pub struct MainBoard {
     pub cpu: Cpu,
     pub mmu: Rc<RefCell<MemoryManagementUnit>>,
+    // save state slots are stored next to the rom
+    pub rom_path: String,
+    // labels from the .sym file next to the rom
+    pub symbols: Symbols,
+    pub rewind: Rewind,
+    pub speed: f64,
+    // held fast forward runs unlimited, whatever the speed is set to
+    pub fast_forward: bool,
+    pub breakpoints: Vec<Breakpoint>,
+    // why running frames stopped early, cleared by whoever resumes
+    pub break_reason: Option<BreakReason>,
+    // set while stepping over or out, or running to the cursor
+    pub step_target: Option<StepTarget>,
+    // messages from log points
+    pub log: Vec<String>,
 }
 
 impl MainBoard {
-    pub fn init(filepath: &str) -> std::io::Result<MainBoard> {
-        let mmu = Rc::new(RefCell::new(MemoryManagementUnit::init(filepath)));
-        let cpu = Cpu::init(mmu.clone());
+    pub fn init(filepath: &str, boot_rom_path: Option<&str>, model: Option<Model>) -> std::io::Result<MainBoard> {
+        let boot_rom = match boot_rom_path {
+            Some(path) => Some(load_boot_rom(path)?),
+            None => None,
+        };
+        let mmu = MemoryManagementUnit::init(filepath, boot_rom, model)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", filepath, e)))?;
+        let mmu = Rc::new(RefCell::new(mmu));
+        let (model, cgb_mode) = (mmu.borrow().model, mmu.borrow().cgb_mode);
+        let cpu = if mmu.borrow().boot_rom_mapped {
+            Cpu::init_for_boot_rom(mmu.clone())
+        } else {
+            Cpu::init(mmu.clone(), model, cgb_mode)
+        };
+        {
+            // carries on from the log of earlier sessions
+            let mmu = mmu.borrow();
+            let (rom_size, ram_size) = (mmu.cartridge.rom().len(), mmu.cartridge.ram().len());
+            mmu.code_data_log.replace(CodeDataLog::load(&CodeDataLog::path_for_rom(filepath), rom_size, ram_size));
+        }
         Ok(MainBoard {
             cpu,
             mmu,
+            rom_path: filepath.to_string(),
+            symbols: Symbols::load_for_rom(filepath),
+            rewind: Rewind::init(),
+            speed: 1.0,
+            fast_forward: false,
+            breakpoints: Vec::new(),
+            break_reason: None,
+            step_target: None,
+            log: Vec::new(),
         })
     }
 
+    // returns the cycles taken, counted at normal speed
     pub fn emulate_cpu_operation(&mut self) -> u32 {
-        let cycles = self.cpu.emulate_operation();
-        self.mmu.borrow_mut().run_cycles(cycles);
-        cycles
+        // https://gbdev.io/pandocs/Reducing_Power_Consumption.html#using-the-stop-instruction
+        if self.cpu.stopped && self.mmu.borrow().joypad.any_pressed() {
+            self.cpu.stopped = false;
+        }
+        let cycles = if self.cpu.stopped { 4 } else { self.cpu.emulate_operation() };
+        if self.cpu.stopped && self.mmu.borrow().speed_switch_armed {
+            self.mmu.borrow_mut().switch_speed();
+            self.cpu.stopped = false;
+        }
+        let mut mmu = self.mmu.borrow_mut();
+        mmu.run_cycles(cycles);
+        let stall_cycles = mmu.take_dma_stall_cycles();
+        mmu.run_cycles(stall_cycles);
+        let cycles = cycles + stall_cycles;
+        if let Some(hit) = mmu.watchpoints.take_hit() {
+            self.break_reason = Some(BreakReason::Watchpoint(hit));
+        }
+        if mmu.double_speed { cycles / 2 } else { cycles }
     }
 
-    pub fn emulate_frame(&mut self) -> u32 {
+    // checked between instructions while running, the breakpoint stops before its instruction runs
+    fn hit_breakpoint(&mut self) -> bool {
+        if self.break_reason.is_some() {
+            self.step_target = None;
+            return true;
+        }
+        let pc = self.cpu.pc;
+        let mmu = self.mmu.borrow();
+        let mut hit = false;
+        for breakpoint in self.breakpoints.iter_mut().filter(|breakpoint| breakpoint.enabled && breakpoint.addr == pc
+            && breakpoint.bank.is_none_or(|bank| mmu.rom_bank(pc) == Some(bank))) {
+            breakpoint.hits += 1;
+            let context = Context { cpu: &self.cpu, mmu: &mmu, hits: breakpoint.hits };
+            if breakpoint.condition.as_ref().is_some_and(|(_, condition)| condition.evaluate(&context) == 0) {
+                continue;
+            }
+            match &breakpoint.log_message {
+                Some((_, message)) => {
+                    if self.log.len() == LOG_LINES {
+                        self.log.remove(0);
+                    }
+                    self.log.push(format!("{}: {}", breakpoint.name(), message.format(&context)));
+                }
+                None => hit = true,
+            }
+        }
+        if hit {
+            self.break_reason = Some(BreakReason::Breakpoint(pc));
+            self.step_target = None;
+            return true;
+        }
+        if self.step_target.is_some_and(|target| target.reached(pc, self.cpu.call_depth)) {
+            self.break_reason = Some(BreakReason::Step(pc));
+            self.step_target = None;
+            return true;
+        }
+        false
+    }
+
+    // the label at addr, in the rom bank mapped there right now
+    pub fn symbol(&self, addr: u16) -> Option<&str> {
+        self.symbols.name(self.mmu.borrow().rom_bank(addr), addr)
+    }
+
+    // Sets the step target to the instruction after the CALL or RST at pc, frames then run until break_reason
+    // is set. Returns false when there's no call to step over, a single step does the same.
+    pub fn step_over(&mut self) -> bool {
+        let pc = self.cpu.pc;
+        let decoded = instruction::decode(&*self.mmu.borrow(), pc);
+        if !matches!(decoded.instruction, Instruction::Call(..) | Instruction::Rst(_)) {
+            self.step_target = None;
+            return false;
+        }
+        let addr = pc.wrapping_add(decoded.length);
+        self.step_target = Some(StepTarget::Address { addr, depth: self.cpu.call_depth });
+        true
+    }
+
+    // runs until the current function returns, returns false outside of any known call
+    pub fn step_out(&mut self) -> bool {
+        if self.cpu.call_stack.is_empty() {
+            return false;
+        }
+        self.step_target = Some(StepTarget::Return { depth: self.cpu.call_depth });
+        true
+    }
+
+    pub fn run_to(&mut self, addr: u16) {
+        self.step_target = Some(StepTarget::Address { addr, depth: usize::MAX });
+    }
+
+    // the size of the picture to show, the Super Game Boy adds a border around the screen
+    pub fn screen_size(&self) -> (usize, usize) {
+        if self.mmu.borrow().sgb.is_some() {
+            (sgb::WIDTH, sgb::HEIGHT)
+        } else {
+            (gpu::WIDTH, gpu::HEIGHT)
+        }
+    }
+
+    // RGBA8 pixels of screen_size()
+    pub fn screen_rgba(&self) -> Vec<u8> {
+        let mmu = self.mmu.borrow();
+        match &mmu.sgb {
+            Some(sgb) => sgb.render_rgba(mmu.gpu.framebuffer()),
+            None => mmu.gpu.framebuffer_rgba(),
+        }
+    }
+
+    // header: magic, format version, model, rom hash. Then the cpu and everything behind the mmu
+    pub fn save_state(&self) -> Vec<u8> {
+        let mmu = self.mmu.borrow();
+        let mut writer = StateWriter::init();
+        writer.write_bytes(save_state::MAGIC);
+        writer.write_u16(save_state::VERSION);
+        writer.write_u8(mmu.model as u8);
+        writer.write_u64(save_state::rom_hash(mmu.cartridge.rom()));
+        self.cpu.save_state(&mut writer);
+        mmu.save_state(&mut writer);
+        writer.data
+    }
+
+    // a state that fails to load leaves the board as it was
+    pub fn load_state(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let mut reader = StateReader::init(data);
+        let mut magic = [0x0; 4];
+        reader.read_bytes(&mut magic)?;
+        if &magic != save_state::MAGIC {
+            return Err(save_state::invalid_data("not a rustyboy save state".to_string()));
+        }
+        let version = reader.read_u16()?;
+        if version != save_state::VERSION {
+            return Err(save_state::invalid_data(format!("unsupported save state version {}, expected {}", version, save_state::VERSION)));
+        }
+        let model = reader.read_u8()?;
+        let rom_hash = reader.read_u64()?;
+        {
+            let mmu = self.mmu.borrow();
+            if model != mmu.model as u8 {
+                return Err(save_state::invalid_data(format!("save state is for a different model than {}", mmu.model.name())));
+            }
+            if rom_hash != save_state::rom_hash(mmu.cartridge.rom()) {
+                return Err(save_state::invalid_data("save state is for a different rom".to_string()));
+            }
+        }
+        let backup = self.save_state();
+        let result = self.load_components(&mut reader).and_then(|_| if reader.is_at_end() {
+            Ok(())
+        } else {
+            Err(save_state::invalid_data("save state has trailing data".to_string()))
+        });
+        if result.is_err() {
+            self.load_components(&mut StateReader::init(&backup[save_state::HEADER_SIZE ..]))
+                .expect("restoring the state from before a failed load");
+        }
+        result
+    }
+
+    fn load_components(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.cpu.load_state(reader)?;
+        self.mmu.borrow_mut().load_state(reader)
+    }
+
+    pub fn save_code_data_log(&self) -> std::io::Result<()> {
+        self.mmu.borrow().code_data_log.borrow().save(&CodeDataLog::path_for_rom(&self.rom_path))
+    }
+
+    pub fn state_slot_path(&self, slot: usize) -> String {
+        format!("{}.state{}", self.rom_path, slot)
+    }
+
+    pub fn save_state_to_slot(&self, slot: usize) -> std::io::Result<()> {
+        std::fs::write(self.state_slot_path(slot), self.save_state())
+    }
+
+    pub fn load_state_from_slot(&mut self, slot: usize) -> std::io::Result<()> {
+        let data = std::fs::read(self.state_slot_path(slot))?;
+        self.load_state(&data)?;
+        // the history leads up to the state that was replaced
+        self.rewind.clear();
+        Ok(())
+    }
+
+    // call once per emulated frame, snapshots are taken every rewind.interval_frames
+    pub fn record_rewind_frame(&mut self) {
+        if self.rewind.frame_finished() {
+            let state = self.save_state();
+            self.rewind.push(state);
+        }
+    }
+
+    // goes back one snapshot, false when there's no history left
+    pub fn rewind_step(&mut self) -> bool {
+        match self.rewind.step_back() {
+            Some(state) => self.load_state(&state).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn effective_speed(&self) -> f64 {
+        if self.fast_forward { f64::INFINITY } else { self.speed }
+    }
+
+    // the apu output only sounds right at normal speed
+    pub fn is_audio_muted_by_speed(&self) -> bool {
+        self.effective_speed() != 1.0
+    }
+
+    // emulates one frame as fast as possible, or until a breakpoint, watchpoint or the step target is hit
+    pub fn run_frame(&mut self) -> u32 {
         let mut emulated_cycles = 0;
-        let time_before = Instant::now();
-        const TARGET_FRAME_TIME: Duration = Duration::from_millis((1000.0_f64 / VSYNC_FREQ) as u64);
         while emulated_cycles < CPU_CLOCKS_PER_FRAME {
             emulated_cycles += self.emulate_cpu_operation();
+            if self.hit_breakpoint() {
+                break;
+            }
         }
-        let sleep_millis = TARGET_FRAME_TIME.checked_sub(Instant::now() - time_before);
-        match sleep_millis {
-            None => {}, // running below target fps
-            Some(sleep_millis) => { 
-                ::std::thread::sleep(sleep_millis);
+        emulated_cycles
+    }
+
+    // emulates one frame, taking as long as it would at the current speed
+    pub fn emulate_frame(&mut self) -> u32 {
+        let time_before = Instant::now();
+        let emulated_cycles = self.run_frame();
+        sleep_until(time_before + frame_time(self.effective_speed()));
+        emulated_cycles
+    }
+
+    // Emulates all the frames that fit in one refresh of the host display at the current speed.
+    // Above 1x several frames are run and only the last one is shown, unlimited keeps going for a
+    // whole refresh. Rewind snapshots are taken as usual.
+    pub fn emulate_display_frame(&mut self) -> u32 {
+        let time_before = Instant::now();
+        let speed = self.effective_speed();
+        let display_frame_time = frame_time(1.0);
+        let frames = if speed.is_infinite() { u32::MAX } else { speed.round().max(1.0) as u32 };
+        let mut emulated_cycles = 0;
+        for _ in 0 .. frames {
+            emulated_cycles += self.run_frame();
+            self.record_rewind_frame();
+            if self.break_reason.is_some() {
+                break;
             }
+            if speed.is_infinite() && Instant::now() - time_before >= display_frame_time {
+                break;
+            }
+        }
+        if !speed.is_infinite() {
+            sleep_until(time_before + frame_time(speed / frames as f64));
         }
         emulated_cycles
     }
-}
\ No newline at end of file
+}
+
+fn frame_time(speed: f64) -> Duration {
+    Duration::from_secs_f64(1.0 / (VSYNC_FREQ * speed))
+}
+
+fn sleep_until(deadline: Instant) {
+    match deadline.checked_duration_since(Instant::now()) {
+        None => {}, // running below target fps
+        Some(sleep_time) => {
+            ::std::thread::sleep(sleep_time);
+        }
+    }
+}
+
+// https://gbdev.io/pandocs/Power_Up_Sequence.html#monochrome-models-dmg0-dmg-mgb
+// the errors name the file, like those of the rom
+fn load_boot_rom(path: &str) -> std::io::Result<Vec<u8>> {
+    let boot_rom = std::fs::read(path).map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    match boot_rom.len() {
+        0x100 | 0x900 => Ok(boot_rom),
+        length => Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+            format!("{}: expected a 256 byte (DMG/MGB/SGB) or 2304 byte (CGB) boot rom, got {} bytes", path, length))),
+    }
+}
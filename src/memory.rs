@@ -1,11 +1,19 @@
 pub trait Memory {
     fn read8(&self, _addr: u16) -> u8;
     fn write8(&mut self, _addr: u16, _data: u8);
+    // a read that debugger watchpoints don't see, for tools and hardware looking at memory
+    fn peek8(&self, addr: u16) -> u8 {
+        self.read8(addr)
+    }
+    // the cpu reading the opcode or an operand of an instruction
+    fn fetch8(&self, addr: u16, _opcode: bool) -> u8 {
+        self.read8(addr)
+    }
     fn read16(&self, addr: u16) -> u16 {
-        u16::from(self.read8(addr)) | (u16::from(self.read8(addr + 1)) << 8)
+        u16::from(self.read8(addr)) | (u16::from(self.read8(addr.wrapping_add(1))) << 8)
     }
     fn write16(&mut self, addr: u16, data: u16) {
         self.write8(addr, (data & 0x00FF) as u8);
-        self.write8(addr + 1, (data >> 8) as u8);
+        self.write8(addr.wrapping_add(1), (data >> 8) as u8);
     }
 }
\ No newline at end of file
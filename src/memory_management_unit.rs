@@ -2,104 +2,462 @@ use super::memory::Memory;
 use super::apu::Apu;
 use super::cartridge;
 use super::cartridge::Cartridge;
+use super::code_data_log;
+use super::code_data_log::CodeDataLog;
+use super::debugger::Watchpoints;
+use super::gpu;
 use super::gpu::Gpu;
+use super::hdma;
+use super::hdma::{Hdma, HdmaMode};
 use super::interrupts::Interrupts;
 use super::joypad::Joypad;
+use super::model::Model;
+use super::save_state::{SaveState, StateReader, StateWriter};
 use super::serial_cable::SerialCable;
+use super::sgb::Sgb;
 use super::timer::Timer;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::ops::RangeInclusive;
 
 pub struct MemoryManagementUnit {
     pub cartridge: Box<dyn Cartridge>,
+    pub model: Model,
+    // https://gbdev.io/pandocs/Power_Up_Sequence.html
+    // 256 bytes for DMG/MGB/SGB, 2304 for CGB which also covers 0x0200-0x08FF
+    boot_rom: Option<Vec<u8>>,
+    // cleared by writing to $FF50, the cartridge shows through from then on
+    pub boot_rom_mapped: bool,
     pub apu: Apu,
     pub gpu: Gpu,
     pub joypad: Joypad,
     pub serial_cable: SerialCable,
     pub timer: Timer,
-    // interrupts: Rc<RefCell<Interrupts>>,
-    // hdma,
+    pub interrupts: Rc<RefCell<Interrupts>>,
+    pub hdma: Hdma,
+    // only present when a SGB game runs outside of CGB mode
+    pub sgb: Option<Sgb>,
     pub work_ram_c000: [u8; 4096],  //wram
-    pub work_ram_d000: [u8; 4096],  //wram
+    pub work_ram_d000: [[u8; 4096]; 7],  //wram banks 1-7, only CGB can switch away from bank 1
     pub hram: [u8; 128],// hram,
     interrupt_enable: u8,
+    oam_dma_source: u8,
+    // https://gbdev.io/pandocs/CGB_Registers.html
+    pub cgb_mode: bool,
+    work_ram_bank: u8,
+    pub double_speed: bool,
+    pub speed_switch_armed: bool,
+    // cycles the cpu has to wait for dma transfers
+    dma_stall_cycles: u32,
+    pub watchpoints: Watchpoints,
+    // marked on every cpu and dma access to the cartridge
+    pub code_data_log: RefCell<CodeDataLog>,
 }
 
 impl MemoryManagementUnit {
-    pub fn init(filepath: &str) -> Self {
-        let cartridge = cartridge::init(filepath);
+    // the model is detected from the cartridge header when not given
+    pub fn init(filepath: &str, boot_rom: Option<Vec<u8>>, model: Option<Model>) -> std::io::Result<Self> {
+        let cartridge = cartridge::init(filepath)?;
+        let model = model.unwrap_or_else(|| Model::detect(&*cartridge));
+        // CGB hardware runs games without CGB support in DMG compatibility mode
+        let cgb_mode = model.is_cgb() && cartridge.is_cgb();
+        let sgb = if model.is_sgb() && cartridge.is_sgb() { Some(Sgb::init()) } else { None };
         let interrupts = Rc::new(RefCell::new(Interrupts::init()));
-        let mmu = Self {
+        let code_data_log = RefCell::new(CodeDataLog::init(cartridge.rom().len(), cartridge.ram().len()));
+        let mut mmu = Self {
             cartridge: cartridge,
-            apu: Apu::init(),
-            gpu: Gpu::init(interrupts.clone()),
+            model,
+            boot_rom_mapped: boot_rom.is_some(),
+            boot_rom,
+            apu: Apu::init(model),
+            gpu: Gpu::init(interrupts.clone(), model, cgb_mode),
             joypad: Joypad::init(interrupts.clone()),
             serial_cable: SerialCable::init(interrupts.clone()),
-            timer: Timer::init(/*interrupts.clone()*/),
-            // interrupts: interrupts.clone(),
+            timer: Timer::init(interrupts.clone(), model.div_counter()),
+            interrupts: interrupts.clone(),
+            hdma: Hdma::init(),
+            sgb,
             work_ram_c000: [0x0; 4096],
-            work_ram_d000: [0x0; 4096],
+            work_ram_d000: [[0x0; 4096]; 7],
             hram: [0x0; 128],
             interrupt_enable: 0x00,
+            oam_dma_source: 0xFF,
+            cgb_mode,
+            work_ram_bank: 0,
+            double_speed: false,
+            speed_switch_armed: false,
+            dma_stall_cycles: 0,
+            watchpoints: Watchpoints::init(),
+            code_data_log,
         };
-        mmu
+        if mmu.boot_rom_mapped {
+            mmu.power_on_state();
+        } else {
+            mmu.post_boot_state();
+        }
+        Ok(mmu)
+    }
+
+    // https://gbdev.io/pandocs/Power_Up_Sequence.html#hardware-registers
+    // the registers each model's boot rom leaves behind that the components don't already start with.
+    // STAT and LY follow the emulated ppu, which starts at the top of a frame
+    fn post_boot_state(&mut self) {
+        // P1 $CF, both button groups selected
+        self.joypad.write8(0xFF00, 0x00);
+        // SC $7F on the CGB, which can read back its clock speed bit
+        self.serial_cable.write8(0xFF02, if self.model.is_cgb() { 0x7F } else { 0x7E });
+        // IF $E1, the vblank the boot rom finished in is still pending
+        self.interrupts.borrow_mut().write8(0xFF0F, 0xE1);
+        // what's left of the boot sound. The SGB boot rom is silent, leaving NR52 at $F0 instead of $F1
+        self.apu.write8(0xFF10, 0x80);
+        self.apu.write8(0xFF11, 0xBF);
+        self.apu.write8(0xFF12, 0xF3);
+        self.apu.write8(0xFF13, 0xFF);
+        if !self.model.is_sgb() {
+            self.apu.write8(0xFF14, 0xBF);
+        }
+        // DMA $00 on the CGB, $FF elsewhere
+        if self.model.is_cgb() {
+            self.oam_dma_source = 0x00;
+        }
+    }
+
+    // everything defaults to the state the boot rom leaves behind, undo that when it's going to run
+    fn power_on_state(&mut self) {
+        self.apu.write8(0xFF26, 0x00);
+        self.gpu.write8(0xFF40, 0x00);
+        self.gpu.write8(0xFF47, 0x00);
+        self.timer.div_counter = 0x0000;
     }
 
     pub fn run_cycles(&mut self, cpu_clock_cycles: u32) {
         // TODO run cycles on components, let them drive interrupts to each other.
         // This is done in small pieces from the main_board, so no need to break up cpu_clock_cycles
-        self.gpu.run_cycles(cpu_clock_cycles);
+        // In double speed mode the gpu and apu keep running at the normal rate
+        let cycles = if self.double_speed { cpu_clock_cycles / 2 } else { cpu_clock_cycles };
+        self.gpu.run_cycles(cycles);
+        self.apu.run_cycles(cycles);
+        self.timer.run_cycles(cpu_clock_cycles);
+        self.serial_cable.run_cycles(cpu_clock_cycles);
+        if self.gpu.take_hblank_started() && self.hdma.active && self.hdma.mode == HdmaMode::HorizontalBlank {
+            self.hdma_transfer_block();
+        }
+    }
+
+    // https://gbdev.io/pandocs/SGB_Command_Packet.html
+    fn write_joypad(&mut self, data: u8) {
+        self.joypad.write8(0xFF00, data);
+        let sgb = match self.sgb.as_mut() {
+            Some(sgb) => sgb,
+            None => return,
+        };
+        sgb.write_p1(data, self.gpu.framebuffer());
+        if let Some(transfer) = sgb.take_pending_transfer() {
+            // real hardware copies the data out of the next frame sent to the lcd. Games keep showing the
+            // data for a few frames after the command, so reading vram right away gives the same bytes
+            sgb.complete_transfer(transfer, &self.gpu.sgb_transfer_data());
+        }
+        self.joypad.players = sgb.player_count;
+        self.joypad.current_player = sgb.current_player;
+    }
+
+    // the cpu doesn't run while dma transfers are in progress, the main board lets these cycles pass
+    pub fn take_dma_stall_cycles(&mut self) -> u32 {
+        std::mem::take(&mut self.dma_stall_cycles)
+    }
+
+    // https://gbdev.io/pandocs/CGB_Registers.html#lcd-vram-dma-transfers
+    fn hdma_transfer_block(&mut self) {
+        let (source, destination) = self.hdma.next_block();
+        for i in 0 .. hdma::BLOCK_SIZE {
+            let data = self.peek8(source.wrapping_add(i));
+            self.log_usage(source.wrapping_add(i), code_data_log::DMA_SOURCE);
+            self.gpu.write8(destination + i, data);
+        }
+        self.dma_stall_cycles += hdma::CYCLES_PER_BLOCK * if self.double_speed { 2 } else { 1 };
+    }
+
+    fn write_hdma(&mut self, addr: u16, data: u8) {
+        self.hdma.write8(addr, data);
+        if addr != 0xFF55 || !self.hdma.active {
+            return;
+        }
+        match self.hdma.mode {
+            HdmaMode::GeneralPurpose => {
+                while self.hdma.active {
+                    self.hdma_transfer_block();
+                }
+            }
+            // with the lcd off there are no horizontal blanks, the first block is copied right away
+            HdmaMode::HorizontalBlank => if !self.gpu.is_lcd_enabled() {
+                self.hdma_transfer_block();
+            }
+        }
+    }
+
+    // https://gbdev.io/pandocs/CGB_Registers.html#ff4d--key1-cgb-mode-only-prepare-speed-switch
+    // called when the cpu executes STOP with a speed switch armed through KEY1
+    pub fn switch_speed(&mut self) {
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+    }
+
+    fn read_boot_rom(&self, addr: u16) -> Option<u8> {
+        if !self.boot_rom_mapped {
+            return None;
+        }
+        let boot_rom = self.boot_rom.as_ref()?;
+        match addr {
+            0x0000 ..= 0x00FF => Some(boot_rom[addr as usize]),
+            0x0200 ..= 0x08FF if boot_rom.len() > 0x100 => Some(boot_rom[addr as usize]),
+            _ => None,
+        }
+    }
+
+    // the rom bank mapped at addr, None outside of the rom or while the boot rom covers it
+    pub fn rom_bank(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x0000 ..= 0x7FFF if self.read_boot_rom(addr).is_none() => Some(self.cartridge.rom_bank(addr)),
+            _ => None,
+        }
+    }
+
+    // where addr is in the rom or cartridge ram, the offset into the code data log's rom or ram
+    fn code_data_log_offset(&self, addr: u16) -> Option<(bool, usize)> {
+        match addr {
+            0x0000 ..= 0x7FFF => Some((true, self.rom_bank(addr)? * 0x4000 + (addr as usize & 0x3FFF))),
+            0xA000 ..= 0xBFFF => Some((false, self.cartridge.ram_offset(addr)?)),
+            _ => None,
+        }
+    }
+
+    fn log_usage(&self, addr: u16, flags: u8) {
+        let Some((rom, offset)) = self.code_data_log_offset(addr) else { return };
+        let mut log = self.code_data_log.borrow_mut();
+        let logged = if rom { log.rom.get_mut(offset) } else { log.ram.get_mut(offset) };
+        if let Some(logged) = logged {
+            *logged |= flags;
+        }
+    }
+
+    // what the code data log knows about the byte at addr, 0 outside of the cartridge
+    pub fn code_data_flags(&self, addr: u16) -> u8 {
+        let Some((rom, offset)) = self.code_data_log_offset(addr) else { return 0x00 };
+        let log = self.code_data_log.borrow();
+        let logged = if rom { log.rom.get(offset) } else { log.ram.get(offset) };
+        logged.copied().unwrap_or(0x00)
+    }
+
+    // a read seen by watchpoints and the code data log
+    fn read_logged(&self, addr: u16, flags: u8) -> u8 {
+        let data = self.peek8(addr);
+        self.watchpoints.check(addr, data, false);
+        self.log_usage(addr, flags);
+        data
+    }
+
+    // https://gbdev.io/pandocs/Memory_Map.html
+    // The banks that can be seen at addr and the one mapped there now, None where there's only one.
+    // For looking at the banks that aren't mapped in, with peek_bank and poke_bank
+    pub fn banks(&self, addr: u16) -> Option<(RangeInclusive<usize>, usize)> {
+        let rom_banks = self.cartridge.rom().len() / cartridge::ROM_BANK_SIZE;
+        let ram_banks = self.cartridge.ram().len() / cartridge::RAM_BANK_SIZE;
+        match addr {
+            0x4000 ..= 0x7FFF if rom_banks > 2 => Some((1 ..= rom_banks - 1, self.cartridge.rom_bank(addr))),
+            0x8000 ..= 0x9FFF if self.cgb_mode => Some((0 ..= 1, (self.gpu.read8(0xFF4F) & 0x01) as usize)),
+            0xA000 ..= 0xBFFF if ram_banks > 1 => {
+                let mapped = self.cartridge.ram_offset(addr).map_or(0, |offset| offset / cartridge::RAM_BANK_SIZE);
+                Some((0 ..= ram_banks - 1, mapped))
+            }
+            0xD000 ..= 0xDFFF if self.cgb_mode => Some((1 ..= 7, self.work_ram_d000_bank() + 1)),
+            _ => None,
+        }
+    }
+
+    // reads addr in a bank that may not be mapped in, addresses that aren't banked read as usual
+    pub fn peek_bank(&self, addr: u16, bank: usize) -> u8 {
+        let data = match addr {
+            0x4000 ..= 0x7FFF => self.cartridge.rom().get(bank * cartridge::ROM_BANK_SIZE + (addr - 0x4000) as usize),
+            0x8000 ..= 0x9FFF => self.gpu.vram.get(bank * gpu::VRAM_SIZE + (addr - 0x8000) as usize),
+            0xA000 ..= 0xBFFF => self.cartridge.ram().get(bank * cartridge::RAM_BANK_SIZE + (addr - 0xA000) as usize),
+            0xD000 ..= 0xDFFF => self.work_ram_d000.get(bank.wrapping_sub(1)).map(|ram| &ram[(addr - 0xD000) as usize]),
+            _ => return self.peek8(addr),
+        };
+        data.copied().unwrap_or(0xFF)
+    }
+
+    // writes addr in a bank that may not be mapped in, the rom can't be written
+    pub fn poke_bank(&mut self, addr: u16, bank: usize, data: u8) {
+        let byte = match addr {
+            0x0000 ..= 0x7FFF => None,
+            0x8000 ..= 0x9FFF => self.gpu.vram.get_mut(bank * gpu::VRAM_SIZE + (addr - 0x8000) as usize),
+            0xA000 ..= 0xBFFF => self.cartridge.ram_mut().get_mut(bank * cartridge::RAM_BANK_SIZE + (addr - 0xA000) as usize),
+            0xD000 ..= 0xDFFF => self.work_ram_d000.get_mut(bank.wrapping_sub(1)).map(|ram| &mut ram[(addr - 0xD000) as usize]),
+            _ => return self.write8(addr, data),
+        };
+        if let Some(byte) = byte {
+            *byte = data;
+        }
+    }
+
+    // SVBK keeps the value written, but bank 0 maps bank 1
+    fn work_ram_d000_bank(&self) -> usize {
+        std::cmp::max(self.work_ram_bank as usize, 1) - 1
+    }
+
+    // https://gbdev.io/pandocs/OAM_DMA_Transfer.html
+    // TODO - the transfer is instant here, real hardware takes 160 machine cycles and blocks most of the bus
+    fn oam_dma_transfer(&mut self, source_high_byte: u8) {
+        let source = (source_high_byte as u16) << 8;
+        for i in 0 .. gpu::OAM_SIZE as u16 {
+            let data = self.peek8(source + i);
+            self.log_usage(source + i, code_data_log::DMA_SOURCE);
+            self.gpu.oam[i as usize] = data;
+        }
+        self.oam_dma_source = source_high_byte;
     }
 }
 
 impl Memory for MemoryManagementUnit {
     fn read8(&self, addr: u16) -> u8 {
+        self.read_logged(addr, code_data_log::DATA)
+    }
+
+    fn fetch8(&self, addr: u16, opcode: bool) -> u8 {
+        self.read_logged(addr, if opcode { code_data_log::EXECUTED } else { code_data_log::OPERAND })
+    }
+
+    fn peek8(&self, addr: u16) -> u8 {
         match addr {
-            0x0000 ..= 0x7FFF => self.cartridge.read8(addr),
+            0x0000 ..= 0x7FFF => self.read_boot_rom(addr).unwrap_or_else(|| self.cartridge.read8(addr)),
+            0x8000 ..= 0x9FFF => self.gpu.read8(addr),
+            0xA000 ..= 0xBFFF => self.cartridge.read8(addr),
             0xC000 ..= 0xCFFF => self.work_ram_c000[(addr - 0xC000) as usize],
-            0xD000 ..= 0xDFFF => self.work_ram_d000[(addr - 0xD000) as usize],
+            0xD000 ..= 0xDFFF => self.work_ram_d000[self.work_ram_d000_bank()][(addr - 0xD000) as usize],
             // Mirror of C000-DDFF
             0xE000 ..= 0xEFFF => self.work_ram_c000[(addr - 0xE000) as usize],
-            0xF000 ..= 0xFDFF => self.work_ram_d000[(addr - 0xF000) as usize],
+            0xF000 ..= 0xFDFF => self.work_ram_d000[self.work_ram_d000_bank()][(addr - 0xF000) as usize],
+            0xFE00 ..= 0xFE9F => self.gpu.read8(addr),
+            0xFEA0 ..= 0xFEFF => 0xFF, // not usable
             0xFF00 => self.joypad.read8(addr),
             0xFF01 | 0xFF02 => self.serial_cable.read8(addr),
             0xFF04 ..= 0xFF07 => self.timer.read8(addr),
+            0xFF0F => self.interrupts.borrow().read8(addr),
             0xFF10 ..= 0xFF26 => self.apu.read8(addr),
-            0xFF30 ..= 0xFF3F => panic!("Waveform RAM not implemented!"),
+            0xFF30 ..= 0xFF3F => self.apu.read8(addr),
+            0xFF46 => self.oam_dma_source,
             0xFF40 ..= 0xFF4B => self.gpu.read8(addr),
-            // $FF4F       VRAM Bank Select
-            // $FF50       Set to non-zero to disable boot ROM
-            // $FF51 $FF55 VRAM DMA
-            // $FF68 $FF69 Palettes
-            // $FF70       WRAM Bank Select
+            0xFF4C => 0xFF,
+            0xFF4D if self.cgb_mode => {
+                (if self.double_speed { 0x80 } else { 0x00 }) | 0x7E | if self.speed_switch_armed { 0x01 } else { 0x00 }
+            }
+            0xFF4F => self.gpu.read8(addr),
+            0xFF50 => 0xFF,
+            0xFF51 ..= 0xFF55 if self.cgb_mode => self.hdma.read8(addr),
+            0xFF51 ..= 0xFF55 => 0xFF,
+            0xFF68 ..= 0xFF6C => self.gpu.read8(addr),
+            0xFF70 if self.cgb_mode => 0xF8 | self.work_ram_bank,
+            0xFF4D | 0xFF70 => 0xFF,
             0xFF80 ..= 0xFFFE => self.hram[(addr - 0xFF80) as usize],
             0xFFFF => self.interrupt_enable,
-            _ => panic!("unimplemented address read on MemoryManagementUnit {:#04x}", addr)
+            _ => 0xFF, // unused io registers
         }
     }
 
     fn write8(&mut self, addr: u16, data: u8) {
+        self.watchpoints.check(addr, data, true);
         match addr {
             0x0000..=0x7fff => self.cartridge.write8(addr, data),
+            0x8000 ..= 0x9FFF => self.gpu.write8(addr, data),
+            0xA000 ..= 0xBFFF => self.cartridge.write8(addr, data),
             0xC000 ..= 0xCFFF => { self.work_ram_c000[(addr - 0xC000) as usize] = data },
-            0xD000 ..= 0xDFFF => { self.work_ram_d000[(addr - 0xD000) as usize] = data },
+            0xD000 ..= 0xDFFF => { self.work_ram_d000[self.work_ram_d000_bank()][(addr - 0xD000) as usize] = data },
             // Mirror of C000-DDFF
             0xE000 ..= 0xEFFF => { self.work_ram_c000[(addr - 0xE000) as usize] = data },
-            0xF000 ..= 0xFDFF => { self.work_ram_d000[(addr - 0xF000) as usize] = data },
-            0xFF00 => self.joypad.write8(addr, data),
+            0xF000 ..= 0xFDFF => { self.work_ram_d000[self.work_ram_d000_bank()][(addr - 0xF000) as usize] = data },
+            0xFE00 ..= 0xFE9F => self.gpu.write8(addr, data),
+            0xFEA0 ..= 0xFEFF => {}, // not usable
+            0xFF00 => self.write_joypad(data),
             0xFF01 | 0xFF02 => self.serial_cable.write8(addr, data),
             0xFF04 ..= 0xFF07 => self.timer.write8(addr, data),
+            0xFF0F => self.interrupts.borrow_mut().write8(addr, data),
             0xFF10 ..= 0xFF26 => self.apu.write8(addr, data),
-            0xFF30 ..= 0xFF3F => panic!("Waveform RAM not implemented!"),
+            0xFF30 ..= 0xFF3F => self.apu.write8(addr, data),
+            0xFF46 => self.oam_dma_transfer(data),
             0xFF40 ..= 0xFF4B => self.gpu.write8(addr, data),
-            // $FF4F       VRAM Bank Select
-            // $FF50       Set to non-zero to disable boot ROM
-            // $FF51 $FF55 VRAM DMA
-            // $FF68 $FF69 Palettes
-            // $FF70       WRAM Bank Select
+            // KEY0, only writable by the CGB boot rom to pick DMG compatibility mode
+            // TODO - compatibility mode is chosen from the cartridge header instead
+            0xFF4C => {},
+            0xFF4D if self.cgb_mode => self.speed_switch_armed = data & 0x01 != 0,
+            0xFF4F => self.gpu.write8(addr, data),
+            // Set to non-zero to disable boot ROM, it can't be mapped back in
+            0xFF50 if data != 0 => self.boot_rom_mapped = false,
+            0xFF50 => {},
+            0xFF51 ..= 0xFF55 if self.cgb_mode => self.write_hdma(addr, data),
+            0xFF51 ..= 0xFF55 => {},
+            0xFF68 ..= 0xFF6C => self.gpu.write8(addr, data),
+            // writing bank 0 selects bank 1
+            0xFF70 if self.cgb_mode => self.work_ram_bank = data & 0x07,
+            0xFF4D | 0xFF70 => {},
             0xFF80 ..= 0xFFFE => self.hram[(addr - 0xFF80) as usize] = data,
             0xFFFF => self.interrupt_enable = data,
-             _ => panic!("unimplemented address write on MemoryManagementUnit {:#04x}", addr),
+            _ => {}, // unused io registers
         }
     }
  }
+
+// the cartridge, boot rom and model are fixed when the mmu is created, the main board checks they match
+impl SaveState for MemoryManagementUnit {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.cartridge.save_state(writer);
+        self.apu.save_state(writer);
+        self.gpu.save_state(writer);
+        self.joypad.save_state(writer);
+        self.serial_cable.save_state(writer);
+        self.timer.save_state(writer);
+        self.interrupts.borrow().save_state(writer);
+        self.hdma.save_state(writer);
+        if let Some(sgb) = &self.sgb {
+            sgb.save_state(writer);
+        }
+        writer.write_bytes(&self.work_ram_c000);
+        for bank in self.work_ram_d000.iter() {
+            writer.write_bytes(bank);
+        }
+        writer.write_bytes(&self.hram);
+        writer.write_u8(self.interrupt_enable);
+        writer.write_u8(self.oam_dma_source);
+        writer.write_bool(self.boot_rom_mapped);
+        writer.write_u8(self.work_ram_bank);
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.speed_switch_armed);
+        writer.write_u32(self.dma_stall_cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.cartridge.load_state(reader)?;
+        self.apu.load_state(reader)?;
+        self.gpu.load_state(reader)?;
+        self.joypad.load_state(reader)?;
+        self.serial_cable.load_state(reader)?;
+        self.timer.load_state(reader)?;
+        self.interrupts.borrow_mut().load_state(reader)?;
+        self.hdma.load_state(reader)?;
+        if let Some(sgb) = &mut self.sgb {
+            sgb.load_state(reader)?;
+        }
+        reader.read_bytes(&mut self.work_ram_c000)?;
+        for bank in self.work_ram_d000.iter_mut() {
+            reader.read_bytes(bank)?;
+        }
+        reader.read_bytes(&mut self.hram)?;
+        self.interrupt_enable = reader.read_u8()?;
+        self.oam_dma_source = reader.read_u8()?;
+        self.boot_rom_mapped = reader.read_bool()? && self.boot_rom.is_some();
+        self.work_ram_bank = reader.read_u8()? & 0x07;
+        self.double_speed = reader.read_bool()?;
+        self.speed_switch_armed = reader.read_bool()?;
+        self.dma_stall_cycles = reader.read_u32()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+use super::cartridge::Cartridge;
+
+// https://gbdev.io/pandocs/Power_Up_Sequence.html
+// the console being emulated. Each one leaves a slightly different state behind after its boot rom
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Model {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    Agb,
+}
+
+pub const MODELS: [Model; 7] = [Model::Dmg0, Model::Dmg, Model::Mgb, Model::Sgb, Model::Sgb2, Model::Cgb, Model::Agb];
+pub const MODEL_NAMES: [&str; 7] = ["dmg0", "dmg", "mgb", "sgb", "sgb2", "cgb", "agb"];
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Model> {
+        let name = name.to_lowercase();
+        MODEL_NAMES.iter().position(|&model_name| model_name == name).map(|index| MODELS[index])
+    }
+
+    pub fn name(&self) -> &'static str {
+        MODEL_NAMES[*self as usize]
+    }
+
+    // the best console for a game, from the CGB and SGB flags of its header
+    pub fn detect(cartridge: &dyn Cartridge) -> Model {
+        if cartridge.is_cgb() {
+            Model::Cgb
+        } else if cartridge.is_sgb() {
+            Model::Sgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    // has the CGB hardware, even when running an old game in DMG compatibility mode
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+
+    // https://gbdev.io/pandocs/Power_Up_Sequence.html#hardware-registers
+    // the internal 16 bit counter behind DIV when the boot rom hands over to the game at 0x0100.
+    // The boot roms take a different amount of time on each model
+    pub fn div_counter(&self) -> u16 {
+        match self {
+            Model::Dmg0 => 0x182C,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            Model::Sgb | Model::Sgb2 => 0xD850,
+            Model::Cgb | Model::Agb => 0x1EA0,
+        }
+    }
+}
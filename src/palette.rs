@@ -1,3 +1,4 @@
+#[derive(Copy, Clone, PartialEq)]
 pub enum PaletteDataColor {
     White,
     LightGray,
@@ -5,6 +6,99 @@ pub enum PaletteDataColor {
     Black,
 }
 
+// the green tinted shades of the original DMG screen, indexed by PaletteDataColor
+pub const DMG_SHADES: [[u8; 3]; 4] = [
+    [0x9B, 0xBC, 0x0F],
+    [0x8B, 0xAC, 0x0F],
+    [0x30, 0x62, 0x30],
+    [0x0F, 0x38, 0x0F],
+];
+
+// evenly spaced greys, what reference screenshots of test roms are taken with
+pub const GRAYSCALE_SHADES: [[u8; 3]; 4] = [
+    [0xFF, 0xFF, 0xFF],
+    [0xAA, 0xAA, 0xAA],
+    [0x55, 0x55, 0x55],
+    [0x00, 0x00, 0x00],
+];
+
+pub const PALETTE_CONFIG_FILE: &str = "rustyboy_palettes.cfg";
+
+// a named mapping from the 4 shades to RGB
+#[derive(Clone)]
+pub struct ShadePalette {
+    pub name: String,
+    pub shades: [[u8; 3]; 4],
+}
+
+pub fn builtin_palettes() -> Vec<ShadePalette> {
+    vec![
+        ShadePalette { name: "DMG".to_string(), shades: DMG_SHADES },
+        ShadePalette {
+            name: "Pocket".to_string(),
+            shades: [[0xC4, 0xCF, 0xA1], [0x8B, 0x95, 0x6D], [0x4D, 0x53, 0x3C], [0x1F, 0x1F, 0x1F]],
+        },
+        ShadePalette {
+            name: "Light".to_string(),
+            shades: [[0x00, 0xB5, 0x81], [0x00, 0x9A, 0x71], [0x00, 0x69, 0x4A], [0x00, 0x4F, 0x3B]],
+        },
+        ShadePalette { name: "High contrast".to_string(), shades: GRAYSCALE_SHADES },
+    ]
+}
+
+// user palettes are stored one per line as: name=RRGGBB,RRGGBB,RRGGBB,RRGGBB
+pub fn load_user_palettes(filepath: &str) -> Vec<ShadePalette> {
+    let contents = match std::fs::read_to_string(filepath) {
+        Ok(contents) => contents,
+        Err(_) => return Vec::new(), // no palettes saved yet
+    };
+    contents.lines().filter_map(parse_palette_line).collect()
+}
+
+fn parse_palette_line(line: &str) -> Option<ShadePalette> {
+    // names may contain '=', the colors never do
+    let (name, colors) = line.trim().rsplit_once('=')?;
+    let mut shades = [[0u8; 3]; 4];
+    let mut colors = colors.split(',');
+    for shade in shades.iter_mut() {
+        let rgb = u32::from_str_radix(colors.next()?.trim(), 16).ok()?;
+        *shade = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8];
+    }
+    Some(ShadePalette { name: name.trim().to_string(), shades })
+}
+
+pub fn save_user_palettes(filepath: &str, palettes: &[ShadePalette]) -> std::io::Result<()> {
+    let mut contents = String::new();
+    for palette in palettes {
+        let colors: Vec<String> = palette.shades.iter()
+            .map(|rgb| format!("{:02X}{:02X}{:02X}", rgb[0], rgb[1], rgb[2]))
+            .collect();
+        contents.push_str(&format!("{}={}\n", palette.name, colors.join(",")));
+    }
+    std::fs::write(filepath, contents)
+}
+
+// which palette register a pixel was drawn with
+#[derive(Copy, Clone, PartialEq)]
+pub enum PaletteLayer {
+    Background = 0,
+    Obj0 = 1,
+    Obj1 = 2,
+}
+
+pub const PALETTE_LAYER_NAMES: [&str; 3] = ["BG", "OBJ0", "OBJ1"];
+
+// the RGB shades used for each layer. Giving the layers different palettes colourises DMG games.
+pub struct ShadeColors {
+    pub layers: [[[u8; 3]; 4]; 3],
+}
+
+impl ShadeColors {
+    pub fn init(shades: [[u8; 3]; 4]) -> Self {
+        Self { layers: [shades; 3] }
+    }
+}
+
 pub struct PaletteData {
     raw: u8,
     pub index_0_color: PaletteDataColor,
@@ -41,4 +135,15 @@ impl PaletteData {
     pub fn read(&self) -> u8 {
         self.raw
     }
+
+    // maps a 2bit color index from tile data to its shade
+    pub fn color(&self, color_index: u8) -> PaletteDataColor {
+        match color_index {
+            0 => self.index_0_color,
+            1 => self.index_1_color,
+            2 => self.index_2_color,
+            3 => self.index_3_color,
+            _ => panic!("Invalid 2bit color index: {:#02X}", color_index),
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+
+pub const DEFAULT_INTERVAL_FRAMES: u32 = 2;
+pub const DEFAULT_MEMORY_BUDGET: usize = 64 * 1024 * 1024;
+
+// History of save states for scrubbing backwards. Only the newest snapshot is kept whole, every
+// older one is stored as the difference to the snapshot after it, so stepping back decodes one
+// delta and dropping the oldest snapshot to stay in budget costs nothing.
+pub struct Rewind {
+    pub enabled: bool,
+    // a snapshot is taken every this many frames
+    pub interval_frames: u32,
+    // bytes all the snapshots together may use
+    pub memory_budget: usize,
+    frames_since_snapshot: u32,
+    newest: Option<Vec<u8>>,
+    // oldest first, each one turns the snapshot after it back into itself
+    deltas: VecDeque<Vec<u8>>,
+    deltas_size: usize,
+    // the newest snapshot was already restored by the last step back
+    at_newest: bool,
+}
+
+impl Rewind {
+    pub fn init() -> Self {
+        Self {
+            enabled: true,
+            interval_frames: DEFAULT_INTERVAL_FRAMES,
+            memory_budget: DEFAULT_MEMORY_BUDGET,
+            frames_since_snapshot: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+            at_newest: false,
+        }
+    }
+
+    // true when a snapshot is due after this frame
+    pub fn frame_finished(&mut self) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        self.frames_since_snapshot += 1;
+        if self.frames_since_snapshot < self.interval_frames.max(1) {
+            return false;
+        }
+        self.frames_since_snapshot = 0;
+        true
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.newest.take() {
+            let delta = encode_delta(&state, &previous);
+            self.deltas_size += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.newest = Some(state);
+        self.at_newest = false;
+        while self.memory_used() > self.memory_budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.deltas_size -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // the state to go back to, None once the history is used up
+    pub fn step_back(&mut self) -> Option<Vec<u8>> {
+        self.frames_since_snapshot = 0;
+        if !self.at_newest {
+            self.at_newest = true;
+            return self.newest.clone();
+        }
+        let delta = self.deltas.pop_back()?;
+        self.deltas_size -= delta.len();
+        let previous = decode_delta(&delta, self.newest.as_ref()?);
+        self.newest = Some(previous.clone());
+        Some(previous)
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.deltas_size = 0;
+        self.frames_since_snapshot = 0;
+        self.at_newest = false;
+    }
+
+    pub fn snapshot_count(&self) -> usize {
+        self.deltas.len() + if self.newest.is_some() { 1 } else { 0 }
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.deltas_size + self.newest.as_ref().map_or(0, |newest| newest.len())
+    }
+}
+
+// Most of a state is unchanged from one snapshot to the next, so the xor of the two is mostly
+// zeroes. It's stored as pairs of (zero run length, literal length) followed by the literal bytes.
+// A state of another size is kept whole behind a 0xFF marker.
+fn encode_delta(reference: &[u8], state: &[u8]) -> Vec<u8> {
+    if reference.len() != state.len() {
+        let mut delta = vec![0xFF];
+        delta.extend_from_slice(state);
+        return delta;
+    }
+    let mut delta = vec![0x00];
+    let mut position = 0;
+    while position < state.len() {
+        let zeroes_start = position;
+        while position < state.len() && state[position] == reference[position] {
+            position += 1;
+        }
+        let literal_start = position;
+        while position < state.len() && state[position] != reference[position] {
+            position += 1;
+        }
+        write_length(&mut delta, literal_start - zeroes_start);
+        write_length(&mut delta, position - literal_start);
+        delta.extend(state[literal_start .. position].iter().zip(&reference[literal_start .. position]).map(|(a, b)| a ^ b));
+    }
+    delta
+}
+
+fn decode_delta(delta: &[u8], reference: &[u8]) -> Vec<u8> {
+    if delta[0] == 0xFF {
+        return delta[1 ..].to_vec();
+    }
+    let mut state = reference.to_vec();
+    let mut offset = 1;
+    let mut position = 0;
+    while offset < delta.len() {
+        position += read_length(delta, &mut offset);
+        let literal_length = read_length(delta, &mut offset);
+        for i in 0 .. literal_length {
+            state[position + i] ^= delta[offset + i];
+        }
+        offset += literal_length;
+        position += literal_length;
+    }
+    state
+}
+
+// LEB128, 7 bits at a time
+fn write_length(data: &mut Vec<u8>, mut length: usize) {
+    loop {
+        let byte = (length & 0x7F) as u8;
+        length >>= 7;
+        if length == 0 {
+            data.push(byte);
+            return;
+        }
+        data.push(byte | 0x80);
+    }
+}
+
+fn read_length(data: &[u8], offset: &mut usize) -> usize {
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*offset];
+        *offset += 1;
+        length |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return length;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(reference: &[u8], state: &[u8]) -> Vec<u8> {
+        let delta = encode_delta(reference, state);
+        assert_eq!(decode_delta(&delta, reference), state);
+        delta
+    }
+
+    #[test]
+    fn identical_snapshots() {
+        let state: Vec<u8> = (0 .. 300).map(|i| i as u8).collect();
+        let delta = round_trip(&state, &state);
+        // marker, then a zero run of 300 and no literal bytes
+        assert_eq!(delta, vec![0x00, 0xAC, 0x02, 0x00]);
+    }
+
+    #[test]
+    fn different_lengths() {
+        let delta = round_trip(&[0x01, 0x02, 0x03], &[0x04, 0x05]);
+        assert_eq!(delta, vec![0xFF, 0x04, 0x05]);
+        round_trip(&[0x01], &[]);
+        round_trip(&[], &[0x01, 0x02]);
+    }
+
+    #[test]
+    fn runs_longer_than_one_length_byte() {
+        let reference = vec![0x00; 100_000];
+        let mut state = reference.clone();
+        // a literal run of 200 bytes after 1000 unchanged ones, then a single byte at the very end
+        state[1000 .. 1200].iter_mut().for_each(|byte| *byte = 0x5A);
+        state[99_999] = 0x01;
+        let delta = round_trip(&reference, &state);
+        assert_eq!(&delta[.. 5], &[0x00, 0xE8, 0x07, 0xC8, 0x01]);
+        assert!(delta.len() < 220);
+    }
+
+    #[test]
+    fn lengths_encode_as_leb128() {
+        for length in [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, usize::MAX >> 1] {
+            let mut data = Vec::new();
+            write_length(&mut data, length);
+            let mut offset = 0;
+            assert_eq!(read_length(&data, &mut offset), length);
+            assert_eq!(offset, data.len());
+        }
+    }
+}
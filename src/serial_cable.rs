@@ -1,14 +1,68 @@
 use std::{rc::Rc, cell::RefCell};
-use super::interrupts::Interrupts;
+use super::interrupts::{Interrupt, Interrupts};
 use super::memory::Memory;
+use super::save_state::{SaveState, StateReader, StateWriter};
 
+// 8192 Hz with the internal clock, one bit every 512 cpu cycles
+const CYCLES_PER_BIT: u32 = 512;
+
+// https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+// Nothing is ever plugged in, so the bits shifted in are all ones. Transfers waiting on an
+// external clock never finish.
 pub struct SerialCable {
-    _interrupts: Rc<RefCell<Interrupts>>,
+    interrupts: Rc<RefCell<Interrupts>>,
+    // SB
+    pub data: u8,
+    // SC
+    pub control: u8,
+    bits_left: u8,
+    cycles_to_next_bit: u32,
+    // every byte sent, test roms report their results this way
+    pub output: Vec<u8>,
 }
 
 impl SerialCable {
-    pub fn init(_interrupts: Rc<RefCell<Interrupts>>) -> Self {
-        SerialCable { _interrupts }
+    pub fn init(interrupts: Rc<RefCell<Interrupts>>) -> Self {
+        SerialCable {
+            interrupts,
+            data: 0x00,
+            control: 0x7E,
+            bits_left: 0,
+            cycles_to_next_bit: 0,
+            output: Vec::new(),
+        }
+    }
+
+    fn write_control(&mut self, data: u8) {
+        self.control = 0x7E | data;
+        let internal_clock = data & 0x01 != 0;
+        if data & 0x80 != 0 && internal_clock {
+            self.output.push(self.data);
+            self.bits_left = 8;
+            self.cycles_to_next_bit = CYCLES_PER_BIT;
+        }
+    }
+
+    pub fn run_cycles(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while self.bits_left > 0 && cycles > 0 {
+            let step = std::cmp::min(cycles, self.cycles_to_next_bit);
+            cycles -= step;
+            self.cycles_to_next_bit -= step;
+            if self.cycles_to_next_bit == 0 {
+                self.data = (self.data << 1) | 0x01;
+                self.bits_left -= 1;
+                self.cycles_to_next_bit = CYCLES_PER_BIT;
+                if self.bits_left == 0 {
+                    self.control &= 0x7F;
+                    self.interrupts.borrow_mut().request(Interrupt::Serial);
+                }
+            }
+        }
+    }
+
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.output).to_string()
     }
 }
 
@@ -16,14 +70,36 @@ impl SerialCable {
 impl Memory for SerialCable {
     fn read8(&self, addr: u16) -> u8 {
         match addr {
-           _ => panic!("unimplemented address read on SerialCable {:#04x}", addr)
+            0xFF01 => self.data,
+            0xFF02 => self.control,
+            _ => panic!("unimplemented address read on SerialCable {:#04x}", addr)
         }
     }
 
     fn write8(&mut self, addr: u16, data: u8) {
         match addr {
+            0xFF01 => self.data = data,
+            0xFF02 => self.write_control(data),
             _ => panic!("unimplemented address write on SerialCable {:#04x}, value: {:#02x}", addr, data)
         }
     }
 }
- 
\ No newline at end of file
+
+
+// the output log isn't part of the console, it stays as it is
+impl SaveState for SerialCable {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.data);
+        writer.write_u8(self.control);
+        writer.write_u8(self.bits_left);
+        writer.write_u32(self.cycles_to_next_bit);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.data = reader.read_u8()?;
+        self.control = 0x7E | reader.read_u8()?;
+        self.bits_left = reader.read_u8()? & 0x0F;
+        self.cycles_to_next_bit = reader.read_u32()?;
+        Ok(())
+    }
+}
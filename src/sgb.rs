@@ -0,0 +1,549 @@
+use super::gpu;
+use super::gpu::rgb555_to_rgb888;
+use super::save_state::{SaveState, StateReader, StateWriter};
+
+// https://gbdev.io/pandocs/SGB_Functions.html
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 224;
+// where the game boy screen sits inside the border
+pub const SCREEN_X: usize = 48;
+pub const SCREEN_Y: usize = 40;
+const CELLS_X: usize = gpu::WIDTH / 8;
+const CELLS_Y: usize = gpu::HEIGHT / 8;
+const PACKET_SIZE: usize = 16;
+const MAX_PACKETS: usize = 7;
+pub const TRANSFER_SIZE: usize = 4096;
+const SYSTEM_PALETTES: usize = 512;
+const BORDER_TILES: usize = 256;
+const BORDER_TILE_SIZE: usize = 32; // SNES 4bpp tiles
+const BORDER_MAP_SIZE: usize = 32 * 28;
+
+// https://gbdev.io/pandocs/SGB_Command_Summary.html
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const MASK_EN: u8 = 0x17;
+
+// https://gbdev.io/pandocs/SGB_Command_Mask.html
+#[derive(Copy, Clone, PartialEq)]
+pub enum Mask {
+    Cancel = 0,
+    Freeze = 1,
+    Black = 2,
+    Color0 = 3,
+}
+
+// data the SGB copies out of the game boy's vram, see Gpu::sgb_transfer_data
+#[derive(Copy, Clone, PartialEq)]
+pub enum VramTransfer {
+    Palettes,
+    BorderTiles { upper_half: bool },
+    BorderMap,
+}
+
+pub struct Sgb {
+    // packet reception through P1
+    command: [u8; PACKET_SIZE * MAX_PACKETS],
+    bit_index: usize,
+    ready_for_pulse: bool,
+    ready_for_write: bool,
+    ready_for_stop: bool,
+    // https://gbdev.io/pandocs/SGB_Command_Multiplayer.html
+    pub player_count: u8,
+    pub current_player: u8,
+    player_lock: bool,
+    // four palettes of four RGB555 colors, color 0 is shared by all of them
+    pub palettes: [[u16; 4]; 4],
+    pub system_palettes: Vec<[u16; 4]>,
+    // palette number for each 8x8 cell of the screen
+    pub attributes: [[u8; CELLS_X]; CELLS_Y],
+    pub border_tiles: Vec<u8>,
+    // 32x28 entries of tile index, palette and flip bits
+    pub border_map: Vec<u16>,
+    // border palettes 4-7, 16 colors each
+    pub border_palettes: [[u16; 16]; 4],
+    pub mask: Mask,
+    frozen_screen: [[u8; gpu::WIDTH]; gpu::HEIGHT],
+    pending_transfer: Option<VramTransfer>,
+}
+
+impl Sgb {
+    pub fn init() -> Self {
+        Self {
+            command: [0x0; PACKET_SIZE * MAX_PACKETS],
+            bit_index: 0,
+            ready_for_pulse: false,
+            ready_for_write: false,
+            ready_for_stop: false,
+            player_count: 1,
+            current_player: 0,
+            player_lock: false,
+            palettes: [[0x7FFF, 0x5294, 0x294A, 0x0000]; 4],
+            system_palettes: vec![[0x0; 4]; SYSTEM_PALETTES],
+            attributes: [[0x0; CELLS_X]; CELLS_Y],
+            border_tiles: vec![0x0; BORDER_TILES * BORDER_TILE_SIZE],
+            border_map: vec![0x0; BORDER_MAP_SIZE],
+            border_palettes: [[0x0; 16]; 4],
+            mask: Mask::Cancel,
+            frozen_screen: [[0x0; gpu::WIDTH]; gpu::HEIGHT],
+            pending_transfer: None,
+        }
+    }
+
+    // https://gbdev.io/pandocs/SGB_Command_Packet.html
+    // packets are sent one bit at a time: a pulse with P14 low is a 0, with P15 low a 1,
+    // and each bit is followed by both lines going high. Both lines low starts a packet.
+    pub fn write_p1(&mut self, data: u8, screen: &[[u8; gpu::WIDTH]; gpu::HEIGHT]) {
+        if data & 0x20 == 0 {
+            self.player_lock = false;
+        }
+        match (data >> 4) & 0x03 {
+            0x03 => {
+                self.ready_for_pulse = true;
+                if self.player_count > 1 && !self.player_lock {
+                    self.current_player = (self.current_player + 1) % self.player_count;
+                    self.player_lock = true;
+                }
+            }
+            0x00 => {
+                // reset pulse
+                if !self.ready_for_pulse {
+                    return;
+                }
+                self.ready_for_pulse = false;
+                self.ready_for_write = true;
+                if !self.bit_index.is_multiple_of(PACKET_SIZE * 8) || self.bit_index == 0 || self.ready_for_stop {
+                    self.bit_index = 0;
+                    self.command = [0x0; PACKET_SIZE * MAX_PACKETS];
+                    self.ready_for_stop = false;
+                }
+            }
+            bits => {
+                if !self.ready_for_pulse || !self.ready_for_write {
+                    return;
+                }
+                self.ready_for_pulse = false;
+                let bit = bits == 0x01; // P14 high and P15 low sends a 1
+                if self.ready_for_stop {
+                    // every packet ends in a 0 bit
+                    self.ready_for_stop = false;
+                    if bit || self.bit_index == 0 {
+                        self.ready_for_write = false;
+                        return;
+                    }
+                    let packets = std::cmp::max(self.command[0] & 0x07, 1) as usize;
+                    if self.bit_index >= packets * PACKET_SIZE * 8 {
+                        self.execute_command(screen);
+                        self.bit_index = 0;
+                        self.ready_for_write = false;
+                    }
+                    return;
+                }
+                if bit {
+                    self.command[self.bit_index / 8] |= 1 << (self.bit_index % 8);
+                }
+                self.bit_index += 1;
+                // the buffer holds the longest command, its last bit is always followed by a stop bit
+                if self.bit_index.is_multiple_of(PACKET_SIZE * 8) {
+                    self.ready_for_stop = true;
+                }
+            }
+        }
+    }
+
+    // the vram transfer requested by the last command, if any
+    pub fn take_pending_transfer(&mut self) -> Option<VramTransfer> {
+        self.pending_transfer.take()
+    }
+
+    pub fn complete_transfer(&mut self, transfer: VramTransfer, data: &[u8]) {
+        match transfer {
+            VramTransfer::Palettes => {
+                for (i, palette) in self.system_palettes.iter_mut().enumerate() {
+                    for (j, color) in palette.iter_mut().enumerate() {
+                        *color = read_color(data, i * 8 + j * 2);
+                    }
+                }
+            }
+            VramTransfer::BorderTiles { upper_half } => {
+                let offset = if upper_half { TRANSFER_SIZE } else { 0 };
+                self.border_tiles[offset .. offset + TRANSFER_SIZE].copy_from_slice(&data[.. TRANSFER_SIZE]);
+            }
+            VramTransfer::BorderMap => {
+                for (i, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = read_color(data, i * 2);
+                }
+                for (i, palette) in self.border_palettes.iter_mut().enumerate() {
+                    for (j, color) in palette.iter_mut().enumerate() {
+                        *color = read_color(data, 0x800 + i * 32 + j * 2);
+                    }
+                }
+            }
+        }
+    }
+
+    fn execute_command(&mut self, screen: &[[u8; gpu::WIDTH]; gpu::HEIGHT]) {
+        let command = self.command;
+        let code = command[0] >> 3;
+        rog::debugln!("SGB command {:#04X}", code);
+        match code {
+            PAL01 => self.set_palette_pair(0, 1, &command),
+            PAL23 => self.set_palette_pair(2, 3, &command),
+            PAL03 => self.set_palette_pair(0, 3, &command),
+            PAL12 => self.set_palette_pair(1, 2, &command),
+            ATTR_BLK => self.attribute_blocks(&command),
+            ATTR_LIN => self.attribute_lines(&command),
+            ATTR_DIV => self.attribute_divide(&command),
+            ATTR_CHR => self.attribute_characters(&command),
+            PAL_SET => {
+                for palette in 0 .. 4 {
+                    let id = (read_color(&command, 1 + palette * 2) & 0x1FF) as usize;
+                    self.palettes[palette] = self.system_palettes[id];
+                }
+                // color 0 of palette 0 is shared by all palettes
+                let shared_color = self.palettes[0][0];
+                for palette in self.palettes.iter_mut() {
+                    palette[0] = shared_color;
+                }
+                if command[9] & 0x40 != 0 {
+                    self.mask = Mask::Cancel;
+                }
+            }
+            PAL_TRN => self.pending_transfer = Some(VramTransfer::Palettes),
+            MLT_REQ => {
+                self.player_count = match command[1] & 0x03 {
+                    0x01 => 2,
+                    0x03 => 4,
+                    _ => 1,
+                };
+                self.current_player = 0;
+            }
+            CHR_TRN => self.pending_transfer = Some(VramTransfer::BorderTiles { upper_half: command[1] & 0x01 != 0 }),
+            PCT_TRN => self.pending_transfer = Some(VramTransfer::BorderMap),
+            MASK_EN => {
+                self.mask = match command[1] & 0x03 {
+                    0x01 => Mask::Freeze,
+                    0x02 => Mask::Black,
+                    0x03 => Mask::Color0,
+                    _ => Mask::Cancel,
+                };
+                if self.mask == Mask::Freeze {
+                    self.frozen_screen = *screen;
+                }
+            }
+            _ => rog::debugln!("unimplemented SGB command {:#04X}", code),
+        }
+    }
+
+    // https://gbdev.io/pandocs/SGB_Command_Palettes.html
+    fn set_palette_pair(&mut self, first: usize, second: usize, command: &[u8]) {
+        let shared_color = read_color(command, 1);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = shared_color;
+        }
+        for i in 1 .. 4 {
+            self.palettes[first][i] = read_color(command, 1 + i * 2);
+            self.palettes[second][i] = read_color(command, 7 + i * 2);
+        }
+    }
+
+    // https://gbdev.io/pandocs/SGB_Command_Attribute.html
+    fn attribute_blocks(&mut self, command: &[u8]) {
+        let datasets = (command[1] & 0x1F) as usize;
+        for dataset in command[2 ..].chunks(6).take(datasets) {
+            if dataset.len() < 6 {
+                break;
+            }
+            let control = dataset[0] & 0x07;
+            let inside_palette = dataset[1] & 0x03;
+            let border_palette = (dataset[1] >> 2) & 0x03;
+            let outside_palette = (dataset[1] >> 4) & 0x03;
+            // when only the inside or only the outside is changed, the border goes along with it
+            let (change_border, border_palette) = match control {
+                0x01 => (true, inside_palette),
+                0x04 => (true, outside_palette),
+                _ => (control & 0x02 != 0, border_palette),
+            };
+            let (x1, y1, x2, y2) = (dataset[2] as usize, dataset[3] as usize, dataset[4] as usize, dataset[5] as usize);
+            for y in 0 .. CELLS_Y {
+                for x in 0 .. CELLS_X {
+                    let inside_or_border = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_border = inside_or_border && (x == x1 || x == x2 || y == y1 || y == y2);
+                    if on_border {
+                        if change_border {
+                            self.attributes[y][x] = border_palette;
+                        }
+                    } else if inside_or_border {
+                        if control & 0x01 != 0 {
+                            self.attributes[y][x] = inside_palette;
+                        }
+                    } else if control & 0x04 != 0 {
+                        self.attributes[y][x] = outside_palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attribute_lines(&mut self, command: &[u8]) {
+        let datasets = command[1] as usize;
+        for &dataset in command[2 ..].iter().take(datasets) {
+            let line = (dataset & 0x1F) as usize;
+            let palette = (dataset >> 5) & 0x03;
+            if dataset & 0x80 != 0 {
+                // horizontal line
+                if line < CELLS_Y {
+                    self.attributes[line] = [palette; CELLS_X];
+                }
+            } else if line < CELLS_X {
+                for row in self.attributes.iter_mut() {
+                    row[line] = palette;
+                }
+            }
+        }
+    }
+
+    fn attribute_divide(&mut self, command: &[u8]) {
+        let after_palette = command[1] & 0x03;
+        let before_palette = (command[1] >> 2) & 0x03;
+        let line_palette = (command[1] >> 4) & 0x03;
+        let horizontal = command[1] & 0x40 != 0;
+        let division = command[2] as usize;
+        for y in 0 .. CELLS_Y {
+            for x in 0 .. CELLS_X {
+                let position = if horizontal { y } else { x };
+                self.attributes[y][x] = if position < division {
+                    before_palette
+                } else if position == division {
+                    line_palette
+                } else {
+                    after_palette
+                };
+            }
+        }
+    }
+
+    fn attribute_characters(&mut self, command: &[u8]) {
+        let mut x = (command[1] as usize).min(CELLS_X - 1);
+        let mut y = (command[2] as usize).min(CELLS_Y - 1);
+        let datasets = (command[3] as usize | (command[4] as usize) << 8).min(CELLS_X * CELLS_Y);
+        let top_to_bottom = command[5] & 0x01 != 0;
+        for i in 0 .. datasets {
+            let byte = match command.get(6 + i / 4) {
+                Some(byte) => *byte,
+                None => break,
+            };
+            self.attributes[y][x] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+            if top_to_bottom {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x = (x + 1) % CELLS_X;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y = (y + 1) % CELLS_Y;
+                }
+            }
+        }
+    }
+
+    // the 256x224 image the Super Game Boy shows: the colorized screen surrounded by the border
+    pub fn render_rgba(&self, screen: &[[u8; gpu::WIDTH]; gpu::HEIGHT]) -> Vec<u8> {
+        let mut pixels = vec![0xFF; WIDTH * HEIGHT * 4];
+        let backdrop = self.palettes[0][0];
+        for y in 0 .. HEIGHT {
+            for x in 0 .. WIDTH {
+                let color = if (SCREEN_X .. SCREEN_X + gpu::WIDTH).contains(&x) && (SCREEN_Y .. SCREEN_Y + gpu::HEIGHT).contains(&y) {
+                    self.screen_color(screen, x - SCREEN_X, y - SCREEN_Y)
+                } else {
+                    self.border_color(x, y).unwrap_or(backdrop)
+                };
+                let rgb = rgb555_to_rgb888(color);
+                pixels[(y * WIDTH + x) * 4 .. (y * WIDTH + x) * 4 + 3].copy_from_slice(&rgb);
+            }
+        }
+        pixels
+    }
+
+    fn screen_color(&self, screen: &[[u8; gpu::WIDTH]; gpu::HEIGHT], x: usize, y: usize) -> u16 {
+        let shade = match self.mask {
+            Mask::Cancel => screen[y][x],
+            Mask::Freeze => self.frozen_screen[y][x],
+            Mask::Black => return 0x0000,
+            Mask::Color0 => 0,
+        };
+        let palette = self.attributes[y / 8][x / 8] as usize;
+        self.palettes[palette][shade as usize]
+    }
+
+    // None where the border is transparent
+    fn border_color(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = self.border_map[(y / 8) * 32 + x / 8];
+        let tile = (entry & 0xFF) as usize;
+        let palette = ((entry >> 10) & 0x07) as usize;
+        let column = if entry & 0x4000 != 0 { 7 - x % 8 } else { x % 8 };
+        let row = if entry & 0x8000 != 0 { 7 - y % 8 } else { y % 8 };
+        let data = &self.border_tiles[tile * BORDER_TILE_SIZE .. (tile + 1) * BORDER_TILE_SIZE];
+        let bit = 7 - column;
+        let color_index = ((data[row * 2] >> bit) & 0x1)
+            | ((data[row * 2 + 1] >> bit) & 0x1) << 1
+            | ((data[16 + row * 2] >> bit) & 0x1) << 2
+            | ((data[16 + row * 2 + 1] >> bit) & 0x1) << 3;
+        if color_index == 0 || palette < 4 {
+            return None;
+        }
+        Some(self.border_palettes[palette - 4][color_index as usize])
+    }
+}
+
+fn read_color(data: &[u8], offset: usize) -> u16 {
+    u16::from(data[offset]) | (u16::from(data[offset + 1]) << 8)
+}
+
+impl SaveState for Sgb {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.command);
+        writer.write_u32(self.bit_index as u32);
+        writer.write_bool(self.ready_for_pulse);
+        writer.write_bool(self.ready_for_write);
+        writer.write_bool(self.ready_for_stop);
+        writer.write_u8(self.player_count);
+        writer.write_u8(self.current_player);
+        writer.write_bool(self.player_lock);
+        for &color in self.palettes.iter().flatten() {
+            writer.write_u16(color);
+        }
+        for &color in self.system_palettes.iter().flatten() {
+            writer.write_u16(color);
+        }
+        for row in self.attributes.iter() {
+            writer.write_bytes(row);
+        }
+        writer.write_bytes(&self.border_tiles);
+        for &entry in self.border_map.iter() {
+            writer.write_u16(entry);
+        }
+        for &color in self.border_palettes.iter().flatten() {
+            writer.write_u16(color);
+        }
+        writer.write_u8(self.mask as u8);
+        for line in self.frozen_screen.iter() {
+            writer.write_bytes(line);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        reader.read_bytes(&mut self.command)?;
+        self.bit_index = reader.read_u32()? as usize % (self.command.len() * 8);
+        self.ready_for_pulse = reader.read_bool()?;
+        self.ready_for_write = reader.read_bool()?;
+        self.ready_for_stop = reader.read_bool()?;
+        self.player_count = reader.read_u8()?.clamp(1, 4);
+        self.current_player = reader.read_u8()? % self.player_count;
+        self.player_lock = reader.read_bool()?;
+        for color in self.palettes.iter_mut().flatten() {
+            *color = reader.read_u16()?;
+        }
+        for color in self.system_palettes.iter_mut().flatten() {
+            *color = reader.read_u16()?;
+        }
+        for row in self.attributes.iter_mut() {
+            reader.read_bytes(row)?;
+            row.iter_mut().for_each(|palette| *palette &= 0x03);
+        }
+        reader.read_bytes(&mut self.border_tiles)?;
+        for entry in self.border_map.iter_mut() {
+            *entry = reader.read_u16()?;
+        }
+        for color in self.border_palettes.iter_mut().flatten() {
+            *color = reader.read_u16()?;
+        }
+        self.mask = match reader.read_u8()? {
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            3 => Mask::Color0,
+            _ => Mask::Cancel,
+        };
+        for line in self.frozen_screen.iter_mut() {
+            reader.read_bytes(line)?;
+            line.iter_mut().for_each(|shade| *shade &= 0x03);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a reset pulse, the 128 bits of each packet least significant first and the stop bit,
+    // which is a 0 unless stop_bit is set
+    fn send(sgb: &mut Sgb, command: &[u8], stop_bit: bool) {
+        let screen = [[0x0; gpu::WIDTH]; gpu::HEIGHT];
+        for packet in command.chunks(PACKET_SIZE) {
+            sgb.write_p1(0x30, &screen);
+            sgb.write_p1(0x00, &screen);
+            for i in 0 .. PACKET_SIZE * 8 {
+                sgb.write_p1(0x30, &screen);
+                let bit = packet[i / 8] >> (i % 8) & 0x01;
+                sgb.write_p1(if bit == 1 { 0x10 } else { 0x20 }, &screen);
+            }
+            sgb.write_p1(0x30, &screen);
+            sgb.write_p1(if stop_bit { 0x10 } else { 0x20 }, &screen);
+        }
+        sgb.write_p1(0x30, &screen);
+    }
+
+    // ATTR_BLK changing inside, border and outside of the whole screen to palette 3
+    const WHOLE_SCREEN: [u8; 6] = [0x07, 0x3F, 0x00, 0x00, (CELLS_X - 1) as u8, (CELLS_Y - 1) as u8];
+
+    #[test]
+    fn one_packet_command() {
+        let mut sgb = Sgb::init();
+        let mut command = [0x0; PACKET_SIZE];
+        command[0] = ATTR_BLK << 3 | 1;
+        command[1] = 1;
+        command[2 .. 8].copy_from_slice(&WHOLE_SCREEN);
+        send(&mut sgb, &command, false);
+        assert!(sgb.attributes.iter().flatten().all(|&palette| palette == 3));
+    }
+
+    #[test]
+    fn seven_packet_command() {
+        let mut sgb = Sgb::init();
+        let mut command = [0x0; PACKET_SIZE * MAX_PACKETS];
+        command[0] = ATTR_BLK << 3 | MAX_PACKETS as u8;
+        // 18 data sets, only the last one in the seventh packet changes anything
+        let datasets = (command.len() - 2) / 6;
+        command[1] = datasets as u8;
+        let last = 2 + (datasets - 1) * 6;
+        command[last .. last + 6].copy_from_slice(&WHOLE_SCREEN);
+        send(&mut sgb, &command, false);
+        assert!(sgb.attributes.iter().flatten().all(|&palette| palette == 3));
+    }
+
+    #[test]
+    fn stop_bit_of_one_drops_the_command() {
+        let mut sgb = Sgb::init();
+        let mut command = [0x0; PACKET_SIZE];
+        command[0] = MLT_REQ << 3 | 1;
+        command[1] = 0x01;
+        send(&mut sgb, &command, true);
+        assert_eq!(sgb.player_count, 1);
+        send(&mut sgb, &command, false);
+        assert_eq!(sgb.player_count, 2);
+    }
+}
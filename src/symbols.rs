@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+// Labels from a .sym file as written by rgblink -n or no$gmb, one "BB:AAAA Label" per line:
+//   00:0150 Main
+//   01:4000 LoadLevel
+//   00:C0A0 wPlayerX
+// ; starts a comment, section headers like [labels] are skipped.
+pub struct Symbols {
+    // the banks and labels at each address
+    by_addr: HashMap<u16, Vec<(usize, String)>>,
+    by_name: HashMap<String, (usize, u16)>,
+}
+
+impl Symbols {
+    pub fn init() -> Self {
+        Self { by_addr: HashMap::new(), by_name: HashMap::new() }
+    }
+
+    // a missing file gives no symbols, lines that don't parse are skipped
+    pub fn load(filepath: &str) -> Self {
+        let mut symbols = Self::init();
+        if let Ok(contents) = std::fs::read_to_string(filepath) {
+            for (bank, addr, name) in contents.lines().filter_map(parse_symbol_line) {
+                symbols.add(bank, addr, name);
+            }
+        }
+        symbols
+    }
+
+    // the .sym file next to the rom, game.gb has game.sym
+    pub fn load_for_rom(rom_path: &str) -> Self {
+        Self::load(std::path::Path::new(rom_path).with_extension("sym").to_str().unwrap_or_default())
+    }
+
+    pub fn add(&mut self, bank: usize, addr: u16, name: &str) {
+        self.by_addr.entry(addr).or_default().push((bank, name.to_string()));
+        self.by_name.insert(name.to_string(), (bank, addr));
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    // The first label at addr. With a bank only labels in that bank count, without one
+    // (memory that isn't banked or a bank that isn't known) any label does.
+    pub fn name(&self, bank: Option<usize>, addr: u16) -> Option<&str> {
+        let labels = self.by_addr.get(&addr)?;
+        labels.iter()
+            .find(|(label_bank, _)| bank.is_none_or(|bank| bank == *label_bank))
+            .map(|(_, name)| name.as_str())
+    }
+
+    // every label as bank, address and name
+    pub fn iter(&self) -> impl Iterator<Item = (usize, u16, &str)> {
+        self.by_addr.iter().flat_map(|(&addr, labels)| labels.iter().map(move |(bank, name)| (*bank, addr, name.as_str())))
+    }
+
+    // the bank and address of a label
+    pub fn find(&self, name: &str) -> Option<(usize, u16)> {
+        self.by_name.get(name).copied()
+    }
+}
+
+fn parse_symbol_line(line: &str) -> Option<(usize, u16, &str)> {
+    let line = line.split(';').next()?.trim();
+    let (location, name) = line.split_once(char::is_whitespace)?;
+    let (bank, addr) = location.split_once(':')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((usize::from_str_radix(bank, 16).ok()?, u16::from_str_radix(addr, 16).ok()?, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lines() {
+        assert_eq!(parse_symbol_line("00:0150 Main"), Some((0x00, 0x0150, "Main")));
+        assert_eq!(parse_symbol_line("1A:4000\tLoadLevel.loop  "), Some((0x1A, 0x4000, "LoadLevel.loop")));
+        assert_eq!(parse_symbol_line("01:4000 LoadLevel ; the level in A"), Some((0x01, 0x4000, "LoadLevel")));
+        assert_eq!(parse_symbol_line("; File generated by rgblink"), None);
+        assert_eq!(parse_symbol_line("[labels]"), None);
+        assert_eq!(parse_symbol_line(""), None);
+        assert_eq!(parse_symbol_line("00:0150"), None);
+        assert_eq!(parse_symbol_line("XY:0150 Main"), None);
+        assert_eq!(parse_symbol_line("00:G150 Main"), None);
+        assert_eq!(parse_symbol_line("00:10000 Main"), None);
+        assert_eq!(parse_symbol_line("0150 Main"), None);
+    }
+
+    #[test]
+    fn labels_in_different_banks() {
+        let mut symbols = Symbols::init();
+        for (bank, addr, name) in ["01:4000 LoadLevel", "02:4000 PlayMusic", "00:C0A0 wPlayerX"].into_iter().filter_map(parse_symbol_line) {
+            symbols.add(bank, addr, name);
+        }
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.name(Some(1), 0x4000), Some("LoadLevel"));
+        assert_eq!(symbols.name(Some(2), 0x4000), Some("PlayMusic"));
+        assert_eq!(symbols.name(Some(3), 0x4000), None);
+        assert_eq!(symbols.name(None, 0x4000), Some("LoadLevel"));
+        assert_eq!(symbols.name(None, 0xC0A0), Some("wPlayerX"));
+        assert_eq!(symbols.find("PlayMusic"), Some((2, 0x4000)));
+        assert_eq!(symbols.find("Main"), None);
+    }
+}
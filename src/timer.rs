@@ -1,18 +1,71 @@
-// use std::{rc::Rc, cell::RefCell};
-// use super::interrupts::Interrupts;
+use std::{rc::Rc, cell::RefCell};
+use super::interrupts::{Interrupt, Interrupts};
 use super::memory::Memory;
+use super::save_state::{SaveState, StateReader, StateWriter};
 
 // https://gbdev.io/pandocs/Timer_and_Divider_Registers.html
 pub struct Timer {
-    // interrupts: Rc<RefCell<Interrupts>>,
-    div: u8,
+    interrupts: Rc<RefCell<Interrupts>>,
+    // DIV is the upper byte of this counter, which is incremented every clock cycle
+    pub div_counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // TIMA reads 0 for one machine cycle after overflowing, then TMA is loaded and the interrupt requested
+    reload_cycles: u32,
 }
 
 impl Timer {
-    pub fn init(/*interrupts: Rc<RefCell<Interrupts>>*/) -> Self {
+    pub fn init(interrupts: Rc<RefCell<Interrupts>>, div_counter: u16) -> Self {
         Timer {
-            // interrupts: interrupts,
-            div: 0
+            interrupts,
+            div_counter,
+            tima: 0x00,
+            tma: 0x00,
+            tac: 0x00,
+            reload_cycles: 0,
+        }
+    }
+
+    // https://gbdev.io/pandocs/Timer_Obscure_Behaviour.html
+    // TIMA counts the falling edges of one bit of the div counter, while the timer is enabled
+    fn timer_bit(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0x00 => 9,
+            0x01 => 3,
+            0x02 => 5,
+            _ => 7,
+        };
+        self.tac & 0x04 != 0 && (self.div_counter >> bit) & 0x01 != 0
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            self.reload_cycles = 4;
+        }
+    }
+
+    // changes the div counter or TAC, counting a falling edge if that makes the selected bit drop
+    fn update<F: FnOnce(&mut Self)>(&mut self, change: F) {
+        let was_set = self.timer_bit();
+        change(self);
+        if was_set && !self.timer_bit() {
+            self.increment_tima();
+        }
+    }
+
+    pub fn run_cycles(&mut self, cpu_clock_cycles: u32) {
+        for _ in 0 .. cpu_clock_cycles {
+            if self.reload_cycles > 0 {
+                self.reload_cycles -= 1;
+                if self.reload_cycles == 0 {
+                    self.tima = self.tma;
+                    self.interrupts.borrow_mut().request(Interrupt::Timer);
+                }
+            }
+            self.update(|timer| timer.div_counter = timer.div_counter.wrapping_add(1));
         }
     }
 }
@@ -20,16 +73,45 @@ impl Timer {
 impl Memory for Timer {
     fn read8(&self, addr: u16) -> u8 {
         match addr {
-            0xFF04 => self.div,
+            0xFF04 => (self.div_counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => 0xF8 | self.tac,
            _ => panic!("unimplemented address read on Timer {:#04x}", addr)
         }
     }
 
     fn write8(&mut self, addr: u16, data: u8) {
         match addr {
-            0xFF04 => self.div = data,
+            // any write resets the whole counter
+            0xFF04 => self.update(|timer| timer.div_counter = 0),
+            0xFF05 => {
+                // writing during the overflow cycle cancels the reload
+                self.tima = data;
+                self.reload_cycles = 0;
+            }
+            0xFF06 => self.tma = data,
+            0xFF07 => self.update(|timer| timer.tac = data & 0x07),
             _ => panic!("unimplemented address write on Timer {:#04x}", addr)
         }
     }
 }
- 
\ No newline at end of file
+
+impl SaveState for Timer {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.div_counter);
+        writer.write_u8(self.tima);
+        writer.write_u8(self.tma);
+        writer.write_u8(self.tac);
+        writer.write_u32(self.reload_cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.div_counter = reader.read_u16()?;
+        self.tima = reader.read_u8()?;
+        self.tma = reader.read_u8()?;
+        self.tac = reader.read_u8()? & 0x07;
+        self.reload_cycles = reader.read_u32()?;
+        Ok(())
+    }
+}
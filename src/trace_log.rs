@@ -0,0 +1,57 @@
+use std::io::{BufWriter, Write};
+use super::main_board::CPU_CLOCKS_PER_FRAME;
+
+// https://github.com/robert/gameboy-doctor
+// One line per instruction, with the registers and the 4 bytes at PC before it runs:
+// A:00 F:11 B:22 C:33 D:44 E:55 H:66 L:77 SP:8888 PC:9999 PCMEM:AA,BB,CC,DD
+// The reference logs start at 0x0100 after the boot rom and expect LY to always read 0x90.
+pub struct TraceLog {
+    writer: BufWriter<Box<dyn Write>>,
+    // cycles the cpu ran since tracing started, counted at normal speed
+    pub cycles: u64,
+    // only instructions starting within these cycles are logged
+    pub first_cycle: u64,
+    pub last_cycle: u64,
+}
+
+impl TraceLog {
+    pub fn init(writer: Box<dyn Write>) -> Self {
+        Self {
+            writer: BufWriter::new(writer),
+            cycles: 0,
+            first_cycle: 0,
+            last_cycle: u64::MAX,
+        }
+    }
+
+    // "-" is stdout
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        let writer: Box<dyn Write> = match path {
+            "-" => Box::new(std::io::stdout()),
+            path => Box::new(std::fs::File::create(path)?),
+        };
+        Ok(Self::init(writer))
+    }
+
+    // both frames included
+    pub fn set_frame_range(&mut self, first_frame: u64, last_frame: u64) {
+        self.first_cycle = first_frame * CPU_CLOCKS_PER_FRAME as u64;
+        self.last_cycle = last_frame.saturating_add(1).saturating_mul(CPU_CLOCKS_PER_FRAME as u64).saturating_sub(1);
+    }
+
+    pub fn is_logging(&self) -> bool {
+        (self.first_cycle ..= self.last_cycle).contains(&self.cycles)
+    }
+
+    // registers are A F B C D E H L
+    pub fn log(&mut self, registers: [u8; 8], sp: u16, pc: u16, pc_memory: [u8; 4]) -> std::io::Result<()> {
+        let [a, f, b, c, d, e, h, l] = registers;
+        writeln!(self.writer,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            a, f, b, c, d, e, h, l, sp, pc, pc_memory[0], pc_memory[1], pc_memory[2], pc_memory[3])
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,149 @@
+// Shared by the integration tests that run test roms. The roms aren't part of the repository,
+// see tests/roms/README.md for where they go.
+#![allow(dead_code)]
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use rustyboy::headless::{self, Outcome, RunOptions, RunResult};
+use rustyboy::main_board::MainBoard;
+use rustyboy::model::Model;
+
+// set to pass suites whose roms aren't there instead of failing them
+pub const SKIP_MISSING_ROMS: &str = "RUSTYBOY_SKIP_MISSING_ROMS";
+
+// RUSTYBOY_TEST_ROMS overrides tests/roms
+pub fn roms_dir() -> PathBuf {
+    match std::env::var_os("RUSTYBOY_TEST_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms"),
+    }
+}
+
+// every .gb/.gbc file below dir, sorted
+pub fn find_roms(dir: &Path) -> Vec<PathBuf> {
+    let mut roms = Vec::new();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return roms,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            roms.extend(find_roms(&path));
+        } else if matches!(path.extension().and_then(|e| e.to_str()), Some("gb") | Some("gbc")) {
+            roms.push(path);
+        }
+    }
+    roms.sort();
+    roms
+}
+
+pub struct RomTest {
+    pub path: PathBuf,
+    // detected from the rom header when None
+    pub model: Option<Model>,
+    pub options: RunOptions,
+}
+
+pub enum Status {
+    Passed,
+    Failed,
+    TimedOut,
+    Panicked,
+    // the rom isn't there, which fails the test unless SKIP_MISSING_ROMS is set
+    Missing,
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Passed => "pass",
+            Status::Failed => "FAIL",
+            Status::TimedOut => "TIMEOUT",
+            Status::Panicked => "PANIC",
+            Status::Missing => "missing",
+        }
+    }
+}
+
+// the rom's path below the test roms directory
+pub fn rom_name(path: &Path) -> String {
+    path.strip_prefix(roms_dir()).unwrap_or(path).display().to_string()
+}
+
+// Runs the roms and prints a table of the results, see report for what fails the test
+pub fn run_rom_tests(suite: &str, tests: Vec<RomTest>) {
+    let mut rows = Vec::new();
+    for test in tests {
+        let name = rom_name(&test.path);
+        if !test.path.is_file() {
+            rows.push((name, Status::Missing, String::new()));
+            continue;
+        }
+        let (status, details) = run_rom_test(&test);
+        rows.push((name, status, details));
+    }
+    report(suite, &rows);
+}
+
+// Prints a table of (rom, status, details) rows and fails the test unless every rom passed.
+// With SKIP_MISSING_ROMS set, missing roms and a suite without any are let through.
+pub fn report(suite: &str, rows: &[(String, Status, String)]) {
+    let name_width = rows.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+    println!("{}", suite);
+    for (name, status, details) in rows {
+        println!("  {:width$}  {:7}  {}", name, status.name(), details, width = name_width);
+    }
+    let count = |wanted: fn(&Status) -> bool| rows.iter().filter(|(_, status, _)| wanted(status)).count();
+    let passed = count(|status| matches!(status, Status::Passed));
+    let missing = count(|status| matches!(status, Status::Missing));
+    let failed = rows.len() - passed - missing;
+    println!("  {} passed, {} failed, {} missing", passed, failed, missing);
+    assert_eq!(failed, 0, "{}: {} of {} roms failed", suite, failed, rows.len() - missing);
+    if missing == 0 && !rows.is_empty() {
+        return;
+    }
+    // a suite that ran nothing hasn't passed
+    let what = if rows.is_empty() { "no roms".to_string() } else { format!("{} of {} roms missing", missing, rows.len()) };
+    if std::env::var_os(SKIP_MISSING_ROMS).is_some() {
+        println!("  {} in {}, skipped since {} is set", what, roms_dir().display(), SKIP_MISSING_ROMS);
+        return;
+    }
+    panic!("{}: {} in {}, see tests/roms/README.md or set {} to skip them", suite, what, roms_dir().display(), SKIP_MISSING_ROMS);
+}
+
+// Loads the rom and runs it headless. prepare gets the main board before the run and finish
+// after it. None when the emulator panicked.
+pub fn run_rom<T>(test: &RomTest, prepare: impl FnOnce(&mut MainBoard), finish: impl FnOnce(&MainBoard, RunResult) -> T) -> Option<T> {
+    let path = test.path.to_str().expect("rom paths are utf-8");
+    catch_unwind(AssertUnwindSafe(|| {
+        let mut main_board = MainBoard::init(path, None, test.model).expect("loading the rom");
+        prepare(&mut main_board);
+        let result = headless::run(&mut main_board, &test.options);
+        finish(&main_board, result)
+    })).ok()
+}
+
+fn run_rom_test(test: &RomTest) -> (Status, String) {
+    let result = run_rom(test, |_| {}, |main_board, result| {
+        let text = match headless::blargg_memory_text(main_board) {
+            text if text.is_empty() => result.serial_output.clone(),
+            text => text,
+        };
+        (result, text)
+    });
+    let (result, text) = match result {
+        Some(result) => result,
+        None => return (Status::Panicked, String::new()),
+    };
+    let status = match result.outcome {
+        Outcome::Passed => Status::Passed,
+        Outcome::Failed => Status::Failed,
+        Outcome::TimedOut => Status::TimedOut,
+    };
+    // the last line of what the rom printed is usually the verdict
+    let last_line = text.lines().map(str::trim).rfind(|line| !line.is_empty()).unwrap_or("");
+    let registers = result.registers;
+    let details = format!("{} frames, B={:02X} C={:02X} D={:02X} E={:02X} H={:02X} L={:02X} {}", result.frames,
+        registers[0], registers[1], registers[2], registers[3], registers[4], registers[5], last_line);
+    (status, details)
+}
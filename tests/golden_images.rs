@@ -0,0 +1,98 @@
+// Runs roms for a fixed number of frames and compares the picture to a reference png.
+// The list is tests/golden/golden.txt, RUSTYBOY_GOLDEN_LIST points at another one. A missing
+// reference fails the test, unless RUSTYBOY_BLESS is set to save the current pictures as the missing
+// references. Missing roms fail it too, unless RUSTYBOY_SKIP_MISSING_ROMS is set. Use --nocapture to
+// see the results.
+mod common;
+
+use std::path::{Path, PathBuf};
+use common::{report, rom_name, roms_dir, run_rom, RomTest, Status};
+use rustyboy::headless::{self, RunOptions};
+use rustyboy::model::Model;
+
+struct GoldenTest {
+    reference: PathBuf,
+    rom: RomTest,
+}
+
+fn list_path() -> PathBuf {
+    match std::env::var_os("RUSTYBOY_GOLDEN_LIST") {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join("golden.txt"),
+    }
+}
+
+// reference png, model or auto, frames and the rom, separated by whitespace. # starts a comment
+fn parse_list(path: &Path) -> Vec<GoldenTest> {
+    let text = std::fs::read_to_string(path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    let references_dir = path.parent().unwrap_or(Path::new("."));
+    let roms = roms_dir();
+    text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')).map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        assert!(fields.len() >= 4, "{}: expected reference, model, frames and rom in: {}", path.display(), line);
+        let model = match fields[1] {
+            "auto" => None,
+            name => Some(Model::from_name(name).unwrap_or_else(|| panic!("{}: unknown model {}", path.display(), name))),
+        };
+        let frames = fields[2].parse().unwrap_or_else(|_| panic!("{}: bad frame count {}", path.display(), fields[2]));
+        GoldenTest {
+            reference: references_dir.join(fields[0]),
+            rom: RomTest { path: roms.join(fields[3 ..].join(" ")), model, options: RunOptions::init(frames) },
+        }
+    }).collect()
+}
+
+fn run_golden_test(test: &GoldenTest, output_dir: &Path) -> (Status, String) {
+    let name = test.reference.file_stem().and_then(|stem| stem.to_str()).unwrap_or("golden").to_string();
+    let frame = run_rom(&test.rom, headless::use_reference_colors, |main_board, _| main_board.mmu.borrow().gpu.framebuffer_rgba());
+    let frame = match frame {
+        Some(frame) => frame,
+        None => return (Status::Panicked, String::new()),
+    };
+    let size = (rustyboy::gpu::WIDTH, rustyboy::gpu::HEIGHT);
+    let reference_path = test.reference.to_str().expect("reference paths are utf-8");
+    let actual_path = output_dir.join(format!("{}.actual.png", name));
+    let actual_path = actual_path.to_str().expect("output paths are utf-8");
+
+    if !test.reference.is_file() {
+        if std::env::var_os("RUSTYBOY_BLESS").is_some() {
+            headless::write_png(reference_path, size, &frame).expect("writing the reference");
+            return (Status::Passed, format!("saved as the reference {}", reference_path));
+        }
+        headless::write_png(actual_path, size, &frame).expect("writing the actual picture");
+        return (Status::Failed, format!("no reference, the picture is in {}, set RUSTYBOY_BLESS to keep it", actual_path));
+    }
+    let (reference_size, reference) = headless::read_png(reference_path).expect("reading the reference");
+    if reference_size != size {
+        return (Status::Failed, format!("reference is {}x{}, expected {}x{}", reference_size.0, reference_size.1, size.0, size.1));
+    }
+    let (mismatched, diff) = headless::diff_images(&reference, &frame);
+    if mismatched == 0 {
+        return (Status::Passed, String::new());
+    }
+    let diff_path = output_dir.join(format!("{}.diff.png", name));
+    let diff_path = diff_path.to_str().expect("output paths are utf-8");
+    headless::write_png(actual_path, size, &frame).expect("writing the actual picture");
+    headless::write_png(diff_path, size, &diff).expect("writing the diff");
+    (Status::Failed, format!("{} pixels differ, see {}", mismatched, diff_path))
+}
+
+#[test]
+fn golden_images() {
+    let list = list_path();
+    let tests = parse_list(&list);
+    let output_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden");
+    std::fs::create_dir_all(&output_dir).expect("creating the output directory");
+
+    let mut rows = Vec::new();
+    for test in &tests {
+        let name = rom_name(&test.rom.path);
+        if !test.rom.path.is_file() {
+            rows.push((name, Status::Missing, String::new()));
+            continue;
+        }
+        let (status, details) = run_golden_test(test, &output_dir);
+        rows.push((name, status, details));
+    }
+    report(&format!("golden images from {}", list.display()), &rows);
+}
@@ -0,0 +1,238 @@
+// https://github.com/SingleStepTests/sm83 - every opcode from random states, one instruction at a time.
+// The vectors aren't part of the repository, see tests/sm83/README.md. Without them the test fails unless
+// RUSTYBOY_SKIP_MISSING_SM83_TESTS is set. Use --nocapture to see the results.
+use std::cell::RefCell;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use rustyboy::cpu::Cpu;
+use rustyboy::memory::Memory;
+use serde_json::Value;
+
+// RUSTYBOY_SM83_TESTS overrides tests/sm83
+fn vectors_dir() -> PathBuf {
+    match std::env::var_os("RUSTYBOY_SM83_TESTS") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("sm83"),
+    }
+}
+
+// 64KiB of plain ram, logging every bus access of the cpu
+struct FlatMemory {
+    bytes: Vec<u8>,
+    accesses: RefCell<Vec<BusAccess>>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+struct BusAccess {
+    addr: u16,
+    data: u8,
+    write: bool,
+}
+
+impl BusAccess {
+    fn describe(&self) -> String {
+        format!("{} {:#04X} at {:#06X}", if self.write { "write" } else { "read" }, self.data, self.addr)
+    }
+}
+
+impl FlatMemory {
+    fn init() -> Self {
+        Self { bytes: vec![0x0; 0x10000], accesses: RefCell::new(Vec::new()) }
+    }
+
+    fn written(&self) -> Vec<u16> {
+        self.accesses.borrow().iter().filter(|access| access.write).map(|access| access.addr).collect()
+    }
+}
+
+impl Memory for FlatMemory {
+    fn read8(&self, addr: u16) -> u8 {
+        let data = self.bytes[addr as usize];
+        self.accesses.borrow_mut().push(BusAccess { addr, data, write: false });
+        data
+    }
+
+    fn write8(&mut self, addr: u16, data: u8) {
+        self.bytes[addr as usize] = data;
+        self.accesses.get_mut().push(BusAccess { addr, data, write: true });
+    }
+
+    // the cpu checks IE and IF for pending interrupts without a bus cycle
+    fn peek8(&self, addr: u16) -> u8 {
+        self.bytes[addr as usize]
+    }
+}
+
+const REGISTERS: [&str; 8] = ["a", "f", "b", "c", "d", "e", "h", "l"];
+
+fn field(state: &Value, name: &str) -> u64 {
+    state[name].as_u64().unwrap_or_else(|| panic!("test vector without {}", name))
+}
+
+// [[address, value], ...]
+fn ram(state: &Value) -> Vec<(u16, u8)> {
+    state["ram"].as_array().expect("test vector without ram").iter()
+        .map(|entry| (entry[0].as_u64().unwrap() as u16, entry[1].as_u64().unwrap() as u8))
+        .collect()
+}
+
+fn set_state(cpu: &mut Cpu, memory: &Rc<RefCell<FlatMemory>>, state: &Value) {
+    let registers = REGISTERS.map(|name| field(state, name) as u8);
+    let [a, f, b, c, d, e, h, l] = registers;
+    (cpu.a, cpu.flags, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l) = (a, f, b, c, d, e, h, l);
+    cpu.pc = field(state, "pc") as u16;
+    cpu.sp = field(state, "sp") as u16;
+    cpu.ime = field(state, "ime") != 0;
+    let mut memory = memory.borrow_mut();
+    if let Some(ie) = state["ie"].as_u64() {
+        memory.bytes[0xFFFF] = ie as u8;
+    }
+    for (addr, value) in ram(state) {
+        memory.bytes[addr as usize] = value;
+    }
+    memory.accesses.get_mut().clear();
+}
+
+// [[address, value, pins], ...] with one entry per machine cycle. The pins are "r-m" for a read,
+// "-wm" for a write and "---" for a cycle without a bus access, which only counts towards the time taken
+fn bus_activity(case: &Value) -> Vec<BusAccess> {
+    case["cycles"].as_array().expect("test vector without cycles").iter()
+        .filter_map(|cycle| {
+            let pins = cycle[2].as_str()?;
+            let write = pins.contains('w');
+            if !write && !pins.contains('r') {
+                return None;
+            }
+            Some(BusAccess { addr: cycle[0].as_u64()? as u16, data: cycle[1].as_u64()? as u8, write })
+        })
+        .collect()
+}
+
+// the reads and writes have to happen in the same order as on hardware
+fn compare_bus_activity(memory: &FlatMemory, case: &Value) -> Option<String> {
+    let expected = bus_activity(case);
+    let actual = memory.accesses.borrow();
+    let describe = |access: Option<&BusAccess>| access.map_or("nothing".to_string(), BusAccess::describe);
+    for i in 0 .. expected.len().max(actual.len()) {
+        if expected.get(i) != actual.get(i) {
+            return Some(format!("bus access {}: expected {}, got {}", i, describe(expected.get(i)), describe(actual.get(i))));
+        }
+    }
+    None
+}
+
+// the first difference to the expected state, if any
+fn compare_state(cpu: &Cpu, memory: &FlatMemory, initial: &Value, expected: &Value) -> Option<String> {
+    let actual = [cpu.a, cpu.flags, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l];
+    for (name, &value) in REGISTERS.iter().zip(actual.iter()) {
+        let wanted = field(expected, name) as u8;
+        if value != wanted {
+            return Some(format!("{}: expected {:#04X}, got {:#04X}", name, wanted, value));
+        }
+    }
+    for (name, value) in [("pc", cpu.pc), ("sp", cpu.sp)] {
+        let wanted = field(expected, name) as u16;
+        if value != wanted {
+            return Some(format!("{}: expected {:#06X}, got {:#06X}", name, wanted, value));
+        }
+    }
+    let wanted_ime = field(expected, "ime") != 0;
+    if cpu.ime != wanted_ime {
+        return Some(format!("ime: expected {}, got {}", wanted_ime, cpu.ime));
+    }
+    if let Some(ie) = expected["ie"].as_u64() {
+        if memory.bytes[0xFFFF] != ie as u8 {
+            return Some(format!("ie: expected {:#04X}, got {:#04X}", ie, memory.bytes[0xFFFF]));
+        }
+    }
+    let expected_ram = ram(expected);
+    for &(addr, wanted) in &expected_ram {
+        if memory.bytes[addr as usize] != wanted {
+            return Some(format!("[{:#06X}]: expected {:#04X}, got {:#04X}", addr, wanted, memory.bytes[addr as usize]));
+        }
+    }
+    // anything written outside of the expected ram has to be unchanged
+    let initial_ram = ram(initial);
+    for addr in memory.written() {
+        if expected_ram.iter().any(|&(expected_addr, _)| expected_addr == addr) {
+            continue;
+        }
+        let wanted = initial_ram.iter().find(|&&(initial_addr, _)| initial_addr == addr).map_or(0x00, |&(_, value)| value);
+        if memory.bytes[addr as usize] != wanted {
+            return Some(format!("[{:#06X}]: unexpected write of {:#04X}", addr, memory.bytes[addr as usize]));
+        }
+    }
+    None
+}
+
+fn run_case(case: &Value) -> Option<String> {
+    let memory = Rc::new(RefCell::new(FlatMemory::init()));
+    let mut cpu = Cpu::init_for_boot_rom(memory.clone());
+    set_state(&mut cpu, &memory, &case["initial"]);
+    let cycles = cpu.emulate_operation();
+    let difference = compare_state(&cpu, &memory.borrow(), &case["initial"], &case["final"]);
+    if difference.is_some() {
+        return difference;
+    }
+    // one entry per machine cycle
+    let wanted_cycles = case["cycles"].as_array().map_or(0, |cycles| cycles.len() as u32 * 4);
+    if cycles != wanted_cycles {
+        return Some(format!("cycles: expected {}, got {}", wanted_cycles, cycles));
+    }
+    let difference = compare_bus_activity(&memory.borrow(), case);
+    difference
+}
+
+#[test]
+fn sm83_single_step() {
+    let dir = vectors_dir();
+    let mut files: Vec<PathBuf> = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries.flatten().map(|entry| entry.path())
+            .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("json"))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    files.sort();
+    if files.is_empty() {
+        // a conformance test that checked nothing hasn't passed
+        if std::env::var_os("RUSTYBOY_SKIP_MISSING_SM83_TESTS").is_some() {
+            println!("no test vectors in {}, skipped since RUSTYBOY_SKIP_MISSING_SM83_TESTS is set", dir.display());
+            return;
+        }
+        panic!("no test vectors in {}, see tests/sm83/README.md or set RUSTYBOY_SKIP_MISSING_SM83_TESTS to skip them", dir.display());
+    }
+
+    // unimplemented opcodes panic, they're reported as failures instead
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let mut failed_files = 0;
+    let (mut total_cases, mut total_failed) = (0, 0);
+    for path in &files {
+        let name = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("").to_string();
+        let text = std::fs::read_to_string(path).expect("reading test vectors");
+        let cases: Value = serde_json::from_str(&text).expect("parsing test vectors");
+        let cases = cases.as_array().expect("test vectors are a list");
+        let mut failed = 0;
+        let mut first_failure = None;
+        for case in cases {
+            let result = catch_unwind(AssertUnwindSafe(|| run_case(case)))
+                .unwrap_or_else(|_| Some("panicked".to_string()));
+            if let Some(difference) = result {
+                failed += 1;
+                first_failure.get_or_insert_with(|| format!("{}: {}", case["name"].as_str().unwrap_or("?"), difference));
+            }
+        }
+        total_cases += cases.len();
+        total_failed += failed;
+        if let Some(first_failure) = first_failure {
+            failed_files += 1;
+            println!("  {:8}  {:5}/{:5} failed  {}", name, failed, cases.len(), first_failure);
+        }
+    }
+    std::panic::set_hook(default_hook);
+
+    println!("{} of {} opcodes passed, {} of {} cases failed",
+        files.len() - failed_files, files.len(), total_failed, total_cases);
+    assert_eq!(failed_files, 0, "{} opcodes failed", failed_files);
+}
@@ -0,0 +1,107 @@
+// Blargg and Mooneye test roms, run headless. Use --nocapture to see the result tables.
+mod common;
+
+use common::{find_roms, roms_dir, run_rom_tests, RomTest};
+use rustyboy::headless::RunOptions;
+use rustyboy::model::Model;
+
+const BLARGG_FRAMES: u32 = 60 * 60;
+const MOONEYE_FRAMES: u32 = 60 * 10;
+
+fn blargg_tests(files: &[&str]) -> Vec<RomTest> {
+    let dir = roms_dir().join("blargg");
+    files.iter().map(|file| RomTest {
+        path: dir.join(file),
+        model: Some(Model::Dmg),
+        options: RunOptions::blargg(BLARGG_FRAMES),
+    }).collect()
+}
+
+#[test]
+fn blargg_cpu_instrs() {
+    run_rom_tests("blargg cpu_instrs", blargg_tests(&[
+        "cpu_instrs/individual/01-special.gb",
+        "cpu_instrs/individual/02-interrupts.gb",
+        "cpu_instrs/individual/03-op sp,hl.gb",
+        "cpu_instrs/individual/04-op r,imm.gb",
+        "cpu_instrs/individual/05-op rp.gb",
+        "cpu_instrs/individual/06-ld r,r.gb",
+        "cpu_instrs/individual/07-jr,jp,call,ret,rst.gb",
+        "cpu_instrs/individual/08-misc instrs.gb",
+        "cpu_instrs/individual/09-op r,r.gb",
+        "cpu_instrs/individual/10-bit ops.gb",
+        "cpu_instrs/individual/11-op a,(hl).gb",
+    ]));
+}
+
+#[test]
+fn blargg_instr_timing() {
+    run_rom_tests("blargg instr_timing", blargg_tests(&[
+        "instr_timing/instr_timing.gb",
+    ]));
+}
+
+#[test]
+fn blargg_mem_timing() {
+    run_rom_tests("blargg mem_timing", blargg_tests(&[
+        "mem_timing/individual/01-read_timing.gb",
+        "mem_timing/individual/02-write_timing.gb",
+        "mem_timing/individual/03-modify_timing.gb",
+    ]));
+}
+
+#[test]
+fn blargg_dmg_sound() {
+    run_rom_tests("blargg dmg_sound", blargg_tests(&[
+        "dmg_sound/rom_singles/01-registers.gb",
+        "dmg_sound/rom_singles/02-len ctr.gb",
+        "dmg_sound/rom_singles/03-trigger.gb",
+        "dmg_sound/rom_singles/04-sweep.gb",
+        "dmg_sound/rom_singles/05-sweep details.gb",
+        "dmg_sound/rom_singles/06-overflow on trigger.gb",
+        "dmg_sound/rom_singles/07-len sweep period sync.gb",
+        "dmg_sound/rom_singles/08-len ctr during power.gb",
+        "dmg_sound/rom_singles/09-wave read while on.gb",
+        "dmg_sound/rom_singles/10-wave trigger while on.gb",
+        "dmg_sound/rom_singles/11-regs after power.gb",
+        "dmg_sound/rom_singles/12-wave write while on.gb",
+    ]));
+}
+
+#[test]
+fn blargg_halt_bug() {
+    run_rom_tests("blargg halt_bug", blargg_tests(&[
+        "halt_bug.gb",
+    ]));
+}
+
+// https://github.com/Gekkio/mooneye-test-suite#test-naming
+// the suffix after the last dash names the models a rom is meant for, the first one is used
+fn mooneye_model(rom_name: &str) -> Model {
+    let suffix = match rom_name.rsplit_once('-') {
+        Some((_, suffix)) => suffix,
+        None => return Model::Dmg,
+    };
+    match suffix {
+        "dmg0" => Model::Dmg0,
+        "mgb" => Model::Mgb,
+        "sgb" | "S" => Model::Sgb,
+        "sgb2" => Model::Sgb2,
+        "A" => Model::Agb,
+        _ if suffix.starts_with("cgb") || suffix == "C" => Model::Cgb,
+        _ => Model::Dmg,
+    }
+}
+
+#[test]
+fn mooneye_acceptance() {
+    let tests = find_roms(&roms_dir().join("mooneye").join("acceptance")).into_iter().map(|path| {
+        let rom_name = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("").to_string();
+        RomTest {
+            path,
+            model: Some(mooneye_model(&rom_name)),
+            options: RunOptions::mooneye(MOONEYE_FRAMES),
+        }
+    }).collect();
+    run_rom_tests("mooneye acceptance", tests);
+}